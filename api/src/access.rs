@@ -0,0 +1,221 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::net::IpAddr;
+use crate::config::{Config, InboxConfig, TokenConfig};
+use crate::bans;
+use crate::control;
+use crate::firewall;
+use crate::links::{self, Link};
+use crate::structure;
+
+/// Permissions a token can be given
+pub const PERMISSION_UPLOAD: &str = "upload";
+pub const PERMISSION_LIST: &str = "list";
+pub const PERMISSION_DOWNLOAD: &str = "download";
+pub const PERMISSION_DELETE: &str = "delete";
+/// Grants every other permission
+pub const PERMISSION_ADMIN: &str = "admin";
+
+/// Checks that `transfer_id` allows `permission` and narrows `config` to what it may access
+///
+/// - the transfer ID of the default inbox or another inbox may upload into it,
+///   list and download if allow_read is on, and delete if allow_delete is on
+/// - a token from [[tokens]] may do what its permissions say, inside its
+///   inbox and folder and up to its size limit
+/// - an upload link may only upload; it is returned so its use can be counted
+///
+/// Inboxes and tokens with an allow list can only be used from `peer`s in it.
+/// Any other ID is refused and counts as failed attempt of `peer`, which is
+/// slowed down and eventually banned.
+pub fn authorize(config: &mut Config, transfer_id: &str, permission: &str, peer: IpAddr) -> Result<Option<Link>> {
+    bans::check(&config.limits, peer)?;
+
+    let inbox = config.select_inbox(transfer_id);
+    if inbox.is_some() || control::constant_time_eq(transfer_id.as_bytes(), config.transfer_id.as_bytes()) {
+        if let Some(inbox) = &inbox {
+            check_inbox_network(inbox, peer)?;
+        }
+        if permission == PERMISSION_DELETE && !config.allow_delete {
+            return Err(anyhow::anyhow!("Delete access is disabled on this server"));
+        }
+        if permission != PERMISSION_UPLOAD && permission != PERMISSION_DELETE && !config.allow_read {
+            return Err(anyhow::anyhow!("Read access is disabled on this server"));
+        }
+        return Ok(None);
+    }
+
+    let token = config.tokens.iter()
+        .find(|token| control::constant_time_eq(transfer_id.as_bytes(), token.token.as_bytes()))
+        .cloned();
+    if let Some(token) = token {
+        authorize_token(config, &token, permission, peer)?;
+        return Ok(None);
+    }
+
+    if let Some(link) = links::apply(config, transfer_id)? {
+        if permission != PERMISSION_UPLOAD {
+            return Err(anyhow::anyhow!("Upload links can only upload"));
+        }
+        return Ok(Some(link));
+    }
+
+    warn!("Refused unknown transfer ID for {}", permission);
+    bans::failed(&config.limits, peer);
+    Err(anyhow::anyhow!("Invalid transfer ID"))
+}
+
+fn authorize_token(config: &mut Config, token: &TokenConfig, permission: &str, peer: IpAddr) -> Result<()> {
+    let expired = !token.expires.is_empty() && DateTime::parse_from_rfc3339(&token.expires)
+        .map_or(true, |expires| expires <= Utc::now());
+    if expired {
+        warn!("Refused expired token {}", token.name);
+        return Err(anyhow::anyhow!("Token expired"));
+    }
+
+    let allowed = token.permissions.iter()
+        .any(|granted| granted == permission || granted == PERMISSION_ADMIN);
+    if !allowed {
+        warn!("Refused token {}: no {} permission", token.name, permission);
+        return Err(anyhow::anyhow!("Token is not allowed to {}", permission));
+    }
+    if !firewall::in_networks(&token.allow, peer)? {
+        warn!("Refused token {} from {}: not in its allow list", token.name, peer);
+        return Err(anyhow::anyhow!("Token can't be used from your address"));
+    }
+
+    let folder = match token.folder.as_str() {
+        "" => String::new(),
+        folder => structure::sanitize_relative_path(folder)?.to_string_lossy().to_string(),
+    };
+    if let Some(inbox) = config.select_inbox_named(&token.inbox)? {
+        check_inbox_network(&inbox, peer)?;
+    }
+    config.narrow(&folder, token.max_size);
+    info!("Token {} authorized to {} (folder: {:?})", token.name, permission, token.folder);
+    Ok(())
+}
+
+fn check_inbox_network(inbox: &InboxConfig, peer: IpAddr) -> Result<()> {
+    if !firewall::in_networks(&inbox.allow, peer)? {
+        warn!("Refused inbox {} for {}: not in its allow list", inbox.name, peer);
+        return Err(anyhow::anyhow!("Inbox can't be used from your address"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn config() -> Config {
+        toml::from_str(r#"
+            transfer_id = "aaaaaaa-bbbbbbb-ccccccc-ddddddd"
+            folder = "/srv/transfer"
+            allow_read = false
+            allow_delete = false
+
+            [[inboxes]]
+            name = "office"
+            transfer_id = "eeeeeee-fffffff-ggggggg-hhhhhhh"
+            allow = ["10.0.0.0/8"]
+
+            [[tokens]]
+            name = "uploader"
+            token = "upload-token"
+            permissions = ["upload"]
+            folder = "scans"
+            max_size = 1000
+            allow = ["192.0.2.0/24"]
+
+            [[tokens]]
+            name = "old"
+            token = "expired-token"
+            permissions = ["admin"]
+            expires = "2020-01-01T00:00:00Z"
+        "#).unwrap()
+    }
+
+    /// Every test uses its own address, so failures and bans don't carry over
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    fn refusal(result: Result<Option<Link>>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn default_id_needs_allow_read_and_allow_delete() {
+        let id = "aaaaaaa-bbbbbbb-ccccccc-ddddddd";
+        assert!(authorize(&mut config(), id, PERMISSION_UPLOAD, peer(1)).unwrap().is_none());
+        assert!(refusal(authorize(&mut config(), id, PERMISSION_LIST, peer(1))).contains("Read access is disabled"));
+        assert!(refusal(authorize(&mut config(), id, PERMISSION_DELETE, peer(1))).contains("Delete access is disabled"));
+
+        let mut open = config();
+        open.allow_read = true;
+        open.allow_delete = true;
+        assert!(authorize(&mut open.clone(), id, PERMISSION_DOWNLOAD, peer(1)).is_ok());
+        assert!(authorize(&mut open, id, PERMISSION_DELETE, peer(1)).is_ok());
+    }
+
+    #[test]
+    fn inbox_id_selects_the_inbox_from_its_networks_only() {
+        let id = "eeeeeee-fffffff-ggggggg-hhhhhhh";
+        assert!(refusal(authorize(&mut config(), id, PERMISSION_UPLOAD, peer(2))).contains("Inbox can't be used"));
+
+        let mut config = config();
+        authorize(&mut config, id, PERMISSION_UPLOAD, IpAddr::from([10, 0, 0, 2])).unwrap();
+        assert_eq!(config.folder, "/srv/transfer-office");
+    }
+
+    #[test]
+    fn token_is_limited_to_its_permissions_networks_and_folder() {
+        assert!(refusal(authorize(&mut config(), "upload-token", PERMISSION_DOWNLOAD, peer(3))).contains("not allowed to download"));
+        assert!(refusal(authorize(&mut config(), "upload-token", PERMISSION_UPLOAD, IpAddr::from([10, 0, 0, 3]))).contains("can't be used from your address"));
+        assert!(refusal(authorize(&mut config(), "expired-token", PERMISSION_UPLOAD, peer(3))).contains("Token expired"));
+
+        let mut config = config();
+        assert!(authorize(&mut config, "upload-token", PERMISSION_UPLOAD, peer(3)).unwrap().is_none());
+        assert_eq!(Path::new(&config.folder), Path::new("/srv/transfer/scans"));
+        assert_eq!(config.inbox_root(), "/srv/transfer");
+        assert_eq!(config.max_file_size, 1000);
+    }
+
+    #[test]
+    fn upload_link_can_only_upload() {
+        let link = links::create(Some("drop"), chrono::Duration::hours(1), 0, 1).unwrap();
+
+        let mut config = config();
+        let applied = authorize(&mut config, &link.token, PERMISSION_UPLOAD, peer(4)).unwrap();
+        assert_eq!(applied.unwrap().token, link.token);
+        assert_eq!(Path::new(&config.folder), Path::new("/srv/transfer/drop"));
+
+        assert!(refusal(authorize(&mut self::config(), &link.token, PERMISSION_LIST, peer(4))).contains("can only upload"));
+    }
+
+    #[test]
+    fn unknown_id_counts_as_failed_attempt() {
+        let mut config = config();
+        config.limits.max_auth_failures = 5;
+        assert!(refusal(authorize(&mut config, "guessed-id", PERMISSION_UPLOAD, peer(5))).contains("Invalid transfer ID"));
+
+        // Even the right ID has to wait until the backoff is over
+        let error = refusal(authorize(&mut config, "aaaaaaa-bbbbbbb-ccccccc-ddddddd", PERMISSION_UPLOAD, peer(5)));
+        assert!(error.contains("try again"), "{}", error);
+    }
+
+    #[test]
+    fn banned_address_is_refused_even_with_a_valid_id() {
+        let mut config = config();
+        config.limits.max_auth_failures = 1;
+        assert!(refusal(authorize(&mut config, "guessed-id", PERMISSION_UPLOAD, peer(6))).contains("Invalid transfer ID"));
+        assert!(bans::active_ban(peer(6)).unwrap().is_some());
+
+        let error = refusal(authorize(&mut config, "aaaaaaa-bbbbbbb-ccccccc-ddddddd", PERMISSION_UPLOAD, peer(6)));
+        assert!(error.contains("banned"), "{}", error);
+        let error = refusal(authorize(&mut config, "upload-token", PERMISSION_UPLOAD, peer(6)));
+        assert!(error.contains("banned"), "{}", error);
+    }
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use crate::metrics;
+
+/// Reading the command, manifest and sizes
+pub const STATE_HEADER: &str = "header";
+/// ACK sent, waiting for the first bytes
+pub const STATE_ACK: &str = "ack";
+pub const STATE_RECEIVING: &str = "receiving";
+/// All bytes of a file arrived, it is being flushed and moved into place
+pub const STATE_VERIFYING: &str = "verifying";
+pub const STATE_COMPLETE: &str = "complete";
+pub const STATE_FAILED: &str = "failed";
+
+/// Minimum time between two progress events of one transfer while receiving
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A TRANSFER, TREE or SESSION command in progress
+///
+/// Also sent as a progress event whenever its state changes and a few
+/// times a second while data arrives.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveInfo {
+    pub id: u64,
+    pub peer: String,
+    /// "file", "tree", "batch" or "http"
+    pub kind: &'static str,
+    /// File or directory name, empty until the sender announced it
+    pub name: String,
+    pub files: usize,
+    pub size: u64,
+    /// RFC 3339, UTC
+    pub started: String,
+    /// One of the STATE_* constants
+    pub state: &'static str,
+    /// File currently being received
+    pub file: String,
+    /// Bytes of file contents received so far, across all files
+    pub bytes: u64,
+    /// Bytes per second since the first byte arrived
+    pub rate: u64,
+    /// Seconds until the remaining bytes arrive at the current rate
+    pub eta_secs: Option<u64>,
+    #[serde(skip)]
+    receiving_since: Option<Instant>,
+    #[serde(skip)]
+    last_event: Option<Instant>,
+}
+
+static ACTIVE: Lazy<Mutex<BTreeMap<u64, ActiveInfo>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static EVENTS: Lazy<broadcast::Sender<ActiveInfo>> = Lazy::new(|| broadcast::channel(256).0);
+
+/// Transfers currently in progress, oldest first
+pub fn active() -> Vec<ActiveInfo> {
+    ACTIVE.lock().unwrap().values().cloned().collect()
+}
+
+/// Receives a progress event for every update of an active transfer
+pub fn subscribe() -> broadcast::Receiver<ActiveInfo> {
+    EVENTS.subscribe()
+}
+
+/// Applies `change` to an active transfer and publishes the result
+///
+/// `change` returns true for state changes, which are published right away;
+/// other updates are limited to one event per PROGRESS_INTERVAL.
+fn update(id: u64, change: impl FnOnce(&mut ActiveInfo) -> bool) {
+    let mut active = ACTIVE.lock().unwrap();
+    let Some(info) = active.get_mut(&id) else {
+        return;
+    };
+    let force = change(info);
+
+    let now = Instant::now();
+    if force || info.last_event.is_none_or(|last| now.duration_since(last) >= PROGRESS_INTERVAL) {
+        info.last_event = Some(now);
+        // Nobody listening is fine
+        let _ = EVENTS.send(info.clone());
+    }
+}
+
+/// Lists a transfer as active until dropped
+///
+/// Dropping it before `complete` was called reports the transfer as failed.
+pub struct ActiveTransfer {
+    id: u64,
+}
+
+impl ActiveTransfer {
+    pub fn start(peer: SocketAddr, kind: &'static str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let started: DateTime<Utc> = Utc::now();
+        let info = ActiveInfo {
+            id,
+            peer: peer.ip().to_canonical().to_string(),
+            kind,
+            name: String::new(),
+            files: 0,
+            size: 0,
+            started: started.to_rfc3339_opts(SecondsFormat::Secs, true),
+            state: STATE_HEADER,
+            file: String::new(),
+            bytes: 0,
+            rate: 0,
+            eta_secs: None,
+            receiving_since: None,
+            last_event: None,
+        };
+
+        {
+            let mut active = ACTIVE.lock().unwrap();
+            active.insert(id, info);
+            metrics::set_active_transfers(active.len());
+        }
+        update(id, |_| true);
+        Self { id }
+    }
+
+    /// Fills in what is being sent once the sender announced it
+    pub fn describe(&self, name: &str, files: usize, size: u64) {
+        update(self.id, |info| {
+            info.name = name.to_string();
+            info.file = name.to_string();
+            info.files = files;
+            info.size = size;
+            true
+        });
+    }
+
+    /// Reports that the ACK was sent
+    pub fn acknowledged(&self) {
+        self.progress().set_state(STATE_ACK);
+    }
+
+    /// Reports that the transfer succeeded
+    pub fn complete(&self) {
+        self.progress().set_state(STATE_COMPLETE);
+    }
+
+    /// Handle for reporting received bytes, see `IncomingFile::track`
+    pub fn progress(&self) -> Progress {
+        Progress { id: self.id }
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        let failed = ACTIVE.lock().unwrap().get(&self.id).is_some_and(|info| info.state != STATE_COMPLETE);
+        if failed {
+            self.progress().set_state(STATE_FAILED);
+        }
+
+        let mut active = ACTIVE.lock().unwrap();
+        active.remove(&self.id);
+        metrics::set_active_transfers(active.len());
+    }
+}
+
+/// Reports the progress of an active transfer while its files arrive
+#[derive(Debug, Clone)]
+pub struct Progress {
+    id: u64,
+}
+
+impl Progress {
+    /// Names the file of a tree or batch that is received next
+    pub fn file(&self, name: &str) {
+        update(self.id, |info| {
+            info.file = name.to_string();
+            false
+        });
+    }
+
+    /// Counts bytes of file contents received
+    pub fn add(&self, bytes: usize) {
+        update(self.id, |info| {
+            let now = Instant::now();
+            let since = *info.receiving_since.get_or_insert(now);
+
+            info.bytes += bytes as u64;
+            let elapsed = now.duration_since(since).as_secs_f64();
+            if elapsed > 0.0 {
+                info.rate = (info.bytes as f64 / elapsed) as u64;
+            }
+            info.eta_secs = (info.rate > 0).then(|| info.size.saturating_sub(info.bytes).div_ceil(info.rate));
+
+            let changed = info.state != STATE_RECEIVING;
+            info.state = STATE_RECEIVING;
+            changed
+        });
+    }
+
+    pub fn set_state(&self, state: &'static str) {
+        update(self.id, |info| {
+            let changed = info.state != state;
+            info.state = state;
+            changed
+        });
+    }
+}
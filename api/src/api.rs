@@ -1,28 +1,121 @@
 use anyhow::{Context, Result};
 use log::{info, warn, error};
-use std::path::PathBuf;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
     fs,
 };
-use crate::config::Config;
+use crate::access;
+use crate::activity::{self, Progress};
+use crate::approval::{self, Decision, PendingTransfer};
+use crate::beacon;
+use crate::compression::{self, FrameDecoder};
+use crate::config::{Config, LimitsConfig};
+use crate::download;
+use crate::firewall::Firewall;
+use crate::history;
+use crate::limits::{self, LimitExceeded, LimitedStream};
+use crate::metadata::{self, FileMetadata};
+use crate::metrics;
+use crate::session;
+use crate::storage::{self, IncomingFile};
 use crate::structure;
+use crate::tree;
 
-/// Start the custom TCP transfer server
-pub async fn start_tcp_server(bind: &str, port: u16) -> Result<()> {
-    let addr = format!("{}:{}", bind, port); // Transfer server runs on main port
-    let listener = TcpListener::bind(&addr).await
-        .with_context(|| format!("Failed to bind TCP transfer server to {}", addr))?;
+/// Version of the transfer protocol, announced during discovery
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features this server supports, announced during discovery
+pub const CAPABILITIES: &[&str] = &["compress", "tree", "session", "transactional", "metadata"];
+
+/// Start the custom TCP transfer server (and the UDP discovery beacon, if enabled)
+///
+/// Connections `firewall` refuses are closed before anything is read from them,
+/// and the beacon doesn't answer them.
+pub async fn start_tcp_server(config: &Config, firewall: Arc<Firewall>) -> Result<()> {
+    if config.beacon {
+        let (beacon_port, name, port) = (config.beacon_port, config.name.clone(), config.port);
+        let firewall = firewall.clone();
+        tokio::spawn(async move {
+            if let Err(e) = beacon::run(beacon_port, name, port, firewall).await {
+                warn!("UDP beacon stopped: {:#}", e);
+            }
+        });
+    }
+    
+    let addresses = config.listen_addresses()?;
     
-    info!("Custom TCP transfer server listening on {}", addr);
+    // A wildcard IPv6 listener also accepts IPv4, unless IPv4 is listened on separately
+    let mut listeners = Vec::with_capacity(addresses.len());
+    for addr in &addresses {
+        let separate_ipv4 = addresses.iter()
+            .any(|other| other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port());
+        listeners.push(bind_listener(*addr, !separate_ipv4)?);
+        info!("Custom TCP transfer server listening on {}", addr);
+    }
+    
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for listener in listeners {
+        accept_loops.spawn(accept_connections(listener, firewall.clone(), config.limits.clone()));
+    }
+    while accept_loops.join_next().await.is_some() {}
     
+    Ok(())
+}
+
+/// Binds a listening socket; `dual_stack` lets an IPv6 wildcard accept IPv4 connections too
+pub fn bind_listener(addr: SocketAddr, dual_stack: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .context("Failed to create TCP socket")?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!(dual_stack && addr.ip().is_unspecified()))
+            .context("Failed to configure IPv6 socket")?;
+    }
+    socket.set_reuse_address(true)
+        .context("Failed to configure TCP socket")?;
+    socket.set_nonblocking(true)
+        .context("Failed to configure TCP socket")?;
+    socket.bind(&addr.into())
+        .with_context(|| format!("Failed to bind TCP transfer server to {}", addr))?;
+    socket.listen(1024)
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    
+    TcpListener::from_std(socket.into())
+        .with_context(|| format!("Failed to register listener on {}", addr))
+}
+
+/// Accepts connections on one listener forever
+async fn accept_connections(listener: TcpListener, firewall: Arc<Firewall>, limits: LimitsConfig) {
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
+            Ok((mut stream, addr)) => {
+                if !firewall.permits(addr) {
+                    drop(stream);
+                    continue;
+                }
+                let slot = match limits::admit(&limits, addr.ip()) {
+                    Ok(slot) => slot,
+                    Err(e) => {
+                        warn!("Refused connection from {}: {}", addr, e);
+                        tokio::spawn(async move {
+                            let refusal = format!("ERROR: {}\n", e);
+                            let _ = tokio::time::timeout(REFUSAL_TIMEOUT, stream.write_all(refusal.as_bytes())).await;
+                        });
+                        continue;
+                    }
+                };
                 info!("New TCP transfer connection from: {}", addr);
+                metrics::connection_accepted();
+                let limits = limits.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_tcp_connection(stream).await {
+                    let _slot = slot;
+                    if let Err(e) = handle_connection(stream, addr, limits).await {
                         error!("Error handling TCP connection from {}: {}", addr, e);
                     }
                 });
@@ -34,27 +127,54 @@ pub async fn start_tcp_server(bind: &str, port: u16) -> Result<()> {
     }
 }
 
-/// Handle TCP connection with custom transfer protocol
-async fn handle_tcp_connection(mut stream: TcpStream) -> Result<()> {
-    let mut buffer = vec![0; 8192];
+/// How long a refused client gets to take its ERROR line
+const REFUSAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Handle a connection with the custom transfer protocol (TCP or bridged from WebSocket)
+///
+/// Exceeding one of `limits` sends the limit as ERROR line and closes the connection.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, peer: SocketAddr, limits: LimitsConfig) -> Result<()> {
+    let mut stream = BufReader::with_capacity(8192, LimitedStream::new(stream, &limits));
     
     loop {
         // Read command from client
-        let n = stream.read(&mut buffer).await
-            .context("Failed to read from TCP stream")?;
+        stream.get_mut().set_in_command(false);
+        let read = tokio::time::timeout(limits::command_timeout(&limits), stream.fill_buf()).await
+            .unwrap_or_else(|_| Err(limits::command_timed_out(&limits).into()));
+        let buffer = match read {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                let e = anyhow::Error::new(e);
+                if let Some(limit) = LimitExceeded::find(&e) {
+                    return send_limit_error(&mut stream, peer, limit).await;
+                }
+                return Err(e.context("Failed to read from stream"));
+            }
+        };
             
-        if n == 0 {
+        if buffer.is_empty() {
             info!("Client disconnected");
             break;
         }
         
-        let command = String::from_utf8_lossy(&buffer[..n]).trim().to_string();
+        // Commands end at a newline; clients that don't send one get the whole read as command
+        let (command_len, consumed) = match buffer.iter().position(|&b| b == b'\n') {
+            Some(newline) => (newline, newline + 1),
+            None => (buffer.len(), buffer.len()),
+        };
+        let command = String::from_utf8_lossy(&buffer[..command_len]).trim().to_string();
+        stream.consume(consumed);
         info!("Received command: {}", command);
         
         // Parse and handle command
-        let response = match parse_and_handle_command(&command, &mut stream).await {
+        stream.get_mut().set_in_command(true);
+        let response = match parse_and_handle_command(&command, peer, &mut stream).await {
             Ok(resp) => resp,
             Err(e) => {
+                // The stream is in an unknown state after a limit, so the connection ends here
+                if let Some(limit) = LimitExceeded::find(&e) {
+                    return send_limit_error(&mut stream, peer, limit).await;
+                }
                 error!("Command error: {}", e);
                 format!("ERROR: {}", e)
             }
@@ -63,97 +183,194 @@ async fn handle_tcp_connection(mut stream: TcpStream) -> Result<()> {
         // Send response back to client (unless it was a file transfer)
         if !response.is_empty() {
         stream.write_all(response.as_bytes()).await
-            .context("Failed to write response to TCP stream")?;
+            .context("Failed to write response to stream")?;
         stream.write_all(b"\n").await
-            .context("Failed to write newline to TCP stream")?;
+            .context("Failed to write newline to stream")?;
         }
     }
     
     Ok(())
 }
 
-/// Parse and handle the custom TRANSFER command
-async fn parse_and_handle_command(command: &str, stream: &mut TcpStream) -> Result<String> {
+/// Tells the client which limit ended its connection
+async fn send_limit_error<S: AsyncWrite + Unpin>(stream: &mut S, peer: SocketAddr, limit: &LimitExceeded) -> Result<()> {
+    warn!("Closing connection from {}: {}", peer, limit);
+    stream.write_all(format!("ERROR: {}\n", limit).as_bytes()).await
+        .context("Failed to write response to stream")?;
+    stream.flush().await
+        .context("Failed to write response to stream")
+}
+
+/// Optional key=value settings appended to a TRANSFER, TREE or SESSION command
+#[derive(Debug, Default)]
+pub struct TransferOptions {
+    /// Compression requested by the sender (only "zstd" is supported)
+    pub compress: Option<String>,
+    /// All-or-nothing batch: files are staged until the sender sends COMMIT
+    pub transactional: bool,
+    /// Metadata of the file (TRANSFER only): mtime=, mode= (octal) and xattr.<name>= (hex)
+    pub metadata: FileMetadata,
+}
+
+/// Arguments shared by the TRANSFER and TREE commands
+/// <transfer_id> <name> [<folder>] [key=value ...]
+struct TransferArgs<'a> {
+    transfer_id: &'a str,
+    name: &'a str,
+    folder: Option<&'a str>,
+    options: TransferOptions,
+}
+
+/// Splits trailing key=value options off the TRANSFER arguments
+fn split_options(args: &str) -> (&str, TransferOptions) {
+    let mut options = TransferOptions::default();
+    let mut rest = args.trim_end();
+    
+    while let Some(last_space) = rest.rfind(' ') {
+        let Some((key, value)) = rest[last_space + 1..].split_once('=') else {
+            break;
+        };
+        match key {
+            "compress" => options.compress = Some(value.to_string()),
+            "transactional" => options.transactional = value == "true",
+            _ if options.metadata.set_option(key, value) => {}
+            _ => break,
+        }
+        rest = rest[..last_space].trim_end();
+    }
+    
+    (rest, options)
+}
+
+/// Parses <transfer_id> <name> [<folder>] [key=value ...]
+fn parse_transfer_args(remaining: &str) -> Option<TransferArgs<'_>> {
+    // Find the first space to separate transfer_id from the rest
+    let first_space = remaining.find(' ')?;
+    let transfer_id = &remaining[..first_space];
+    let (rest, options) = split_options(remaining[first_space + 1..].trim());
+    
+    // Find the last space to separate folder from filename (if folder exists)
+    // We assume that if there are multiple spaces, the last "word" is the folder
+    // and everything before it is the filename
+    if let Some(last_space) = rest.rfind(' ') {
+        // Check if the last part looks like a folder (no file extension)
+        let potential_folder = &rest[last_space + 1..];
+        let potential_filename = &rest[..last_space];
+        
+        // If the potential folder doesn't contain a dot, treat it as a folder
+        // Otherwise, treat the entire rest as filename
+        if !potential_folder.contains('.') && !potential_folder.is_empty() {
+            return Some(TransferArgs { transfer_id, name: potential_filename, folder: Some(potential_folder), options });
+        }
+    }
+    
+    // No folder, so it's just filename
+    Some(TransferArgs { transfer_id, name: rest, folder: None, options })
+}
+
+/// Parse and handle the custom TRANSFER, TREE, SESSION, LIST, GET and DELETE commands
+async fn parse_and_handle_command<S: AsyncRead + AsyncWrite + Unpin>(command: &str, peer: SocketAddr, stream: &mut S) -> Result<String> {
     let command = command.trim();
     
     if command.is_empty() {
         return Err(anyhow::anyhow!("Empty command"));
     }
     
-    // Check if it starts with TRANSFER
-    if !command.to_uppercase().starts_with("TRANSFER ") {
-        let first_word = command.split_whitespace().next().unwrap_or("");
-        return Err(anyhow::anyhow!("Unknown command: {}. Available commands: TRANSFER", first_word));
-    }
-    
-    // Remove "TRANSFER " prefix (8 characters)
-    let remaining = &command[8..].trim();
+    // Separate the command word from its arguments
+    let (verb, remaining) = command.split_once(' ').unwrap_or((command, ""));
+    let remaining = remaining.trim();
     
-    // Find the first space to separate transfer_id from the rest
-    if let Some(first_space) = remaining.find(' ') {
-        let transfer_id = &remaining[..first_space];
-        let rest = &remaining[first_space + 1..].trim();
-        
-        // Find the last space to separate folder from filename (if folder exists)
-        // We assume that if there are multiple spaces, the last "word" is the folder
-        // and everything before it is the filename
-        if let Some(last_space) = rest.rfind(' ') {
-            // Check if the last part looks like a folder (no file extension)
-            let potential_folder = &rest[last_space + 1..];
-            let potential_filename = &rest[..last_space];
-    
-            // If the potential folder doesn't contain a dot, treat it as a folder
-            // Otherwise, treat the entire rest as filename
-            if !potential_folder.contains('.') && !potential_folder.is_empty() {
-                handle_transfer_command(transfer_id, potential_filename, Some(potential_folder), stream).await
-            } else {
-                handle_transfer_command(transfer_id, rest, None, stream).await
+    match verb.to_uppercase().as_str() {
+        "TRANSFER" => {
+            let args = parse_transfer_args(remaining)
+                .context("TRANSFER command usage: TRANSFER <transfer_id> <file> [<folder>] [compress=zstd] [mtime=<secs>] [mode=<octal>] [xattr.<name>=<hex>]")?;
+            handle_transfer_command(args.transfer_id, args.name, args.folder, &args.options, peer, stream).await
+        }
+        "TREE" => {
+            let args = parse_transfer_args(remaining)
+                .context("TREE command usage: TREE <transfer_id> <directory> [<folder>] [compress=zstd]")?;
+            tree::handle_tree_command(args.transfer_id, args.name, args.folder, &args.options, peer, stream).await
+        }
+        "SESSION" => {
+            let (args, options) = split_options(remaining);
+            let mut words = args.split_whitespace();
+            let transfer_id = words.next()
+                .context("SESSION command usage: SESSION <transfer_id> [<folder>] [compress=zstd] [transactional=true]")?;
+            let folder = words.next();
+            session::handle_session_command(transfer_id, folder, &options, peer, stream).await
+        }
+        "LIST" => {
+            // The folder is the rest of the line and may contain spaces
+            let (transfer_id, folder) = match remaining.split_once(' ') {
+                Some((transfer_id, folder)) => (transfer_id, Some(folder.trim())),
+                None => (remaining, None),
+            };
+            if transfer_id.is_empty() {
+                return Err(anyhow::anyhow!("LIST command usage: LIST <transfer_id> [<folder>]"));
             }
-        } else {
-            // No additional spaces, so it's just filename
-            handle_transfer_command(transfer_id, rest, None, stream).await
+            download::handle_list_command(transfer_id, folder, peer, stream).await
         }
-    } else {
-        return Err(anyhow::anyhow!("TRANSFER command usage: TRANSFER <transfer_id> <file> [<folder>]"));
+        "GET" => {
+            let (args, options) = split_options(remaining);
+            // Share tokens don't need a path
+            let (transfer_id, path) = args.split_once(' ').unwrap_or((args, ""));
+            if transfer_id.is_empty() {
+                return Err(anyhow::anyhow!("GET command usage: GET <transfer_id> <path> [compress=zstd]"));
+            }
+            download::handle_get_command(transfer_id, path.trim(), &options, peer, stream).await
+        }
+        "DELETE" => {
+            let (transfer_id, path) = remaining.split_once(' ')
+                .context("DELETE command usage: DELETE <transfer_id> <path>")?;
+            download::handle_delete_command(transfer_id, path.trim(), peer).await
+        }
+        _ => Err(anyhow::anyhow!("Unknown command: {}. Available commands: TRANSFER, TREE, SESSION, LIST, GET, DELETE", verb)),
     }
 }
 
 /// Handle TRANSFER command - receives a file with the given transfer_id
-async fn handle_transfer_command(transfer_id: &str, filename: &str, folder: Option<&str>, stream: &mut TcpStream) -> Result<String> {
+/// With approval enabled the ACK is only sent once the local user accepts
+/// Metadata sent as command options is applied according to the [metadata] config
+async fn handle_transfer_command<S: AsyncRead + AsyncWrite + Unpin>(transfer_id: &str, filename: &str, folder: Option<&str>, options: &TransferOptions, peer: SocketAddr, stream: &mut S) -> Result<String> {
     info!("Handling TRANSFER command - transfer_id: {}, file: {}, folder: {:?}", transfer_id, filename, folder);
     
     // Load config to verify we can accept this transfer
-    let config = Config::load_or_create()
+    let mut config = Config::current()
         .context("Failed to load config")?;
     
-    // Check if this is our transfer_id (optional validation)
-    if transfer_id != config.transfer_id {
-        warn!("Transfer ID mismatch. Expected: {}, Received: {}", config.transfer_id, transfer_id);
-        // Still allow the transfer but log the mismatch
-    }
+    // Tokens and upload links narrow the folder and file size limit
+    let link = access::authorize(&mut config, transfer_id, access::PERMISSION_UPLOAD, peer.ip())?;
+    
+    // Every attempt ends up in the transfer history, including refused ones
+    let attempt = history::Attempt::start(&config, peer, transfer_id, "file");
+    let active = activity::ActiveTransfer::start(peer, "file");
+    let record_failure = |size: u64, code: &str, e: &anyhow::Error| attempt.record(attempt.failed(filename, size, code, e));
+    
+    let filename = structure::sanitize_filename(filename)
+        .inspect_err(|e| record_failure(0, history::RESULT_FAILED, e))?;
     
     // Ensure target directory exists and get the path
     let receive_dir = structure::ensure_directory_exists(&config.folder, folder).await
-        .context("Failed to ensure receive directory exists")?;
+        .context("Failed to ensure receive directory exists")
+        .inspect_err(|e| record_failure(0, history::RESULT_FAILED, e))?;
     
     // Read file size first to check limits BEFORE sending ACK
     let mut buffer = [0u8; 8];
     stream.read_exact(&mut buffer).await
-        .context("Failed to read file size")?;
+        .context("Failed to read file size")
+        .inspect_err(|e| record_failure(0, history::RESULT_FAILED, e))?;
     let file_size = u64::from_be_bytes(buffer);
+    active.describe(filename, 1, file_size);
     
     info!("Incoming file: {} ({} bytes)", filename, file_size);
     
     // Check size limits before sending ACK
     if let Err(e) = check_size_limits(&config, file_size, &receive_dir).await {
         error!("Size limit exceeded: {}", e);
+        record_failure(file_size, history::RESULT_SIZE_LIMIT, &e);
     
         // Send error response instead of ACK
-        let error_msg = if e.to_string().contains("File size") {
-            format!("FILE_SIZE_LIMIT_EXCEEDED: {}", e)
-        } else {
-            format!("FOLDER_SIZE_LIMIT_EXCEEDED: {}", e)
-        };
+        let error_msg = size_limit_response(&e);
         
         stream.write_all(error_msg.as_bytes()).await
             .context("Failed to send error response")?;
@@ -163,113 +380,246 @@ async fn handle_transfer_command(transfer_id: &str, filename: &str, folder: Opti
         return Err(e);
     }
     
+    storage::check_conflict(&receive_dir, filename, &config.conflict).await
+        .inspect_err(|e| record_failure(file_size, history::RESULT_REJECTED, e))?;
+    
+    // Wait for the receiver to accept the transfer (approval mode)
+    require_approval(&config, PendingTransfer::new(peer, "file", filename, 1, file_size, folder), stream).await
+        .inspect_err(|e| record_failure(file_size, history::RESULT_REJECTED, e))?;
+    if let Some(link) = &link {
+        link.redeem()
+            .inspect_err(|e| record_failure(file_size, history::RESULT_REJECTED, e))?;
+    }
+    
+    // Agree to compression only if the sender asked for it and it is enabled
+    let compressed = negotiate_compression(&config, options);
+    
     // Send acknowledgment only if size limits are OK
-    stream.write_all(b"ACK\n").await
+    stream.write_all(ack_line(compressed).as_bytes()).await
         .context("Failed to send ACK")?;
+    active.acknowledged();
     
     // Receive file data
-    match receive_file_data_with_size(stream, &receive_dir, filename, file_size as usize).await {
-        Ok(received_filename) => {
+    match receive_file_data_with_size(stream, &receive_dir, filename, file_size, compressed, &config.conflict, active.progress()).await {
+        Ok((received_filename, checksum)) => {
             info!("Successfully received file: {}", received_filename);
             
+            metadata::apply(&receive_dir.join(&received_filename), &options.metadata, &config.metadata);
+            attempt.record(attempt.succeeded(filename, &received_filename, file_size, checksum));
+            active.complete();
+            
             Ok(format!("TRANSFER_COMPLETE: {}", received_filename))
                 }
                 Err(e) => {
             error!("Failed to receive file: {}", e);
+            record_failure(file_size, history::RESULT_FAILED, &e);
             
             Err(e)
                 }
             }
         }
 
-/// Generate a unique filename if the original already exists
-async fn generate_unique_filename(transfer_dir: &PathBuf, filename: &str) -> String {
-    let file_path = transfer_dir.join(filename);
-    
-    // If file doesn't exist, use original name
-    if !tokio::fs::try_exists(&file_path).await.unwrap_or(false) {
-        return filename.to_string();
+/// Returns true if the payload will be sent zstd compressed
+pub fn negotiate_compression(config: &Config, options: &TransferOptions) -> bool {
+    config.compression && options.compress.as_deref() == Some(compression::ZSTD)
+}
+
+/// Builds the ACK line, announcing the agreed compression if any
+pub fn ack_line(compressed: bool) -> String {
+    if compressed {
+        format!("ACK compress={}\n", compression::ZSTD)
+    } else {
+        "ACK\n".to_string()
     }
-    
-    // Split filename into name and extension
-    let path = std::path::Path::new(filename);
-    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(filename);
-    let extension = path.extension().and_then(|s| s.to_str());
-    
-    // Try incrementing numbers until we find a unique name
-    let mut counter = 1;
+}
+
+/// Longest command line accepted while a command is in progress (e.g. COMMIT)
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Reads a newline-terminated line from the stream
+/// Returns None if the client disconnected before sending a full line
+pub async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<String>> {
+    let mut line = Vec::new();
     loop {
-        let new_filename = if let Some(ext) = extension {
-            format!("{}{}.{}", stem, counter, ext)
-        } else {
-            format!("{}{}", stem, counter)
+        let byte = match stream.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read from stream"),
         };
-        
-        let new_file_path = transfer_dir.join(&new_filename);
-        if !tokio::fs::try_exists(&new_file_path).await.unwrap_or(false) {
-            return new_filename;
+        if byte == b'\n' {
+            return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
         }
-        
-        counter += 1;
-    
-        // Safety check to prevent infinite loop
-        if counter > 9999 {
-            // Fallback to timestamp-based naming
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-            
-            return if let Some(ext) = extension {
-                format!("{}{}.{}", stem, timestamp, ext)
-            } else {
-                format!("{}{}", stem, timestamp)
-            };
-            }
+        if line.len() >= MAX_LINE_LENGTH {
+            return Err(anyhow::anyhow!("Line exceeds maximum length of {} bytes", MAX_LINE_LENGTH));
         }
+        line.push(byte);
+    }
 }
 
+/// Size of the chunks read from the stream while receiving file data
+const RECEIVE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest compressed frame accepted from a sender
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
 /// Receive file data from TCP stream when size is already known
-async fn receive_file_data_with_size(stream: &mut TcpStream, transfer_dir: &PathBuf, filename: &str, file_size: usize) -> Result<String> {
-    info!("Receiving file: {} ({} bytes)", filename, file_size);
+/// Returns the stored name and the checksum of the contents
+async fn receive_file_data_with_size<S: AsyncRead + Unpin>(stream: &mut S, transfer_dir: &Path, filename: &str, file_size: u64, compressed: bool, conflict: &str, progress: Progress) -> Result<(String, String)> {
+    info!("Receiving file: {} ({} bytes, compressed: {})", filename, file_size, compressed);
     
-    // Read file data
-    let mut file_data = vec![0u8; file_size];
-    stream.read_exact(&mut file_data).await
-        .context("Failed to read file data")?;
+    let mut incoming = IncomingFile::create(transfer_dir, filename).await?;
+    incoming.track(progress);
     
-    // Generate unique filename if original already exists
-    let unique_filename = generate_unique_filename(transfer_dir, filename).await;
-    let file_path = transfer_dir.join(&unique_filename);
+    if let Err(e) = receive_payload(stream, &mut incoming, file_size, compressed).await {
+        incoming.abort().await;
+        return Err(e);
+    }
     
-    info!("Saving file as: {}", unique_filename);
+    // Save file to the received directory under a unique name
+    let checksum = incoming.checksum();
+    let unique_filename = incoming.finish(conflict).await?;
     
-    // Save file to the received directory
-    tokio::fs::write(&file_path, &file_data).await
-        .with_context(|| format!("Failed to write file: {}", file_path.display()))?;
+    info!("Saved file as: {}", unique_filename);
     
-    Ok(unique_filename)
+    Ok((unique_filename, checksum))
+}
+
+/// Reads the payload of one file into `incoming`
+/// `file_size` is the uncompressed size announced by the sender
+pub async fn receive_payload<S: AsyncRead + Unpin>(stream: &mut S, incoming: &mut IncomingFile, file_size: u64, compressed: bool) -> Result<()> {
+    if compressed {
+        receive_frames(stream, incoming, file_size).await
+    } else {
+        receive_raw(stream, incoming, file_size).await
+    }
 }
 
-/// Calculate the total size of all files in a directory
-async fn calculate_folder_size(folder_path: &PathBuf) -> Result<u64> {
+/// Reads exactly `file_size` uncompressed bytes
+async fn receive_raw<S: AsyncRead + Unpin>(stream: &mut S, incoming: &mut IncomingFile, file_size: u64) -> Result<()> {
+    // Stream file data to disk in chunks instead of buffering it in memory
+    let mut buffer = vec![0u8; RECEIVE_CHUNK_SIZE];
+    let mut remaining = file_size;
+    while remaining > 0 {
+        let chunk_len = remaining.min(buffer.len() as u64) as usize;
+        stream.read_exact(&mut buffer[..chunk_len]).await
+            .context("Failed to read file data")?;
+        incoming.write(&buffer[..chunk_len]).await?;
+        remaining -= chunk_len as u64;
+    }
+    Ok(())
+}
+
+/// Reads compressed frames until the end marker and writes the decompressed data
+async fn receive_frames<S: AsyncRead + Unpin>(stream: &mut S, incoming: &mut IncomingFile, file_size: u64) -> Result<()> {
+    let mut decoder = FrameDecoder::new(file_size)?;
+    let mut frame = Vec::new();
+    
+    loop {
+        let frame_len = stream.read_u32().await
+            .context("Failed to read compressed frame length")? as usize;
+        if frame_len == 0 {
+            break;
+        }
+        if frame_len > MAX_FRAME_SIZE {
+            return Err(anyhow::anyhow!("Compressed frame of {} bytes exceeds maximum of {} bytes", frame_len, MAX_FRAME_SIZE));
+        }
+        
+        frame.resize(frame_len, 0);
+        stream.read_exact(&mut frame).await
+            .context("Failed to read compressed frame")?;
+        
+        let data = decoder.update(&frame)?;
+        incoming.write(&data).await?;
+    }
+    
+    if decoder.produced() != file_size {
+        return Err(anyhow::anyhow!(
+            "Decompressed size {} bytes does not match announced size {} bytes",
+            decoder.produced(), file_size
+        ));
+    }
+    
+    Ok(())
+}
+
+/// Reads and discards the payload of a file that can't be stored, keeping the stream in sync
+pub async fn discard_payload<S: AsyncRead + Unpin>(stream: &mut S, file_size: u64, compressed: bool) -> Result<()> {
+    if compressed {
+        loop {
+            let frame_len = stream.read_u32().await
+                .context("Failed to read compressed frame length")? as u64;
+            if frame_len == 0 {
+                return Ok(());
+            }
+            if frame_len > MAX_FRAME_SIZE as u64 {
+                return Err(anyhow::anyhow!("Compressed frame of {} bytes exceeds maximum of {} bytes", frame_len, MAX_FRAME_SIZE));
+            }
+            tokio::io::copy(&mut (&mut *stream).take(frame_len), &mut tokio::io::sink()).await
+                .context("Failed to read compressed frame")?;
+        }
+    }
+    
+    let discarded = tokio::io::copy(&mut (&mut *stream).take(file_size), &mut tokio::io::sink()).await
+        .context("Failed to read file data")?;
+    if discarded != file_size {
+        return Err(anyhow::anyhow!("Connection closed while reading file data"));
+    }
+    Ok(())
+}
+
+/// Holds an incoming transfer until the local user decides on it (see [approval] in the config)
+/// A rejection or timeout is sent to the sender as `REJECTED: <reason>` instead of the ACK
+pub async fn require_approval<S: AsyncWrite + Unpin>(config: &Config, transfer: PendingTransfer, stream: &mut S) -> Result<()> {
+    match approval::request(&config.approval, transfer).await {
+        Decision::Accepted => Ok(()),
+        Decision::Rejected(reason) => {
+            warn!("Transfer rejected: {}", reason);
+            metrics::rejected(metrics::REASON_APPROVAL);
+            
+            stream.write_all(format!("REJECTED: {}\n", reason).as_bytes()).await
+                .context("Failed to send rejection")?;
+            
+            Err(anyhow::anyhow!("Transfer {}", reason))
+        }
+    }
+}
+
+/// Builds the response sent instead of ACK when a size limit is exceeded
+pub fn size_limit_response(e: &anyhow::Error) -> String {
+    let message = e.to_string();
+    if message.contains("File size") || message.contains("upload link") {
+        format!("FILE_SIZE_LIMIT_EXCEEDED: {}", e)
+    } else {
+        format!("FOLDER_SIZE_LIMIT_EXCEEDED: {}", e)
+    }
+}
+
+/// Calculate the total size of all files in a directory, including subdirectories
+///
+/// Staged batches don't count until they are committed.
+pub async fn calculate_folder_size(folder_path: &Path) -> Result<u64> {
     let mut total_size = 0u64;
     
     if !folder_path.exists() {
         return Ok(0);
     }
     
-    let mut dir = fs::read_dir(folder_path).await
-        .context("Failed to read directory")?;
-    
-    while let Some(entry) = dir.next_entry().await
-        .context("Failed to read directory entry")? {
+    let mut pending = vec![folder_path.to_path_buf()];
+    while let Some(dir_path) = pending.pop() {
+        let mut dir = fs::read_dir(&dir_path).await
+            .context("Failed to read directory")?;
         
-        let metadata = entry.metadata().await
-            .context("Failed to get file metadata")?;
-        
-        if metadata.is_file() {
-            total_size += metadata.len();
+        while let Some(entry) = dir.next_entry().await
+            .context("Failed to read directory entry")? {
+            
+            let metadata = entry.metadata().await
+                .context("Failed to get file metadata")?;
+            
+            if metadata.is_file() {
+                total_size = total_size.saturating_add(metadata.len());
+            } else if metadata.is_dir() && entry.file_name() != storage::STAGING_DIR {
+                pending.push(entry.path());
+            }
         }
     }
     
@@ -277,27 +627,50 @@ async fn calculate_folder_size(folder_path: &PathBuf) -> Result<u64> {
 }
 
 /// Check if file size and folder size limits are respected
-async fn check_size_limits(config: &Config, file_size: u64, folder_path: &PathBuf) -> Result<()> {
-    // Check individual file size limit
+pub async fn check_size_limits(config: &Config, file_size: u64, folder_path: &Path) -> Result<()> {
+    check_file_size_limit(config, file_size)?;
+    check_folder_size_limit(config, file_size, folder_path).await
+}
+
+/// Check the individual file size limit
+pub fn check_file_size_limit(config: &Config, file_size: u64) -> Result<()> {
     if config.max_file_size > 0 && file_size > config.max_file_size {
+        metrics::rejected(metrics::REASON_FILE_SIZE);
         return Err(anyhow::anyhow!(
             "File size {} bytes exceeds maximum allowed file size {} bytes",
             file_size, config.max_file_size
         ));
     }
     
-    // Check folder size limit (if enabled)
+    Ok(())
+}
+
+/// Bytes that can still be added to the folder, None if its size isn't limited
+pub async fn folder_space(config: &Config, folder_path: &Path) -> Result<Option<u64>> {
+    if config.max_folder_size == 0 {
+        return Ok(None);
+    }
+    let current_folder_size = calculate_folder_size(folder_path).await?;
+    metrics::folder_usage(folder_path, current_folder_size, config.max_folder_size);
+    Ok(Some(config.max_folder_size.saturating_sub(current_folder_size)))
+}
+
+/// Check that adding `added_size` bytes keeps the folder within its limit (if enabled)
+pub async fn check_folder_size_limit(config: &Config, added_size: u64, folder_path: &Path) -> Result<()> {
     if config.max_folder_size > 0 {
         let current_folder_size = calculate_folder_size(folder_path).await?;
-        let new_total_size = current_folder_size + file_size;
+        let new_total_size = current_folder_size.checked_add(added_size);
+        metrics::folder_usage(folder_path, current_folder_size, config.max_folder_size);
         
-        if new_total_size > config.max_folder_size {
+        // An overflowing sum is over any limit
+        if new_total_size.is_none_or(|size| size > config.max_folder_size) {
+            metrics::rejected(metrics::REASON_FOLDER_SIZE);
             return Err(anyhow::anyhow!(
-                "Adding file would result in folder size {} bytes, exceeding maximum allowed folder size {} bytes (current: {} bytes)",
-                new_total_size, config.max_folder_size, current_folder_size
+                "Adding {} bytes would exceed maximum allowed folder size {} bytes (current: {} bytes)",
+                added_size, config.max_folder_size, current_folder_size
             ));
         }
     }
     
     Ok(())
-} 
\ No newline at end of file
+}
@@ -0,0 +1,344 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, oneshot},
+};
+use crate::config::ApprovalConfig;
+use crate::control;
+
+/// An incoming transfer waiting for the local user's decision
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingTransfer {
+    pub id: u64,
+    /// Address of the sender
+    pub peer: IpAddr,
+    /// "file", "tree", "batch" or "http"
+    pub kind: &'static str,
+    /// File or directory name (batch: the first file)
+    pub name: String,
+    pub files: usize,
+    pub size: u64,
+    pub folder: Option<String>,
+}
+
+impl PendingTransfer {
+    /// Describes an incoming transfer; the id is assigned once it is queued
+    pub fn new(peer: SocketAddr, kind: &'static str, name: &str, files: usize, size: u64, folder: Option<&str>) -> Self {
+        Self {
+            id: 0,
+            peer: peer.ip(),
+            kind,
+            name: name.to_string(),
+            files,
+            size,
+            folder: folder.map(str::to_string),
+        }
+    }
+}
+
+/// Outcome of an approval request
+#[derive(Debug)]
+pub enum Decision {
+    Accepted,
+    Rejected(String),
+}
+
+struct Waiter {
+    transfer: PendingTransfer,
+    decision: oneshot::Sender<bool>,
+}
+
+static PENDING: Lazy<Mutex<BTreeMap<u64, Waiter>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static ANNOUNCE: Lazy<broadcast::Sender<PendingTransfer>> = Lazy::new(|| broadcast::channel(64).0);
+
+/// Decides whether an incoming transfer may proceed
+///
+/// Without approval mode, or for trusted senders, transfers are accepted right
+/// away. Otherwise the transfer is queued until the local user accepts or
+/// rejects it, or the configured timeout passes.
+pub async fn request(config: &ApprovalConfig, mut transfer: PendingTransfer) -> Decision {
+    if !config.enabled || is_trusted(config, transfer.peer) {
+        return Decision::Accepted;
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    transfer.id = id;
+    let (sender, receiver) = oneshot::channel();
+
+    info!("Waiting for approval of {} (request {})", describe(&transfer), id);
+    PENDING.lock().unwrap().insert(id, Waiter { transfer: transfer.clone(), decision: sender });
+    let _ = ANNOUNCE.send(transfer);
+
+    let decision = tokio::time::timeout(Duration::from_secs(config.timeout), receiver).await;
+    PENDING.lock().unwrap().remove(&id);
+
+    match decision {
+        Ok(Ok(true)) => Decision::Accepted,
+        Ok(Ok(false)) => Decision::Rejected("rejected by receiver".to_string()),
+        Ok(Err(_)) => Decision::Rejected("approval was cancelled".to_string()),
+        Err(_) => Decision::Rejected("approval timed out".to_string()),
+    }
+}
+
+/// Trusted senders skip the approval prompt
+fn is_trusted(config: &ApprovalConfig, ip: IpAddr) -> bool {
+    config.trusted.iter()
+        .filter_map(|trusted| trusted.parse::<IpAddr>().ok())
+        .any(|trusted| trusted == ip || trusted.to_canonical() == ip.to_canonical())
+}
+
+/// Transfers currently waiting for a decision, oldest first
+pub fn pending() -> Vec<PendingTransfer> {
+    PENDING.lock().unwrap()
+        .values()
+        .map(|waiter| waiter.transfer.clone())
+        .collect()
+}
+
+/// Accepts or rejects a waiting transfer
+pub fn decide(id: u64, accept: bool) -> Result<()> {
+    let waiter = PENDING.lock().unwrap().remove(&id)
+        .with_context(|| format!("No pending transfer with id {}", id))?;
+
+    info!("Transfer request {} {}", id, if accept { "accepted" } else { "rejected" });
+    // The sender may have disconnected in the meantime
+    let _ = waiter.decision.send(accept);
+    Ok(())
+}
+
+/// Describes a pending transfer in one line
+fn describe(transfer: &PendingTransfer) -> String {
+    let what = match transfer.kind {
+        "file" => transfer.name.clone(),
+        _ => format!("{} {} ({} files)", transfer.kind, transfer.name, transfer.files),
+    };
+    match &transfer.folder {
+        Some(folder) => format!("{} ({} bytes) from {} into {}", what, transfer.size, transfer.peer, folder),
+        None => format!("{} ({} bytes) from {}", what, transfer.size, transfer.peer),
+    }
+}
+
+/// Asks on the terminal running the server for every new request
+///
+/// Answer with `y` / `n` for the oldest request, or `y <id>` / `n <id>`.
+pub async fn run_prompt() {
+    let mut announcements = ANNOUNCE.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match announcements.recv().await {
+                Ok(transfer) => println!(
+                    "[{}] Incoming {} - accept? (y {} / n {})",
+                    transfer.id, describe(&transfer), transfer.id, transfer.id
+                ),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let mut words = line.split_whitespace();
+        let accept = match words.next().map(str::to_lowercase).as_deref() {
+            Some("y" | "yes") => true,
+            Some("n" | "no") => false,
+            _ => continue,
+        };
+        let id = match words.next() {
+            Some(id) => id.parse().ok(),
+            None => pending().first().map(|transfer| transfer.id),
+        };
+
+        match id {
+            Some(id) => {
+                if let Err(e) = decide(id, accept) {
+                    println!("{}", e);
+                }
+            }
+            None => println!("No pending transfers"),
+        }
+    }
+}
+
+/// Serves the local control socket used by other front ends (e.g. the UI)
+///
+/// Line based, on the loopback interface only. The first line must be
+/// `AUTH <token>` with the control API token from transfer.toml, answered `OK`.
+/// Then `PENDING` lists requests as `PENDING <id> <json>` lines followed by `END`,
+/// `ACCEPT <id>` and `REJECT <id>` answer `OK` or `ERROR: <message>`.
+pub async fn run_control_socket(port: u16, token: String) -> Result<()> {
+    if token.is_empty() {
+        return Err(anyhow::anyhow!("Control API token is empty"));
+    }
+    let token = Arc::new(token);
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await
+        .with_context(|| format!("Failed to bind approval control socket to port {}", port))?;
+
+    info!("Approval control socket listening on 127.0.0.1:{}", port);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept control connection: {}", e);
+                continue;
+            }
+        };
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_control_connection(stream, &token).await {
+                warn!("Control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_control_connection(stream: TcpStream, token: &str) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let auth = lines.next_line().await.context("Failed to read control command")?.unwrap_or_default();
+    let authorized = auth.strip_prefix("AUTH ")
+        .is_some_and(|presented| control::constant_time_eq(presented.trim().as_bytes(), token.as_bytes()));
+    if !authorized {
+        writer.write_all(b"ERROR: Missing or invalid token\n").await
+            .context("Failed to send control response")?;
+        return Err(anyhow::anyhow!("Missing or invalid token"));
+    }
+    writer.write_all(b"OK\n").await
+        .context("Failed to send control response")?;
+
+    while let Some(line) = lines.next_line().await.context("Failed to read control command")? {
+        let mut words = line.split_whitespace();
+        let response = match (words.next().map(str::to_uppercase).as_deref(), words.next()) {
+            (Some("PENDING"), None) => {
+                let mut response = String::new();
+                for transfer in pending() {
+                    let json = serde_json::to_string(&transfer)
+                        .context("Failed to serialize pending transfer")?;
+                    response.push_str(&format!("PENDING {} {}\n", transfer.id, json));
+                }
+                response.push_str("END");
+                response
+            }
+            (Some(command @ ("ACCEPT" | "REJECT")), Some(id)) => {
+                let result = id.parse()
+                    .context("Invalid request id")
+                    .and_then(|id| decide(id, command == "ACCEPT"));
+                match result {
+                    Ok(()) => "OK".to_string(),
+                    Err(e) => format!("ERROR: {}", e),
+                }
+            }
+            _ => "ERROR: Available commands: PENDING, ACCEPT <id>, REJECT <id>".to_string(),
+        };
+
+        writer.write_all(format!("{}\n", response).as_bytes()).await
+            .context("Failed to send control response")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approval(timeout: u64) -> ApprovalConfig {
+        ApprovalConfig { enabled: true, timeout, trusted: vec!["::ffff:192.0.2.10".to_string()], control_port: 0 }
+    }
+
+    /// Every test uses its own sender, as pending transfers are shared by the whole run
+    fn transfer(last: u8) -> PendingTransfer {
+        PendingTransfer::new(SocketAddr::from(([192, 0, 2, last], 5000)), "file", "photo.jpg", 1, 100, None)
+    }
+
+    /// Asks for approval in the background and returns the id it is queued under
+    async fn queue(config: ApprovalConfig, last: u8) -> (u64, tokio::task::JoinHandle<Decision>) {
+        let waiting = tokio::spawn(async move { request(&config, transfer(last)).await });
+        loop {
+            if let Some(queued) = pending().into_iter().find(|queued| queued.peer == transfer(last).peer) {
+                return (queued.id, waiting);
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_or_trusted_is_accepted_right_away() {
+        let disabled = ApprovalConfig { enabled: false, ..approval(60) };
+        assert!(matches!(request(&disabled, transfer(1)).await, Decision::Accepted));
+        assert!(matches!(request(&approval(60), transfer(10)).await, Decision::Accepted));
+    }
+
+    #[tokio::test]
+    async fn accepted_transfer_proceeds() {
+        let (id, waiting) = queue(approval(60), 2).await;
+        decide(id, true).unwrap();
+        assert!(matches!(waiting.await.unwrap(), Decision::Accepted));
+        assert!(decide(id, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn denied_transfer_is_rejected() {
+        let (id, waiting) = queue(approval(60), 3).await;
+        decide(id, false).unwrap();
+        match waiting.await.unwrap() {
+            Decision::Rejected(reason) => assert_eq!(reason, "rejected by receiver"),
+            Decision::Accepted => panic!("denied transfer was accepted"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_transfer_times_out() {
+        let (id, waiting) = queue(approval(30), 4).await;
+        match waiting.await.unwrap() {
+            Decision::Rejected(reason) => assert_eq!(reason, "approval timed out"),
+            Decision::Accepted => panic!("unanswered transfer was accepted"),
+        }
+        assert!(!pending().iter().any(|queued| queued.id == id));
+    }
+
+    /// Runs one control connection with token "secret" and sends it `lines`
+    async fn control(lines: &str) -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let connection = tokio::spawn(async move { handle_control_connection(stream, "secret").await });
+
+        client.write_all(lines.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let _ = connection.await.unwrap();
+        let mut response = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut client, &mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn control_socket_needs_the_token() {
+        assert_eq!(control("AUTH wrong\nPENDING\n").await, "ERROR: Missing or invalid token\n");
+        assert_eq!(control("PENDING\n").await, "ERROR: Missing or invalid token\n");
+    }
+
+    #[tokio::test]
+    async fn control_socket_rejects_transfers() {
+        let (id, waiting) = queue(approval(60), 5).await;
+        let response = control(&format!("AUTH secret\nREJECT {}\nREJECT {}\n", id, id)).await;
+        assert_eq!(response, format!("OK\nOK\nERROR: No pending transfer with id {}\n", id));
+        assert!(matches!(waiting.await.unwrap(), Decision::Rejected(_)));
+    }
+}
@@ -0,0 +1,352 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use crate::config::LimitsConfig;
+use crate::metrics;
+use crate::structure;
+
+/// An address refused after too many failed authentications
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: String,
+    /// RFC 3339, UTC
+    pub since: String,
+    /// RFC 3339, UTC
+    pub until: String,
+    /// Number of times the address was banned
+    pub count: u32,
+}
+
+impl Ban {
+    pub fn is_active(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.until).is_ok_and(|until| until > Utc::now())
+    }
+}
+
+/// Bans are kept next to transfer.toml, so they survive restarts
+const BANS_FILE: &str = "bans.json";
+
+/// Bans loaded from bans.json, kept in memory so checking a connection needs no disk access
+///
+/// Every change is written back right away. None until first used.
+static BANS: Lazy<Mutex<Option<Loaded>>> = Lazy::new(|| Mutex::new(None));
+
+/// How often the server looks for changes to bans.json, e.g. from `transfer unban`
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Loaded {
+    bans: Vec<Ban>,
+    /// Modification time of bans.json when it was last read or written
+    modified: Option<SystemTime>,
+}
+
+/// Longest wait between two attempts from an address that keeps failing
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long expired bans are remembered, so a returning address gets a longer ban
+const BAN_MEMORY_DAYS: i64 = 7;
+
+/// Failed authentications of an address since its last ban
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+static FAILURES: Lazy<Mutex<HashMap<IpAddr, Failures>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Refuses `ip` while it is banned or still has to wait after its last failure
+///
+/// The wait doubles with every failure (1s, 2s, 4s... up to a minute), so IDs
+/// can't be guessed quickly even before the address is banned.
+pub fn check(limits: &LimitsConfig, ip: IpAddr) -> Result<()> {
+    if limits.max_auth_failures == 0 {
+        return Ok(());
+    }
+    let ip = ip.to_canonical();
+    if let Some(ban) = active_ban(ip)? {
+        return Err(anyhow::anyhow!("Too many failed attempts, your address is banned until {}", ban.until));
+    }
+
+    let failures = FAILURES.lock().unwrap();
+    if let Some(failures) = failures.get(&ip) {
+        let wait = backoff(failures.count);
+        let waited = failures.last.elapsed();
+        if waited < wait {
+            return Err(anyhow::anyhow!("Too many failed attempts, try again in {}s", (wait - waited).as_secs() + 1));
+        }
+    }
+    Ok(())
+}
+
+fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.saturating_sub(1).min(6)).min(MAX_BACKOFF)
+}
+
+/// Counts a failed authentication from `ip` and bans it once max_auth_failures is reached
+///
+/// Failures are forgotten after ban_duration without another one.
+pub fn failed(limits: &LimitsConfig, ip: IpAddr) {
+    metrics::auth_failed();
+    let ip = ip.to_canonical();
+    if limits.max_auth_failures == 0 {
+        warn!("Failed authentication from {}", ip);
+        return;
+    }
+
+    let count = {
+        let mut failures = FAILURES.lock().unwrap();
+        let entry = failures.entry(ip).or_insert(Failures { count: 0, last: Instant::now() });
+        if entry.last.elapsed() > Duration::from_secs(limits.ban_duration) {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = Instant::now();
+        let count = entry.count;
+        // Counting starts over once the address is banned
+        if count >= limits.max_auth_failures {
+            failures.remove(&ip);
+        }
+        count
+    };
+    warn!("Failed authentication from {} ({}/{})", ip, count, limits.max_auth_failures);
+
+    if count >= limits.max_auth_failures && let Err(e) = ban(limits, ip, count) {
+        error!("Failed to ban {}: {:#}", ip, e);
+    }
+}
+
+fn ban(limits: &LimitsConfig, ip: IpAddr, failures: u32) -> Result<()> {
+    let seconds = update(|bans| {
+        let now = Utc::now();
+        bans.retain(|ban| DateTime::parse_from_rfc3339(&ban.until)
+            .is_ok_and(|until| until > now - chrono::Duration::days(BAN_MEMORY_DAYS)));
+
+        let ip_text = ip.to_string();
+        let count = bans.iter().find(|ban| ban.ip == ip_text).map_or(0, |ban| ban.count) + 1;
+        let seconds = limits.ban_duration.saturating_mul(1 << (count - 1).min(10));
+        let ban = Ban {
+            ip: ip_text.clone(),
+            since: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            until: (now + chrono::Duration::seconds(seconds.min(i64::MAX as u64) as i64)).to_rfc3339_opts(SecondsFormat::Secs, true),
+            count,
+        };
+        bans.retain(|ban| ban.ip != ip_text);
+        bans.push(ban);
+        seconds
+    })?;
+
+    metrics::banned();
+    warn!("Banned {} for {}s after {} failed authentications", ip, seconds, failures);
+    Ok(())
+}
+
+/// The ban on `ip`, if it is banned right now
+pub fn active_ban(ip: IpAddr) -> Result<Option<Ban>> {
+    let ip = ip.to_canonical().to_string();
+    read(|bans| bans.iter().find(|ban| ban.ip == ip && ban.is_active()).cloned())
+}
+
+/// Bans that are still in effect, oldest first
+pub fn list() -> Result<Vec<Ban>> {
+    read(|bans| bans.iter().filter(|ban| ban.is_active()).cloned().collect())
+}
+
+/// Lifts the ban on `ip` and forgets earlier ones; returns false if it isn't banned
+///
+/// A running server picks the change up within RELOAD_INTERVAL.
+pub fn unban(ip: &str) -> Result<bool> {
+    let ip = ip.parse::<IpAddr>()
+        .with_context(|| format!("Invalid IP address: {}", ip))?
+        .to_canonical()
+        .to_string();
+
+    update(|bans| {
+        let banned = bans.iter().any(|ban| ban.ip == ip && ban.is_active());
+        bans.retain(|ban| ban.ip != ip);
+        banned
+    })
+}
+
+/// Reads bans.json into memory, so a broken file stops the server at startup
+pub fn load() -> Result<()> {
+    read(|_| ())
+}
+
+/// Reloads bans.json whenever it was changed by another process
+pub async fn watch() {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = reload() {
+            error!("Failed to reload bans: {:#}", e);
+        }
+    }
+}
+
+/// Reads bans.json again if it changed since it was last read or written
+fn reload() -> Result<()> {
+    let mut loaded = BANS.lock().unwrap();
+    if loaded.as_ref().is_none_or(|loaded| loaded.modified != modified()) {
+        *loaded = Some(read_file()?);
+    }
+    Ok(())
+}
+
+/// Runs `f` on the bans in memory, reading bans.json first if needed
+fn read<T>(f: impl FnOnce(&[Ban]) -> T) -> Result<T> {
+    let mut loaded = BANS.lock().unwrap();
+    if loaded.is_none() {
+        *loaded = Some(read_file()?);
+    }
+    Ok(f(&loaded.as_ref().unwrap().bans))
+}
+
+/// Changes the bans with `f` and writes them to bans.json
+fn update<T>(f: impl FnOnce(&mut Vec<Ban>) -> T) -> Result<T> {
+    let mut guard = BANS.lock().unwrap();
+    // Another process may have changed the file since it was read
+    if guard.as_ref().is_none_or(|loaded| loaded.modified != modified()) {
+        *guard = Some(read_file()?);
+    }
+    let loaded = guard.as_mut().unwrap();
+    let mut bans = loaded.bans.clone();
+    let result = f(&mut bans);
+    structure::write_json_list(BANS_FILE, &bans)?;
+    *loaded = Loaded { bans, modified: modified() };
+    Ok(result)
+}
+
+fn read_file() -> Result<Loaded> {
+    // Taken before reading, so a change while reading is picked up next time
+    let modified = modified();
+    Ok(Loaded { bans: structure::read_json_list(BANS_FILE)?, modified })
+}
+
+/// Modification time of bans.json, None if it doesn't exist
+fn modified() -> Option<SystemTime> {
+    let path = structure::get_config_directory().ok()?.join(BANS_FILE);
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn limits(max_auth_failures: u32) -> LimitsConfig {
+        LimitsConfig { max_auth_failures, ban_duration: 60, ..Default::default() }
+    }
+
+    /// Every test uses its own address, as failures and bans are shared by the whole run
+    fn address(last: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, last])
+    }
+
+    fn seconds_banned(ban: &Ban) -> i64 {
+        let since = DateTime::parse_from_rfc3339(&ban.since).unwrap();
+        let until = DateTime::parse_from_rfc3339(&ban.until).unwrap();
+        (until - since).num_seconds()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let waits: Vec<u64> = (1..=8).map(|failures| backoff(failures).as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failure_makes_the_address_wait() {
+        let limits = limits(5);
+        check(&limits, address(1)).unwrap();
+        failed(&limits, address(1));
+        let error = check(&limits, address(1)).unwrap_err().to_string();
+        assert!(error.contains("try again in 1s"), "{}", error);
+        assert!(active_ban(address(1)).unwrap().is_none());
+
+        // No limit, no waiting
+        let unlimited = self::limits(0);
+        failed(&unlimited, address(2));
+        check(&unlimited, address(2)).unwrap();
+    }
+
+    #[test]
+    fn enough_failures_ban_the_address() {
+        let limits = limits(1);
+        failed(&limits, address(3));
+        let ban = active_ban(address(3)).unwrap().unwrap();
+        assert_eq!(ban.count, 1);
+        assert_eq!(seconds_banned(&ban), 60);
+        let error = check(&limits, address(3)).unwrap_err().to_string();
+        assert!(error.contains("banned until"), "{}", error);
+        assert!(list().unwrap().iter().any(|ban| ban.ip == address(3).to_string()));
+    }
+
+    #[test]
+    fn repeated_bans_last_longer() {
+        let limits = limits(1);
+        ban(&limits, address(4), 1).unwrap();
+        ban(&limits, address(4), 1).unwrap();
+        ban(&limits, address(4), 1).unwrap();
+        let ban = active_ban(address(4)).unwrap().unwrap();
+        assert_eq!(ban.count, 3);
+        assert_eq!(seconds_banned(&ban), 240);
+    }
+
+    #[test]
+    fn expired_ban_is_not_active() {
+        let mut ban = Ban {
+            ip: address(5).to_string(),
+            since: "2020-01-01T00:00:00Z".to_string(),
+            until: "2020-01-01T00:01:00Z".to_string(),
+            count: 1,
+        };
+        assert!(!ban.is_active());
+        ban.until = (Utc::now() + chrono::Duration::minutes(1)).to_rfc3339();
+        assert!(ban.is_active());
+    }
+
+    #[test]
+    fn unban_lifts_the_ban() {
+        ban(&limits(1), address(6), 1).unwrap();
+        assert!(unban(&address(6).to_string()).unwrap());
+        assert!(active_ban(address(6)).unwrap().is_none());
+        check(&limits(1), address(6)).unwrap();
+        assert!(!unban(&address(6).to_string()).unwrap());
+        assert!(unban("not an address").is_err());
+    }
+
+    #[test]
+    fn changes_by_another_process_are_reloaded() {
+        load().unwrap();
+        let now = Utc::now();
+        let ban = Ban {
+            ip: address(7).to_string(),
+            since: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            until: (now + chrono::Duration::minutes(1)).to_rfc3339_opts(SecondsFormat::Secs, true),
+            count: 1,
+        };
+        {
+            // Holding the lock keeps other tests from writing in between
+            let _bans = BANS.lock().unwrap();
+            let mut bans: Vec<Ban> = structure::read_json_list(BANS_FILE).unwrap();
+            bans.push(ban);
+            structure::write_json_list(BANS_FILE, &bans).unwrap();
+            // Make sure the modification time differs on file systems with a coarse clock
+            let path = structure::get_config_directory().unwrap().join(BANS_FILE);
+            File::options().write(true).open(path).unwrap()
+                .set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        }
+
+        reload().unwrap();
+        assert!(active_ban(address(7)).unwrap().is_some());
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+use crate::api::{CAPABILITIES, PROTOCOL_VERSION};
+use crate::discovery::Peer;
+use crate::firewall::Firewall;
+
+/// Datagram a client broadcasts to find receivers, padded with spaces to PROBE_SIZE
+const PROBE: &[u8] = b"PROBE";
+
+/// Size probes are padded to
+///
+/// Shorter probes are ignored and replies are never larger, so answering a
+/// probe with a forged source address doesn't amplify the traffic.
+const PROBE_SIZE: usize = 1024;
+
+/// Longest server name sent in a reply, which keeps the reply below PROBE_SIZE
+const MAX_NAME_CHARS: usize = 64;
+
+/// Shortest time between two replies to the same address
+const REPLY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Addresses remembered for REPLY_INTERVAL; probes from further addresses
+/// are ignored while this many were answered within the interval
+const MAX_RECENT_REPLIES: usize = 4096;
+
+/// Largest reply accepted from a receiver
+const MAX_REPLY_SIZE: usize = 2048;
+
+/// Reply sent by a receiver to a PROBE
+#[derive(Debug, Serialize, Deserialize)]
+struct BeaconReply {
+    name: String,
+    port: u16,
+    version: u32,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+/// Answers PROBE broadcasts on `beacon_port` with this server's name, port and capabilities
+///
+/// Fallback for networks that block multicast (and with it mDNS). Probes from
+/// addresses `firewall` refuses are ignored, so the beacon can't be used to
+/// send replies to them, and every address gets at most one reply per
+/// REPLY_INTERVAL.
+pub async fn run(beacon_port: u16, name: String, port: u16, firewall: Arc<Firewall>) -> Result<()> {
+    // Broadcasts are only delivered to sockets bound to the wildcard address
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, beacon_port)).await
+        .with_context(|| format!("Failed to bind UDP beacon to port {}", beacon_port))?;
+
+    let reply = serde_json::to_vec(&BeaconReply {
+        name: name.chars().take(MAX_NAME_CHARS).collect(),
+        port,
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    })
+    .context("Failed to serialize beacon reply")?;
+    if reply.len() > PROBE_SIZE {
+        return Err(anyhow::anyhow!("Beacon reply of {} bytes is larger than a probe", reply.len()));
+    }
+
+    info!("UDP beacon listening on port {}", beacon_port);
+
+    let mut replied: HashMap<IpAddr, Instant> = HashMap::new();
+    let mut buffer = [0u8; PROBE_SIZE];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive beacon probe: {}", e);
+                continue;
+            }
+        };
+
+        if n < PROBE_SIZE || buffer[..n].trim_ascii() != PROBE || !firewall.permits(from) {
+            continue;
+        }
+        if !may_reply(&mut replied, from.ip(), Instant::now()) {
+            debug!("Not answering another beacon probe from {} yet", from);
+            continue;
+        }
+
+        debug!("Answering beacon probe from {}", from);
+        if let Err(e) = socket.send_to(&reply, from).await {
+            warn!("Failed to answer beacon probe from {}: {}", from, e);
+        }
+    }
+}
+
+/// Whether `ip` may get a reply at `now`, remembering it in `replied` if so
+fn may_reply(replied: &mut HashMap<IpAddr, Instant>, ip: IpAddr, now: Instant) -> bool {
+    if replied.len() >= MAX_RECENT_REPLIES {
+        replied.retain(|_, at| now.duration_since(*at) < REPLY_INTERVAL);
+    }
+    let recent = replied.get(&ip).is_some_and(|at| now.duration_since(*at) < REPLY_INTERVAL);
+    if recent || replied.len() >= MAX_RECENT_REPLIES {
+        return false;
+    }
+    replied.insert(ip, now);
+    true
+}
+
+/// Broadcasts a PROBE on `beacon_port` and collects the replies that arrive within `timeout`
+pub async fn probe(beacon_port: u16, timeout: Duration) -> Result<Vec<Peer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await
+        .context("Failed to bind UDP socket")?;
+    socket.set_broadcast(true)
+        .context("Failed to enable broadcast")?;
+    let mut probe = PROBE.to_vec();
+    probe.resize(PROBE_SIZE, b' ');
+    socket.send_to(&probe, (Ipv4Addr::BROADCAST, beacon_port)).await
+        .context("Failed to broadcast probe")?;
+
+    // Keyed by reply address so a receiver answering twice is listed once
+    let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buffer = [0u8; MAX_REPLY_SIZE];
+
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (n, from) = received.context("Failed to receive beacon reply")?;
+        let reply: BeaconReply = match serde_json::from_slice(&buffer[..n]) {
+            Ok(reply) => reply,
+            Err(e) => {
+                debug!("Ignoring invalid beacon reply from {}: {}", from, e);
+                continue;
+            }
+        };
+
+        peers.insert(from, Peer {
+            name: reply.name,
+            host: from.ip().to_string(),
+            addresses: vec![from.ip()],
+            port: reply.port,
+            version: Some(reply.version.to_string()),
+            capabilities: reply.capabilities,
+        });
+    }
+
+    let mut peers: Vec<Peer> = peers.into_values().collect();
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_once_per_interval_and_address() {
+        let mut replied = HashMap::new();
+        let (a, b): (IpAddr, IpAddr) = ("192.168.1.2".parse().unwrap(), "192.168.1.3".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(may_reply(&mut replied, a, start));
+        assert!(!may_reply(&mut replied, a, start + Duration::from_millis(500)));
+        assert!(may_reply(&mut replied, b, start + Duration::from_millis(500)));
+        assert!(may_reply(&mut replied, a, start + REPLY_INTERVAL));
+    }
+
+    #[test]
+    fn stops_replying_to_floods_of_addresses() {
+        let mut replied = HashMap::new();
+        let start = Instant::now();
+        for i in 0..MAX_RECENT_REPLIES as u32 {
+            assert!(may_reply(&mut replied, IpAddr::V4(Ipv4Addr::from(i)), start));
+        }
+        let next = IpAddr::V4(Ipv4Addr::from(MAX_RECENT_REPLIES as u32));
+        assert!(!may_reply(&mut replied, next, start));
+        // Once the interval passed, the old addresses are forgotten
+        assert!(may_reply(&mut replied, next, start + REPLY_INTERVAL));
+        assert_eq!(replied.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_padded_probes_only() {
+        let config = crate::config::Config::default();
+        let firewall = Arc::new(Firewall::new(&config).unwrap());
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let beacon_port = server.local_addr().unwrap().port();
+        drop(server);
+        tokio::spawn(run(beacon_port, "x".repeat(1000), 1000, firewall));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut buffer = [0u8; MAX_REPLY_SIZE];
+        client.send_to(PROBE, (Ipv4Addr::LOCALHOST, beacon_port)).await.unwrap();
+        let unpadded = tokio::time::timeout(Duration::from_millis(300), client.recv_from(&mut buffer)).await;
+        assert!(unpadded.is_err(), "answered an unpadded probe");
+
+        let mut probe = PROBE.to_vec();
+        probe.resize(PROBE_SIZE, b' ');
+        client.send_to(&probe, (Ipv4Addr::LOCALHOST, beacon_port)).await.unwrap();
+        let (n, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buffer)).await.unwrap().unwrap();
+        assert!(n <= PROBE_SIZE);
+        let reply: BeaconReply = serde_json::from_slice(&buffer[..n]).unwrap();
+        assert_eq!(reply.name.chars().count(), MAX_NAME_CHARS);
+    }
+}
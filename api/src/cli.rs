@@ -0,0 +1,569 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use crate::client;
+use crate::config::Config;
+use crate::crypto::{self, KeyResolver};
+use crate::bans;
+use crate::beacon;
+use crate::discovery;
+use crate::history;
+use crate::ip;
+use crate::links;
+use crate::shares;
+use crate::storage;
+use crate::tree::EntryKind;
+
+/// Usage text printed for `transfer help` and unknown commands
+const USAGE: &str = "Usage: transfer [<command>]
+
+Without a command, starts the transfer server.
+
+Commands:
+  send <host:port> <transfer_id> <path>... [--folder <folder>] [--no-compress] [--transactional]
+                             Send files (as one batch) or directory trees to a transfer server
+  list <host:port> <transfer_id> [<folder>]
+                             List a folder on a server that allows reads, or with a token that may list
+  get <host:port> <transfer_id> <path> [<output>] [--no-compress]
+                             Download a file from a server that allows reads, with a token or a share
+  delete <host:port> <transfer_id> <path>
+                             Delete a file or directory on a server, with a token that may delete
+  discover [--timeout <secs>] [--no-mdns] [--no-beacon]
+                             List receivers on the local network (mDNS and UDP broadcast)
+  history [--since <when>] [--until <when>] [--peer <ip>] [--name <text>] [--result <code>] [--failed] [--limit <n>] [--json]
+                             Show received transfers (when: today, yesterday, YYYY-MM-DD or an age like 12h)
+  interfaces                 List network interfaces and their addresses (for the interface setting)
+  decrypt <file> <output>    Decrypt a received file to <output>
+  export <output_dir>        Copy the receive folder to <output_dir>, decrypting encrypted files
+  link create [--folder <folder>] [--expires <age>] [--max-size <size>] [--uses <n>]
+                             Create an upload link: a token that senders use as transfer ID, limited
+                             to <folder>, files of <size> (e.g. 500M, 1G) and <n> transfers (default 1)
+                             until it expires (e.g. 2h, 7d; default 24h)
+  link list                  List upload links and their status
+  link revoke <token>        Stop accepting uploads with an upload link
+  share <path> [--expires <age>] [--downloads <n>]
+                             Publish a received file (path below the receive folder) behind a token
+                             until it expires (default 1d) or was downloaded <n> times
+  share list                 List shares and their status
+  share revoke <token>       Stop serving a share
+  bans                       List addresses banned after too many failed authentications
+  unban <ip>                 Lift the ban on an address
+  help                       Show this message";
+
+/// Runs the command given on the command line
+pub async fn run(args: &[String]) -> Result<()> {
+    let command = args.first().map(String::as_str).unwrap_or("help");
+    let rest = &args[1.min(args.len())..];
+
+    match command {
+        "send" => send(rest).await,
+        "list" => list(rest).await,
+        "get" => get(rest).await,
+        "delete" => delete(rest).await,
+        "discover" => discover(rest).await,
+        "history" => history(rest),
+        "interfaces" => interfaces(),
+        "decrypt" => decrypt(rest),
+        "export" => export(rest),
+        "link" => link(rest),
+        "share" => share(rest),
+        "bans" => list_bans(),
+        "unban" => unban(rest),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(anyhow::anyhow!("Unknown command: {}\n\n{}", other, USAGE)),
+    }
+}
+
+/// transfer send <host:port> <transfer_id> <path>... [--folder <folder>] [--no-compress] [--transactional]
+async fn send(args: &[String]) -> Result<()> {
+    let usage = "Usage: transfer send <host:port> <transfer_id> <path>... [--folder <folder>] [--no-compress] [--transactional]";
+
+    let mut compress = true;
+    let mut transactional = false;
+    let mut folder = None;
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--no-compress" => compress = false,
+            "--transactional" => transactional = true,
+            "--folder" => folder = Some(iter.next().context(usage)?.as_str()),
+            _ => positional.push(arg),
+        }
+    }
+
+    let [addr, transfer_id, paths @ ..] = positional.as_slice() else {
+        return Err(anyhow::anyhow!(usage));
+    };
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!(usage));
+    }
+
+    // Directories are sent as trees, files together in one batch session
+    let (directories, files): (Vec<PathBuf>, Vec<PathBuf>) = paths.iter()
+        .map(PathBuf::from)
+        .partition(|path| path.is_dir());
+
+    for directory in &directories {
+        let result = client::send_directory(addr, transfer_id, directory, folder, compress).await?;
+        println!("Sent {} (saved as {})", directory.display(), result);
+    }
+
+    match files.as_slice() {
+        [] => {}
+        [file] if !transactional => {
+            let saved_name = client::send_file(addr, transfer_id, file, folder, compress).await?;
+            println!("Sent {} (saved as {})", file.display(), saved_name);
+        }
+        _ => {
+            let batch = client::send_files(addr, transfer_id, &files, folder, compress, transactional).await?;
+            for file in &batch.files {
+                match &file.result {
+                    Ok(saved_name) => println!("Sent {} (saved as {})", file.path.display(), saved_name),
+                    Err(error) => println!("Failed {}: {}", file.path.display(), error),
+                }
+            }
+            println!("{}", batch.summary);
+        }
+    }
+
+    Ok(())
+}
+
+/// transfer list <host:port> <transfer_id> [<folder>]
+async fn list(args: &[String]) -> Result<()> {
+    let (addr, transfer_id, folder) = match args {
+        [addr, transfer_id] => (addr, transfer_id, None),
+        [addr, transfer_id, folder] => (addr, transfer_id, Some(folder.as_str())),
+        _ => return Err(anyhow::anyhow!("Usage: transfer list <host:port> <transfer_id> [<folder>]")),
+    };
+
+    let entries = client::list_folder(addr, transfer_id, folder).await?;
+    for entry in &entries {
+        let modified = chrono::DateTime::from_timestamp(entry.mtime as i64, 0)
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        match entry.kind {
+            EntryKind::Dir => println!("{:>12}  {}  {}/", "-", modified, entry.name),
+            EntryKind::File => println!("{:>12}  {}  {}", entry.size, modified, entry.name),
+        }
+    }
+
+    Ok(())
+}
+
+/// transfer get <host:port> <transfer_id> <path> [<output>] [--no-compress]
+async fn get(args: &[String]) -> Result<()> {
+    let usage = "Usage: transfer get <host:port> <transfer_id> <path> [<output>] [--no-compress]";
+
+    let compress = !args.iter().any(|arg| arg == "--no-compress");
+    let positional: Vec<&String> = args.iter().filter(|arg| *arg != "--no-compress").collect();
+    let (addr, transfer_id, path, output) = match positional.as_slice() {
+        [addr, transfer_id, path] => (addr, transfer_id, path, None),
+        [addr, transfer_id, path, output] => (addr, transfer_id, path, Some(PathBuf::from(output))),
+        _ => return Err(anyhow::anyhow!(usage)),
+    };
+
+    // Default to the remote file name in the current directory
+    let output = match output {
+        Some(output) => output,
+        None => PathBuf::from(path.rsplit('/').next().filter(|name| !name.is_empty()).context(usage)?),
+    };
+
+    let size = client::get_file(addr, transfer_id, path, &output, compress).await?;
+    println!("Downloaded {} to {} ({} bytes)", path, output.display(), size);
+
+    Ok(())
+}
+
+/// transfer delete <host:port> <transfer_id> <path>
+async fn delete(args: &[String]) -> Result<()> {
+    let [addr, transfer_id, path] = args else {
+        return Err(anyhow::anyhow!("Usage: transfer delete <host:port> <transfer_id> <path>"));
+    };
+
+    client::delete_path(addr, transfer_id, path).await?;
+    println!("Deleted {}", path);
+
+    Ok(())
+}
+
+/// transfer discover [--timeout <secs>] [--no-mdns] [--no-beacon]
+async fn discover(args: &[String]) -> Result<()> {
+    let usage = "Usage: transfer discover [--timeout <secs>] [--no-mdns] [--no-beacon]";
+
+    let mut timeout = 3;
+    let mut mdns = true;
+    let mut beacon = true;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--timeout" => timeout = iter.next().context(usage)?.parse().context(usage)?,
+            "--no-mdns" => mdns = false,
+            "--no-beacon" => beacon = false,
+            _ => return Err(anyhow::anyhow!(usage)),
+        }
+    }
+
+    let config = Config::load_or_create()
+        .context("Failed to load config")?;
+    let timeout = Duration::from_secs(timeout);
+
+    println!("Searching for receivers ({}s)...", timeout.as_secs());
+
+    // Run both methods at once; either failing (e.g. no multicast) still leaves the other
+    let (mdns_peers, beacon_peers) = tokio::join!(
+        async { if mdns { discovery::browse(timeout).await } else { Ok(Vec::new()) } },
+        async { if beacon { beacon::probe(config.beacon_port, timeout).await } else { Ok(Vec::new()) } },
+    );
+    let mdns_peers = mdns_peers.unwrap_or_else(|e| {
+        eprintln!("mDNS discovery failed: {:#}", e);
+        Vec::new()
+    });
+    let beacon_peers = beacon_peers.unwrap_or_else(|e| {
+        eprintln!("Broadcast discovery failed: {:#}", e);
+        Vec::new()
+    });
+    let peers = discovery::merge_peers([mdns_peers, beacon_peers]);
+
+    if peers.is_empty() {
+        println!("No receivers found");
+    }
+    for peer in &peers {
+        let addresses: Vec<String> = peer.addresses.iter()
+            .map(|address| match address {
+                std::net::IpAddr::V6(v6) => format!("[{}]:{}", v6, peer.port),
+                std::net::IpAddr::V4(v4) => format!("{}:{}", v4, peer.port),
+            })
+            .collect();
+        println!("{}  {}  (protocol {}; {})", peer.name, addresses.join(", "), peer.version.as_deref().unwrap_or("unknown"), peer.capabilities.join(", "));
+    }
+
+    Ok(())
+}
+
+/// transfer history [--since <when>] [--until <when>] [--peer <ip>] [--name <text>] [--result <code>] [--failed] [--limit <n>] [--json]
+fn history(args: &[String]) -> Result<()> {
+    let usage = "Usage: transfer history [--since <when>] [--until <when>] [--peer <ip>] [--name <text>] [--result <code>] [--failed] [--limit <n>] [--json]";
+
+    let mut filter = history::Filter::default();
+    let mut limit = None;
+    let mut json = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--since" => filter.since = Some(history::parse_time(iter.next().context(usage)?)?),
+            "--until" => filter.until = Some(history::parse_time(iter.next().context(usage)?)?),
+            "--peer" => filter.peer = Some(iter.next().context(usage)?.clone()),
+            "--name" => filter.name = Some(iter.next().context(usage)?.clone()),
+            "--result" => filter.result = Some(iter.next().context(usage)?.clone()),
+            "--failed" => filter.result = Some("error".to_string()),
+            "--limit" => limit = Some(iter.next().context(usage)?.parse::<usize>().context(usage)?),
+            "--json" => json = true,
+            _ => return Err(anyhow::anyhow!(usage)),
+        }
+    }
+
+    let mut entries = history::read(&filter)?;
+    // The limit keeps the most recent entries
+    if let Some(limit) = limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+
+    if json {
+        for entry in &entries {
+            println!("{}", serde_json::to_string(entry).context("Failed to serialize history entry")?);
+        }
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        println!("No matching transfers");
+    }
+    for entry in &entries {
+        let time = chrono::DateTime::parse_from_rfc3339(&entry.timestamp)
+            .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|_| entry.timestamp.clone());
+        let name = match &entry.stored_name {
+            Some(stored) if *stored != entry.filename => format!("{} -> {}", entry.filename, stored),
+            _ => entry.filename.clone(),
+        };
+        println!("{}  {:<10} {:>12}  {:>7.1}s  {}  {}", time, entry.result, entry.size, entry.duration_ms as f64 / 1000.0, entry.peer, name);
+        if let Some(checksum) = &entry.checksum {
+            println!("{:>21}sha256 {}", "", checksum);
+        }
+        if let Some(error) = &entry.error {
+            println!("{:>21}{}", "", error);
+        }
+    }
+
+    Ok(())
+}
+
+/// transfer interfaces
+fn interfaces() -> Result<()> {
+    let mut addresses = ip::list_interfaces()?;
+    addresses.sort_by(|a, b| a.interface.cmp(&b.interface).then(a.ip.is_ipv6().cmp(&b.ip.is_ipv6())));
+
+    for address in &addresses {
+        let mut notes = Vec::new();
+        if address.is_loopback {
+            notes.push("loopback");
+        }
+        if !address.is_up {
+            notes.push("down");
+        }
+        if notes.is_empty() {
+            println!("{:<16} {}", address.interface, address.ip);
+        } else {
+            println!("{:<16} {} ({})", address.interface, address.ip, notes.join(", "));
+        }
+    }
+
+    Ok(())
+}
+
+/// transfer decrypt <file> <output>
+fn decrypt(args: &[String]) -> Result<()> {
+    let [input, output] = args else {
+        return Err(anyhow::anyhow!("Usage: transfer decrypt <file> <output>"));
+    };
+
+    let config = Config::load_or_create()
+        .context("Failed to load config")?;
+    let mut keys = KeyResolver::new(&config.encryption);
+
+    crypto::decrypt_file(&mut keys, Path::new(input), Path::new(output))?;
+    println!("Decrypted {} to {}", input, output);
+
+    Ok(())
+}
+
+/// transfer export <output_dir>
+fn export(args: &[String]) -> Result<()> {
+    let [output_dir] = args else {
+        return Err(anyhow::anyhow!("Usage: transfer export <output_dir>"));
+    };
+
+    let config = Config::load_or_create()
+        .context("Failed to load config")?;
+    let mut keys = KeyResolver::new(&config.encryption);
+
+    let count = export_directory(&mut keys, Path::new(&config.folder), Path::new(output_dir))?;
+    println!("Exported {} file(s) from {} to {}", count, config.folder, output_dir);
+
+    Ok(())
+}
+
+/// transfer bans
+fn list_bans() -> Result<()> {
+    let bans = bans::list()?;
+    if bans.is_empty() {
+        println!("No banned addresses");
+    }
+    for ban in &bans {
+        println!("{:<39}  banned {}  until {}  ({} ban(s))", ban.ip, ban.since, ban.until, ban.count);
+    }
+
+    Ok(())
+}
+
+/// transfer unban <ip>
+fn unban(args: &[String]) -> Result<()> {
+    let [ip] = args else {
+        return Err(anyhow::anyhow!("Usage: transfer unban <ip>"));
+    };
+    if !bans::unban(ip)? {
+        return Err(anyhow::anyhow!("{} is not banned", ip));
+    }
+    println!("Unbanned {}", ip);
+    Ok(())
+}
+
+const LINK_USAGE: &str = "Usage: transfer link create [--folder <folder>] [--expires <age>] [--max-size <size>] [--uses <n>]
+       transfer link list
+       transfer link revoke <token>";
+
+/// transfer link create|list|revoke
+fn link(args: &[String]) -> Result<()> {
+    match args.split_first().map(|(command, rest)| (command.as_str(), rest)) {
+        Some(("create", rest)) => create_link(rest),
+        Some(("list", [])) => list_links(),
+        Some(("revoke", [token])) => {
+            if !links::revoke(token)? {
+                return Err(anyhow::anyhow!("No upload link with token {}", token));
+            }
+            println!("Revoked upload link {}", token);
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!(LINK_USAGE)),
+    }
+}
+
+fn create_link(args: &[String]) -> Result<()> {
+    let usage = LINK_USAGE;
+    let mut folder = None;
+    let mut lifetime = chrono::Duration::hours(24);
+    let mut max_size = 0;
+    let mut uses = 1;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--folder" => folder = Some(iter.next().context(usage)?.as_str()),
+            "--expires" => lifetime = history::parse_age(iter.next().context(usage)?)?,
+            "--max-size" => max_size = parse_size(iter.next().context(usage)?)?,
+            "--uses" => uses = iter.next().context(usage)?.parse().context(usage)?,
+            _ => return Err(anyhow::anyhow!(usage)),
+        }
+    }
+
+    let link = links::create(folder, lifetime, max_size, uses)?;
+    let config = Config::load_or_create()
+        .context("Failed to load config")?;
+
+    println!("{}", link.token);
+    println!();
+    println!("Valid until {} for {} transfer(s)", link.expires, link.uses);
+    println!("Send:   transfer send <host>:{} {} <path>...", config.port, link.token);
+    if config.http_upload {
+        println!("Upload: curl -T <file> -H \"Authorization: Bearer {}\" http://<host>:{}/", link.token, config.http_upload_port);
+    }
+
+    Ok(())
+}
+
+fn list_links() -> Result<()> {
+    let links = links::list()?;
+    if links.is_empty() {
+        println!("No upload links");
+    }
+    for link in &links {
+        let folder = if link.folder.is_empty() { "/" } else { link.folder.as_str() };
+        let max_size = if link.max_size > 0 { link.max_size.to_string() } else { "-".to_string() };
+        println!("{}  {:<8} {}/{} uses  expires {}  max {:>12}  {}", link.token, link.status(), link.used, link.uses, link.expires, max_size, folder);
+    }
+
+    Ok(())
+}
+
+const SHARE_USAGE: &str = "Usage: transfer share <path> [--expires <age>] [--downloads <n>]
+       transfer share list
+       transfer share revoke <token>";
+
+/// transfer share <path>|list|revoke
+fn share(args: &[String]) -> Result<()> {
+    match args.split_first().map(|(command, rest)| (command.as_str(), rest)) {
+        Some(("list", [])) => list_shares(),
+        Some(("revoke", [token])) => {
+            if !shares::revoke(token)? {
+                return Err(anyhow::anyhow!("No share with token {}", token));
+            }
+            println!("Revoked share {}", token);
+            Ok(())
+        }
+        Some((path, rest)) => create_share(path, rest),
+        None => Err(anyhow::anyhow!(SHARE_USAGE)),
+    }
+}
+
+fn create_share(path: &str, args: &[String]) -> Result<()> {
+    let usage = SHARE_USAGE;
+    let mut lifetime = chrono::Duration::days(1);
+    let mut downloads = 0;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--expires" => lifetime = history::parse_age(iter.next().context(usage)?)?,
+            "--downloads" => downloads = iter.next().context(usage)?.parse().context(usage)?,
+            _ => return Err(anyhow::anyhow!(usage)),
+        }
+    }
+
+    let config = Config::load_or_create()
+        .context("Failed to load config")?;
+    let share = shares::create(&config, path, lifetime, downloads)?;
+
+    println!("{}", share.token);
+    println!();
+    match share.downloads {
+        0 => println!("Valid until {}", share.expires),
+        downloads => println!("Valid until {} for {} download(s)", share.expires, downloads),
+    }
+    println!("Get:      transfer get <host>:{} {} {}", config.port, share.token, share.path);
+    if config.http_upload {
+        println!("Download: http://<host>:{}/{}/{}", config.http_upload_port, share.token, share.file_name());
+    }
+
+    Ok(())
+}
+
+fn list_shares() -> Result<()> {
+    let shares = shares::list()?;
+    if shares.is_empty() {
+        println!("No shares");
+    }
+    for share in &shares {
+        let downloads = match share.downloads {
+            0 => format!("{}", share.downloaded),
+            limit => format!("{}/{}", share.downloaded, limit),
+        };
+        println!("{}  {:<8} {:>7} downloads  expires {}  {}", share.token, share.status(), downloads, share.expires, share.path);
+    }
+
+    Ok(())
+}
+
+/// Parses a byte count with an optional K, M, G or T suffix (powers of 1024)
+fn parse_size(value: &str) -> Result<u64> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match value[digits.len()..].to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(anyhow::anyhow!("Invalid size unit in {} (use K, M, G or T)", value)),
+    };
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(multiplier))
+        .with_context(|| format!("Invalid size: {}", value))
+}
+
+/// Recursively copies a directory, decrypting encrypted files on the way
+fn export_directory(keys: &mut KeyResolver, source: &Path, destination: &Path) -> Result<usize> {
+    fs::create_dir_all(destination)
+        .with_context(|| format!("Failed to create directory: {}", destination.display()))?;
+
+    let mut count = 0;
+    for entry in fs::read_dir(source).with_context(|| format!("Failed to read directory: {}", source.display()))? {
+        let entry = entry.context("Failed to read directory entry")?;
+        let name = entry.file_name();
+        let source_path = entry.path();
+        let destination_path = destination.join(&name);
+
+        // Skip partially received files and uncommitted batches
+        if storage::is_internal_name(&name.to_string_lossy()) {
+            continue;
+        }
+
+        if source_path.is_dir() {
+            count += export_directory(keys, &source_path, &destination_path)?;
+        } else if crypto::is_encrypted_file(&source_path)? {
+            crypto::decrypt_file(keys, &source_path, &destination_path)?;
+            count += 1;
+        } else {
+            fs::copy(&source_path, &destination_path)
+                .with_context(|| format!("Failed to copy {}", source_path.display()))?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,444 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use crate::api;
+use crate::compression::{self, FrameEncoder};
+use crate::config;
+use crate::ip;
+use crate::metadata::FileMetadata;
+use crate::storage::IncomingFile;
+use crate::tree::{EntryKind, Manifest, ManifestEntry};
+
+/// Size of the chunks read from disk while sending file data
+const SEND_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest metadata options appended to a TRANSFER command, so the command
+/// fits in the server's read buffer
+const MAX_METADATA_OPTIONS: usize = 4096;
+
+/// Outcome of one file in a batch session
+#[derive(Debug)]
+pub struct BatchFileResult {
+    pub path: PathBuf,
+    /// Name the file was saved as, or the server's error message
+    pub result: std::result::Result<String, String>,
+}
+
+/// Outcome of a batch session
+#[derive(Debug)]
+pub struct BatchResult {
+    pub files: Vec<BatchFileResult>,
+    /// Final summary line sent by the server
+    pub summary: String,
+}
+
+/// An entry of a remote folder listing
+#[derive(Debug)]
+pub struct RemoteEntry {
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: u64,
+    pub name: String,
+}
+
+/// Sends a file to a transfer server and returns the name it was saved as
+///
+/// When `compress` is set, zstd compression is requested unless the file looks
+/// already compressed; the server decides in its ACK whether it is used.
+pub async fn send_file(addr: &str, transfer_id: &str, path: &Path, folder: Option<&str>, compress: bool) -> Result<String> {
+    let filename = file_name(path)?;
+
+    let file_size = tokio::fs::metadata(path).await
+        .with_context(|| format!("Failed to read file metadata: {}", path.display()))?
+        .len();
+
+    // Sample the start of the file to decide whether compression is worthwhile
+    let request_compression = compress && worth_compressing(path).await?;
+
+    let mut stream = connect(addr).await?;
+
+    // Send TRANSFER command (with the file's metadata) followed by the uncompressed file size
+    let metadata = FileMetadata::from_path(path).to_options(MAX_METADATA_OPTIONS);
+    let command = build_command(&format!("TRANSFER {} {}", transfer_id, filename), folder, request_compression, &metadata);
+    stream.write_all(command.as_bytes()).await
+        .context("Failed to send TRANSFER command")?;
+    stream.write_all(&file_size.to_be_bytes()).await
+        .context("Failed to send file size")?;
+
+    // Wait for ACK (or an error such as a size limit) before sending data
+    let compressed = read_ack(&mut stream).await?;
+
+    info!("Sending {} ({} bytes, compressed: {})", filename, file_size, compressed);
+
+    send_exact(&mut stream, path, file_size, compressed).await?;
+    stream.flush().await.context("Failed to flush file data")?;
+
+    let response = read_line(&mut stream).await?;
+    match response.strip_prefix("TRANSFER_COMPLETE: ") {
+        Some(saved_name) => Ok(saved_name.to_string()),
+        None => Err(anyhow::anyhow!("Transfer failed: {}", response)),
+    }
+}
+
+/// Sends several files over one connection as a batch session
+///
+/// Size limits are checked by the server for the whole batch before any data
+/// is sent; afterwards every file gets its own result. A `transactional` batch
+/// is only committed if every file arrived, otherwise it is aborted and none
+/// of the files are kept.
+pub async fn send_files(addr: &str, transfer_id: &str, paths: &[PathBuf], folder: Option<&str>, compress: bool, transactional: bool) -> Result<BatchResult> {
+    let mut manifest = Manifest::default();
+    let mut request_compression = false;
+    for path in paths {
+        let metadata = tokio::fs::metadata(path).await
+            .with_context(|| format!("Failed to read file metadata: {}", path.display()))?;
+        manifest.entries.push(ManifestEntry {
+            path: file_name(path)?.to_string(),
+            kind: EntryKind::File,
+            size: metadata.len(),
+            metadata: FileMetadata::from_path(path),
+        });
+        request_compression |= compress && worth_compressing(path).await?;
+    }
+
+    let mut stream = connect(addr).await?;
+
+    // Send SESSION command followed by the manifest
+    let mut command = build_command(&format!("SESSION {}", transfer_id), folder, request_compression, "");
+    if transactional {
+        command.insert_str(command.len() - 1, " transactional=true");
+    }
+    stream.write_all(command.as_bytes()).await
+        .context("Failed to send SESSION command")?;
+    write_manifest(&mut stream, &manifest).await?;
+
+    let compressed = read_ack(&mut stream).await?;
+
+    info!("Sending batch of {} files ({} bytes, compressed: {})", paths.len(), manifest.total_size(), compressed);
+
+    // Send every payload, reading the per-file result after each one
+    let mut files = Vec::with_capacity(paths.len());
+    for (path, entry) in paths.iter().zip(&manifest.entries) {
+        send_exact(&mut stream, path, entry.size, compressed).await?;
+        stream.flush().await.context("Failed to flush file data")?;
+
+        let response = read_line(&mut stream).await?;
+        let result = if let Some((_, name)) = response.strip_prefix("FILE_COMPLETE ").and_then(|r| r.split_once(": ")) {
+            Ok(name.to_string())
+        } else if let Some((_, error)) = response.strip_prefix("FILE_FAILED ").and_then(|r| r.split_once(": ")) {
+            Err(error.to_string())
+        } else {
+            return Err(anyhow::anyhow!("Batch failed: {}", response));
+        };
+        files.push(BatchFileResult { path: path.clone(), result });
+    }
+
+    if transactional {
+        return finish_transaction(&mut stream, files).await;
+    }
+
+    let summary = read_line(&mut stream).await?;
+    match summary.strip_prefix("SESSION_COMPLETE: ") {
+        Some(summary) => Ok(BatchResult { files, summary: summary.to_string() }),
+        None => Err(anyhow::anyhow!("Batch failed: {}", summary)),
+    }
+}
+
+/// Commits a staged batch if every file arrived and aborts it otherwise
+async fn finish_transaction(stream: &mut BufReader<TcpStream>, mut files: Vec<BatchFileResult>) -> Result<BatchResult> {
+    let staged = read_line(stream).await?;
+    if !staged.starts_with("SESSION_STAGED: ") {
+        return Err(anyhow::anyhow!("Batch failed: {}", staged));
+    }
+
+    let all_received = files.iter().all(|file| file.result.is_ok());
+    let decision: &[u8] = if all_received { b"COMMIT\n" } else { b"ABORT\n" };
+    stream.write_all(decision).await
+        .context("Failed to send commit decision")?;
+    stream.flush().await.context("Failed to flush commit decision")?;
+
+    if all_received {
+        // The final names can differ from the staged ones after commit
+        for file in files.iter_mut() {
+            let response = read_line(stream).await?;
+            match response.strip_prefix("FILE_COMMITTED ").and_then(|r| r.split_once(": ")) {
+                Some((_, name)) => file.result = Ok(name.to_string()),
+                None => return Err(anyhow::anyhow!("Commit failed: {}", response)),
+            }
+        }
+    } else {
+        for file in files.iter_mut().filter(|file| file.result.is_ok()) {
+            file.result = Err("Discarded, batch aborted".to_string());
+        }
+    }
+
+    let summary = read_line(stream).await?;
+    match summary.strip_prefix("SESSION_COMPLETE: ").or_else(|| summary.strip_prefix("SESSION_ABORTED: ")) {
+        Some(summary) => Ok(BatchResult { files, summary: summary.to_string() }),
+        None => Err(anyhow::anyhow!("Batch failed: {}", summary)),
+    }
+}
+
+/// Sends a directory tree to a transfer server and returns the name of the received root directory
+pub async fn send_directory(addr: &str, transfer_id: &str, path: &Path, folder: Option<&str>, compress: bool) -> Result<String> {
+    let name = file_name(path)?;
+
+    let manifest = Manifest::from_directory(path)?;
+
+    let mut stream = connect(addr).await?;
+
+    // Send TREE command followed by the manifest
+    let command = build_command(&format!("TREE {} {}", transfer_id, name), folder, compress, "");
+    stream.write_all(command.as_bytes()).await
+        .context("Failed to send TREE command")?;
+    write_manifest(&mut stream, &manifest).await?;
+
+    let compressed = read_ack(&mut stream).await?;
+
+    info!("Sending tree {} ({} entries, {} bytes, compressed: {})", name, manifest.entries.len(), manifest.total_size(), compressed);
+
+    // Stream file contents in manifest order, exactly the announced size each
+    for entry in manifest.files() {
+        send_exact(&mut stream, &path.join(&entry.path), entry.size, compressed).await?;
+    }
+    stream.flush().await.context("Failed to flush file data")?;
+
+    let response = read_line(&mut stream).await?;
+    match response.strip_prefix("TREE_COMPLETE: ") {
+        Some(result) => Ok(result.to_string()),
+        None => Err(anyhow::anyhow!("Transfer failed: {}", response)),
+    }
+}
+
+/// Lists a folder on a transfer server that allows reads
+pub async fn list_folder(addr: &str, transfer_id: &str, folder: Option<&str>) -> Result<Vec<RemoteEntry>> {
+    let mut stream = connect(addr).await?;
+
+    let command = build_command(&format!("LIST {}", transfer_id), folder, false, "");
+    stream.write_all(command.as_bytes()).await
+        .context("Failed to send LIST command")?;
+
+    let mut entries = Vec::new();
+    loop {
+        let response = read_line(&mut stream).await?;
+        if response.starts_with("LIST_COMPLETE: ") {
+            return Ok(entries);
+        }
+        let Some(entry) = response.strip_prefix("ENTRY ") else {
+            return Err(anyhow::anyhow!("List failed: {}", response));
+        };
+
+        // <kind> <size> <mtime> <name>, where the name may contain spaces
+        let mut fields = entry.splitn(4, ' ');
+        let (Some(kind), Some(size), Some(mtime), Some(name)) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            return Err(anyhow::anyhow!("Invalid list entry: {}", entry));
+        };
+        entries.push(RemoteEntry {
+            kind: if kind == "dir" { EntryKind::Dir } else { EntryKind::File },
+            size: size.parse().with_context(|| format!("Invalid list entry: {}", entry))?,
+            mtime: mtime.parse().with_context(|| format!("Invalid list entry: {}", entry))?,
+            name: name.to_string(),
+        });
+    }
+}
+
+/// Downloads a file from a transfer server that allows reads and returns its size
+///
+/// The data is written to a temporary file next to `output`, which only
+/// appears once the download is complete.
+pub async fn get_file(addr: &str, transfer_id: &str, path: &str, output: &Path, compress: bool) -> Result<u64> {
+    let output_dir = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let output_name = file_name(output)?;
+
+    let mut stream = connect(addr).await?;
+
+    let command = build_command(&format!("GET {} {}", transfer_id, path), None, compress, "");
+    stream.write_all(command.as_bytes()).await
+        .context("Failed to send GET command")?;
+
+    let compressed = read_ack(&mut stream).await?;
+    let size = stream.read_u64().await
+        .context("Failed to read file size")?;
+
+    info!("Receiving {} ({} bytes, compressed: {})", path, size, compressed);
+
+    let mut incoming = IncomingFile::create(output_dir, output_name).await?;
+    if let Err(e) = api::receive_payload(&mut stream, &mut incoming, size, compressed).await {
+        incoming.abort().await;
+        return Err(e);
+    }
+
+    let response = read_line(&mut stream).await?;
+    if !response.starts_with("GET_COMPLETE: ") {
+        incoming.abort().await;
+        return Err(anyhow::anyhow!("Download failed: {}", response));
+    }
+    incoming.finish_at(output).await?;
+
+    Ok(size)
+}
+
+/// Deletes a file or directory on a transfer server, with a token that may delete
+pub async fn delete_path(addr: &str, transfer_id: &str, path: &str) -> Result<()> {
+    let mut stream = connect(addr).await?;
+
+    stream.write_all(format!("DELETE {} {}\n", transfer_id, path).as_bytes()).await
+        .context("Failed to send DELETE command")?;
+
+    let response = read_line(&mut stream).await?;
+    if !response.starts_with("DELETE_COMPLETE: ") {
+        return Err(anyhow::anyhow!("Delete failed: {}", response));
+    }
+    Ok(())
+}
+
+/// Opens a connection to a transfer server
+/// `addr` may be "host:port", "[v6]:port" or a bare host / IPv6 address using the default port
+async fn connect(addr: &str) -> Result<BufReader<TcpStream>> {
+    let addr = ip::normalize_destination(addr, config::DEFAULT_PORT)?;
+    let stream = TcpStream::connect(&addr).await
+        .with_context(|| format!("Failed to connect to {}", addr))?;
+    Ok(BufReader::new(stream))
+}
+
+/// Appends the optional folder, compression request and extra options to a command line
+fn build_command(base: &str, folder: Option<&str>, compress: bool, options: &str) -> String {
+    let mut command = base.to_string();
+    if let Some(folder) = folder {
+        command.push_str(&format!(" {}", folder));
+    }
+    if compress {
+        command.push_str(&format!(" compress={}", compression::ZSTD));
+    }
+    command.push_str(options);
+    command.push('\n');
+    command
+}
+
+/// Writes a length-prefixed JSON manifest
+async fn write_manifest(stream: &mut BufReader<TcpStream>, manifest: &Manifest) -> Result<()> {
+    let manifest_json = serde_json::to_vec(manifest)
+        .context("Failed to serialize manifest")?;
+    stream.write_all(&(manifest_json.len() as u64).to_be_bytes()).await
+        .context("Failed to send manifest length")?;
+    stream.write_all(&manifest_json).await
+        .context("Failed to send manifest")
+}
+
+/// Waits for the ACK and returns whether the server agreed to compression
+async fn read_ack(stream: &mut BufReader<TcpStream>) -> Result<bool> {
+    let response = read_line(stream).await?;
+    let Some(ack_options) = response.strip_prefix("ACK") else {
+        let reason = response.strip_prefix("REJECTED: ").unwrap_or(&response);
+        return Err(anyhow::anyhow!("Transfer rejected: {}", reason));
+    };
+    Ok(ack_options.split_whitespace()
+        .any(|option| option == format!("compress={}", compression::ZSTD)))
+}
+
+/// Sends exactly `size` bytes of a file, failing if the file changed size
+async fn send_exact(stream: &mut BufReader<TcpStream>, path: &Path, size: u64, compressed: bool) -> Result<()> {
+    let file = File::open(path).await
+        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut limited = file.take(size);
+
+    let sent = if compressed {
+        send_compressed(&mut limited, stream).await?
+    } else {
+        send_raw(&mut limited, stream).await?
+    };
+    if sent != size {
+        return Err(anyhow::anyhow!("{} changed size while sending", path.display()));
+    }
+    Ok(())
+}
+
+/// Samples the start of a file to decide whether compression is worthwhile
+async fn worth_compressing(path: &Path) -> Result<bool> {
+    let mut file = File::open(path).await
+        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut sample = vec![0u8; compression::SAMPLE_SIZE];
+    let n = read_full(&mut file, &mut sample).await?;
+    Ok(compression::should_compress(path, &sample[..n]))
+}
+
+/// Returns the final component of a path as a string
+fn file_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("Invalid file name: {}", path.display()))
+}
+
+/// Streams the file as-is and returns the number of bytes sent
+async fn send_raw<R: AsyncRead + Unpin>(file: &mut R, stream: &mut BufReader<TcpStream>) -> Result<u64> {
+    let mut buffer = vec![0u8; SEND_CHUNK_SIZE];
+    let mut sent = 0u64;
+    loop {
+        let n = file.read(&mut buffer).await
+            .context("Failed to read file")?;
+        if n == 0 {
+            return Ok(sent);
+        }
+        stream.write_all(&buffer[..n]).await
+            .context("Failed to send file data")?;
+        sent += n as u64;
+    }
+}
+
+/// Streams the file as zstd compressed frames and returns the number of uncompressed bytes sent
+async fn send_compressed<R: AsyncRead + Unpin>(file: &mut R, stream: &mut BufReader<TcpStream>) -> Result<u64> {
+    let mut encoder = FrameEncoder::new()?;
+    let mut buffer = vec![0u8; SEND_CHUNK_SIZE];
+    let mut sent = 0u64;
+    loop {
+        let n = file.read(&mut buffer).await
+            .context("Failed to read file")?;
+        if n == 0 {
+            break;
+        }
+        if let Some(frame) = encoder.update(&buffer[..n])? {
+            stream.write_all(&frame).await
+                .context("Failed to send compressed frame")?;
+        }
+        sent += n as u64;
+    }
+
+    let remaining = encoder.finish()?;
+    stream.write_all(&remaining).await
+        .context("Failed to send compressed frame")?;
+    Ok(sent)
+}
+
+/// Reads a single response line from the server
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    let n = stream.read_line(&mut line).await
+        .context("Failed to read response from server")?;
+    if n == 0 {
+        return Err(anyhow::anyhow!("Server closed the connection"));
+    }
+    Ok(line.trim().to_string())
+}
+
+/// Reads until the buffer is full or the end of the file is reached
+async fn read_full(file: &mut File, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = file.read(&mut buffer[filled..]).await
+            .context("Failed to read file")?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
@@ -0,0 +1,239 @@
+use anyhow::{Context, Result};
+use std::{
+    io::Write,
+    path::Path,
+};
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+/// Name of the compression algorithm negotiated in the TRANSFER exchange
+pub const ZSTD: &str = "zstd";
+
+/// zstd compression level used by senders
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Number of bytes sampled from the start of a file to estimate its entropy
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Entropy (bits per byte) above which data is considered already compressed
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// File extensions that are already compressed and not worth compressing again
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar",
+    "jpg", "jpeg", "png", "gif", "webp", "heic",
+    "mp3", "aac", "ogg", "flac", "mp4", "mkv", "mov", "avi", "webm",
+];
+
+/// Decides whether a file should be sent compressed
+/// Known compressed formats are skipped by extension, everything else by sampling entropy
+pub fn should_compress(path: &Path, sample: &[u8]) -> bool {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    if let Some(extension) = extension && COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+        return false;
+    }
+
+    // Tiny files don't benefit from compression
+    if sample.len() < 512 {
+        return false;
+    }
+
+    shannon_entropy(sample) < ENTROPY_THRESHOLD
+}
+
+/// Calculates the Shannon entropy of the data in bits per byte
+fn shannon_entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+
+    let len = data.len() as f64;
+    counts.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Streaming zstd compressor producing the frames sent over the wire
+///
+/// Compressed payloads are sent as a sequence of frames, each a 4 byte
+/// big-endian length followed by that many bytes of the zstd stream. A frame
+/// with length zero ends the payload.
+pub struct FrameEncoder {
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Result<Self> {
+        let encoder = zstd::stream::write::Encoder::new(Vec::new(), COMPRESSION_LEVEL)
+            .context("Failed to create zstd encoder")?;
+        Ok(Self { encoder })
+    }
+
+    /// Compresses data and returns the next frame, or None if nothing is ready yet
+    pub fn update(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.encoder.write_all(data)
+            .context("Failed to compress data")?;
+        Ok(take_frame(self.encoder.get_mut()))
+    }
+
+    /// Finishes the zstd stream and returns the remaining frames including the end marker
+    pub fn finish(self) -> Result<Vec<u8>> {
+        let mut remaining = self.encoder.finish()
+            .context("Failed to finish zstd stream")?;
+        let mut output = take_frame(&mut remaining).unwrap_or_default();
+        output.extend_from_slice(&0u32.to_be_bytes());
+        Ok(output)
+    }
+}
+
+/// Wraps the pending compressed bytes into a length-prefixed frame
+fn take_frame(pending: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if pending.is_empty() {
+        return None;
+    }
+    let mut frame = Vec::with_capacity(4 + pending.len());
+    frame.extend_from_slice(&(pending.len() as u32).to_be_bytes());
+    frame.append(pending);
+    Some(frame)
+}
+
+/// Largest piece decompressed at once, so the limit is checked before more is produced
+const DECOMPRESS_CHUNK: usize = 64 * 1024;
+
+/// Streaming zstd decompressor for received frames
+pub struct FrameDecoder {
+    decoder: zstd::stream::raw::Decoder<'static>,
+    limit: u64,
+    produced: u64,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder that refuses to produce more than `limit` bytes
+    pub fn new(limit: u64) -> Result<Self> {
+        let decoder = zstd::stream::raw::Decoder::new()
+            .context("Failed to create zstd decoder")?;
+        Ok(Self { decoder, limit, produced: 0 })
+    }
+
+    /// Decompresses a frame payload and returns the decompressed bytes
+    ///
+    /// The frame is decompressed in chunks of at most one byte past the limit,
+    /// so a small frame that expands enormously fails without being expanded.
+    pub fn update(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        let mut input = InBuffer::around(frame);
+        let mut output = Vec::new();
+        let mut chunk = vec![0u8; DECOMPRESS_CHUNK];
+        loop {
+            let room = self.limit.saturating_sub(self.produced).saturating_add(1).min(DECOMPRESS_CHUNK as u64) as usize;
+            let mut out = OutBuffer::around(&mut chunk[..room]);
+            self.decoder.run(&mut input, &mut out)
+                .context("Failed to decompress data")?;
+            let written = out.pos();
+
+            self.produced += written as u64;
+            if self.produced > self.limit {
+                return Err(anyhow::anyhow!(
+                    "Decompressed data exceeds announced file size of {} bytes", self.limit
+                ));
+            }
+            output.extend_from_slice(&chunk[..written]);
+
+            // A full chunk may leave more output buffered inside the decoder
+            if input.pos() == frame.len() && written < room {
+                return Ok(output);
+            }
+        }
+    }
+
+    /// Returns the number of decompressed bytes produced so far
+    pub fn produced(&self) -> u64 {
+        self.produced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compresses `data` and splits the wire format back into frame payloads
+    fn frames(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut encoder = FrameEncoder::new().unwrap();
+        let mut wire = encoder.update(data).unwrap().unwrap_or_default();
+        wire.extend(encoder.finish().unwrap());
+
+        let mut frames = Vec::new();
+        let mut rest = wire.as_slice();
+        loop {
+            let (length, tail) = rest.split_at(4);
+            let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+            if length == 0 {
+                return frames;
+            }
+            frames.push(tail[..length].to_vec());
+            rest = &tail[length..];
+        }
+    }
+
+    fn decode(frames: &[Vec<u8>], limit: u64) -> Result<Vec<u8>> {
+        let mut decoder = FrameDecoder::new(limit)?;
+        let mut output = Vec::new();
+        for frame in frames {
+            output.extend(decoder.update(frame)?);
+        }
+        assert_eq!(decoder.produced(), output.len() as u64);
+        Ok(output)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(decode(&frames(&data), data.len() as u64).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_payload() {
+        assert!(decode(&frames(b""), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn exactly_at_limit() {
+        let data = vec![7u8; 10_000];
+        assert_eq!(decode(&frames(&data), 10_000).unwrap().len(), 10_000);
+    }
+
+    #[test]
+    fn rejects_data_past_limit() {
+        let data = vec![7u8; 10_000];
+        let e = decode(&frames(&data), 9_999).unwrap_err();
+        assert!(e.to_string().contains("exceeds announced file size"));
+    }
+
+    #[test]
+    fn stops_expanding_bomb_at_limit() {
+        // 64 MiB of zeros compresses to a few KiB
+        let bomb = zstd::encode_all(std::io::Read::take(std::io::repeat(0), 64 << 20), COMPRESSION_LEVEL).unwrap();
+        let mut decoder = FrameDecoder::new(1000).unwrap();
+        assert!(decoder.update(&bomb).is_err());
+        assert!(decoder.produced() <= 1001);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut decoder = FrameDecoder::new(1000).unwrap();
+        assert!(decoder.update(b"definitely not zstd").is_err());
+    }
+
+    #[test]
+    fn compresses_text_but_not_archives() {
+        let text = "hello world ".repeat(100);
+        assert!(should_compress(Path::new("notes.txt"), text.as_bytes()));
+        assert!(!should_compress(Path::new("notes.ZIP"), text.as_bytes()));
+        assert!(!should_compress(Path::new("tiny.txt"), b"hello"));
+    }
+}
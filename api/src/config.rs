@@ -1,21 +1,48 @@
 use anyhow::{Context, Result};
+use log::warn;
+use once_cell::sync::Lazy;
 use rand::{distributions::Alphanumeric, Rng};
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::Write,
-    path::Path,
+    fs,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
 };
-use toml;
 use crate::structure;
 
+/// The config commands run with, so they don't read transfer.toml every time
+///
+/// Read again when the file's modification time changes. None until first used.
+static CURRENT: Lazy<Mutex<Option<Loaded>>> = Lazy::new(|| Mutex::new(None));
+
+struct Loaded {
+    config: Config,
+    /// Modification time of transfer.toml when it was last read or written
+    modified: Option<SystemTime>,
+}
+
 // Configuration structure that maps to transfer.toml
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default = "default_bind")]
     pub bind: String,
+    /// Network interface to bind to: "" picks one automatically, "any" uses all
+    #[serde(default)]
+    pub interface: String,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Addresses to listen on instead of bind:port, e.g. "[::]" for dual-stack
+    /// or "192.168.1.5:1000"; entries without a port use `port`
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// Networks that may connect, e.g. "192.168.1.0/24" or "fd00::/8"; empty for any
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Networks that may not connect, even if allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
     #[serde(default = "generate_transfer_id")]
     pub transfer_id: String,
     #[serde(default = "default_folder")]
@@ -24,6 +51,199 @@ pub struct Config {
     pub max_folder_size: u64,
     #[serde(default = "default_max_file_size")]
     pub max_file_size: u64,
+    /// What happens when a received name is taken: "rename", "overwrite" or "reject"
+    #[serde(default = "default_conflict")]
+    pub conflict: String,
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    #[serde(default = "default_allow_read")]
+    pub allow_read: bool,
+    #[serde(default = "default_allow_delete")]
+    pub allow_delete: bool,
+    #[serde(default = "default_name")]
+    pub name: String,
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    /// Answer UDP broadcast probes on beacon_port, for networks without multicast
+    #[serde(default = "default_beacon")]
+    pub beacon: bool,
+    #[serde(default = "default_beacon_port")]
+    pub beacon_port: u16,
+    #[serde(default = "default_history")]
+    pub history: bool,
+    #[serde(default = "default_metrics")]
+    pub metrics: bool,
+    #[serde(default = "default_metrics_address")]
+    pub metrics_address: String,
+    /// Also accept transfers over WebSocket, so browsers can send directly
+    #[serde(default = "default_websocket")]
+    pub websocket: bool,
+    #[serde(default = "default_websocket_port")]
+    pub websocket_port: u16,
+    /// Accept PUT and multipart POST uploads, e.g. from curl
+    #[serde(default = "default_http_upload")]
+    pub http_upload: bool,
+    #[serde(default = "default_http_upload_port")]
+    pub http_upload_port: u16,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Further inboxes, selected by the transfer ID senders present
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inboxes: Vec<InboxConfig>,
+    /// Named tokens with limited permissions, presented instead of a transfer ID
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<TokenConfig>,
+    /// Root folder of the inbox in use once `folder` was narrowed to a subfolder of it
+    #[serde(skip)]
+    inbox_root: Option<String>,
+}
+
+// At-rest encryption settings for received files ([encryption] table)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// "off", "keyfile" or "passphrase"
+    #[serde(default = "default_encryption_mode")]
+    pub mode: String,
+    /// Path of the key file used in keyfile mode
+    #[serde(default = "default_key_file")]
+    pub key_file: String,
+    /// Hex encoded salt used to derive the key in passphrase mode
+    #[serde(default = "crate::crypto::generate_salt")]
+    pub salt: String,
+}
+
+// Which sender metadata is applied to received files ([metadata] table)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataConfig {
+    /// Apply the sender's modification time
+    #[serde(default = "default_preserve_mtime")]
+    pub preserve_mtime: bool,
+    /// "preserve", "strip_setuid" (drop setuid and setgid bits) or "ignore"
+    #[serde(default = "default_permissions")]
+    pub permissions: String,
+    /// Apply extended attributes in the user namespace
+    #[serde(default = "default_preserve_xattrs")]
+    pub preserve_xattrs: bool,
+}
+
+// Interactive accept/reject of incoming transfers ([approval] table)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalConfig {
+    /// Ask the local user before accepting a transfer
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds to wait for a decision before rejecting
+    #[serde(default = "default_approval_timeout")]
+    pub timeout: u64,
+    /// Sender IP addresses that are accepted without asking
+    #[serde(default)]
+    pub trusted: Vec<String>,
+    /// Loopback port of the control socket used by the UI, which needs control.token
+    #[serde(default = "default_control_port")]
+    pub control_port: u16,
+}
+
+// Local HTTP control API used by the web UI ([control] table)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlConfig {
+    #[serde(default = "default_control_enabled")]
+    pub enabled: bool,
+    /// Loopback address the API listens on
+    #[serde(default = "default_control_address")]
+    pub address: String,
+    /// Bearer token every request must present
+    #[serde(default = "generate_control_token")]
+    pub token: String,
+}
+
+// Connection limits and timeouts of the transfer server ([limits] table)
+//
+// 0 turns a limit off. Exceeding one ends the connection with an ERROR line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    /// Connections handled at the same time
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Connections handled at the same time from one IP address
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+    /// Seconds a client has to send its next command
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+    /// Seconds a transfer may go without receiving or sending any data
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Slowest transfer accepted in bytes per second, averaged over 30 seconds of waiting for data
+    #[serde(default = "default_min_rate")]
+    pub min_rate: u64,
+    /// Seconds a connection may stay open
+    #[serde(default = "default_max_session")]
+    pub max_session: u64,
+    /// Failed authentications from one IP address before it is banned
+    #[serde(default = "default_max_auth_failures")]
+    pub max_auth_failures: u32,
+    /// Seconds the first ban of an address lasts; every further ban lasts twice as long
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+}
+
+// An inbox hosted next to the default one ([[inboxes]] tables)
+//
+// The top-level transfer_id, folder, size limits and conflict policy make up
+// the default inbox; a sender presenting an inbox's transfer_id gets its settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboxConfig {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "generate_transfer_id")]
+    pub transfer_id: String,
+    /// Root folder of the inbox, empty for `<folder>-<name>` next to the default inbox
+    #[serde(default)]
+    pub folder: String,
+    #[serde(default = "default_max_folder_size")]
+    pub max_folder_size: u64,
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    #[serde(default = "default_conflict")]
+    pub conflict: String,
+    /// Networks the inbox can be used from, empty for any
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+// A named access token ([[tokens]] tables)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenConfig {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "generate_control_token")]
+    pub token: String,
+    /// Any of "upload", "list", "download", "delete" and "admin" (all of them)
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    /// Inbox the token belongs to, empty for the default inbox
+    #[serde(default)]
+    pub inbox: String,
+    /// Subfolder the token is limited to, empty for the whole inbox
+    #[serde(default)]
+    pub folder: String,
+    /// RFC 3339, empty if the token doesn't expire
+    #[serde(default)]
+    pub expires: String,
+    /// Largest file the token may upload in bytes, 0 for the inbox limit
+    #[serde(default)]
+    pub max_size: u64,
+    /// Networks the token can be used from, empty for any
+    #[serde(default)]
+    pub allow: Vec<String>,
 }
 
 /// Default function for bind field
@@ -31,9 +251,12 @@ fn default_bind() -> String {
     "127.0.0.1".to_string()
 }
 
+/// Port used when neither the config nor a destination specifies one
+pub const DEFAULT_PORT: u16 = 1000;
+
 /// Default function for port field
 fn default_port() -> u16 {
-    1000
+    DEFAULT_PORT
 }
 
 /// Default function for folder field
@@ -51,55 +274,478 @@ fn default_max_file_size() -> u64 {
     0
 }
 
+/// Default function for conflict field (keep both files, the new one under a numbered name)
+fn default_conflict() -> String {
+    crate::storage::CONFLICT_RENAME.to_string()
+}
+
+/// Default function for compression field (allow senders to negotiate zstd)
+fn default_compression() -> bool {
+    true
+}
+
+/// Default function for allow_read field (LIST and GET are opt-in)
+fn default_allow_read() -> bool {
+    false
+}
+
+/// Default function for allow_delete field (DELETE is opt-in, separately from reading)
+fn default_allow_delete() -> bool {
+    false
+}
+
+/// Default function for name field (device name shown to other devices, the host name)
+fn default_name() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+/// Default function for mdns field (advertise the server on the local network)
+fn default_mdns() -> bool {
+    true
+}
+
+/// Default function for beacon field (off: mDNS covers most networks, and
+/// the beacon answers anyone on the wildcard address)
+fn default_beacon() -> bool {
+    false
+}
+
+/// Default function for beacon_port field
+fn default_beacon_port() -> u16 {
+    1001
+}
+
+/// Default function for history field (record received transfers in history.jsonl)
+fn default_history() -> bool {
+    true
+}
+
+/// Default function for metrics field (the /metrics endpoint is opt-in)
+fn default_metrics() -> bool {
+    false
+}
+
+/// Default function for metrics_address field (only reachable from this machine)
+fn default_metrics_address() -> String {
+    "127.0.0.1:9185".to_string()
+}
+
+/// Default function for websocket field
+fn default_websocket() -> bool {
+    true
+}
+
+/// Default function for websocket_port field
+fn default_websocket_port() -> u16 {
+    1004
+}
+
+/// Default function for http_upload field (the HTTP listener is opt-in)
+fn default_http_upload() -> bool {
+    false
+}
+
+/// Default function for http_upload_port field
+fn default_http_upload_port() -> u16 {
+    1005
+}
+
+/// Default function for encryption.mode field
+fn default_encryption_mode() -> String {
+    "off".to_string()
+}
+
+/// Default function for encryption.key_file field (stored next to transfer.toml)
+fn default_key_file() -> String {
+    structure::get_config_directory()
+        .map(|dir| dir.join("transfer.key").to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Default function for metadata.preserve_mtime field
+fn default_preserve_mtime() -> bool {
+    true
+}
+
+/// Default function for metadata.permissions field
+fn default_permissions() -> String {
+    crate::metadata::PERMISSIONS_STRIP_SETUID.to_string()
+}
+
+/// Default function for metadata.preserve_xattrs field
+fn default_preserve_xattrs() -> bool {
+    true
+}
+
+/// Default function for approval.timeout field
+fn default_approval_timeout() -> u64 {
+    60
+}
+
+/// Default function for approval.control_port field
+fn default_control_port() -> u16 {
+    1002
+}
+
+/// Default function for control.enabled field
+fn default_control_enabled() -> bool {
+    true
+}
+
+/// Default function for control.address field
+fn default_control_address() -> String {
+    "127.0.0.1:1003".to_string()
+}
+
+/// Default function for control.token field (random, 32 bytes hex encoded)
+fn generate_control_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Default function for limits.max_connections field
+fn default_max_connections() -> usize {
+    256
+}
+
+/// Default function for limits.max_connections_per_ip field
+fn default_max_connections_per_ip() -> usize {
+    16
+}
+
+/// Default function for limits.command_timeout field
+fn default_command_timeout() -> u64 {
+    30
+}
+
+/// Default function for limits.idle_timeout field
+fn default_idle_timeout() -> u64 {
+    60
+}
+
+/// Default function for limits.min_rate field (1 KiB/s)
+fn default_min_rate() -> u64 {
+    1024
+}
+
+/// Default function for limits.max_session field (large transfers can take hours)
+fn default_max_session() -> u64 {
+    0
+}
+
+/// Default function for limits.max_auth_failures field
+fn default_max_auth_failures() -> u32 {
+    10
+}
+
+/// Default function for limits.ban_duration field (1 hour)
+fn default_ban_duration() -> u64 {
+    3600
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            mode: default_encryption_mode(),
+            key_file: default_key_file(),
+            salt: crate::crypto::generate_salt(),
+        }
+    }
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        Self {
+            preserve_mtime: default_preserve_mtime(),
+            permissions: default_permissions(),
+            preserve_xattrs: default_preserve_xattrs(),
+        }
+    }
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: default_approval_timeout(),
+            trusted: Vec::new(),
+            control_port: default_control_port(),
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_control_enabled(),
+            address: default_control_address(),
+            token: generate_control_token(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            max_connections_per_ip: default_max_connections_per_ip(),
+            command_timeout: default_command_timeout(),
+            idle_timeout: default_idle_timeout(),
+            min_rate: default_min_rate(),
+            max_session: default_max_session(),
+            max_auth_failures: default_max_auth_failures(),
+            ban_duration: default_ban_duration(),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             bind: default_bind(),
+            interface: String::new(),
             port: default_port(),
+            listen: Vec::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
             transfer_id: generate_transfer_id(),
             folder: default_folder(),
             max_folder_size: default_max_folder_size(),
             max_file_size: default_max_file_size(),
+            conflict: default_conflict(),
+            compression: default_compression(),
+            allow_read: default_allow_read(),
+            allow_delete: default_allow_delete(),
+            name: default_name(),
+            mdns: default_mdns(),
+            beacon: default_beacon(),
+            beacon_port: default_beacon_port(),
+            history: default_history(),
+            metrics: default_metrics(),
+            metrics_address: default_metrics_address(),
+            websocket: default_websocket(),
+            websocket_port: default_websocket_port(),
+            http_upload: default_http_upload(),
+            http_upload_port: default_http_upload_port(),
+            encryption: EncryptionConfig::default(),
+            metadata: MetadataConfig::default(),
+            approval: ApprovalConfig::default(),
+            control: ControlConfig::default(),
+            limits: LimitsConfig::default(),
+            inboxes: Vec::new(),
+            tokens: Vec::new(),
+            inbox_root: None,
         }
     }
 }
 
 impl Config {
     /// Loads config from transfer.toml if exists, or creates a new one
+    ///
+    /// The file is only written when it is created, lacks settings added
+    /// since, or the detected bind address changed.
     pub fn load_or_create() -> Result<Self> {
-        // Get the AppData config directory path
-        let config_dir = structure::get_config_directory()?;
-        let config_path = config_dir.join("transfer.toml");
+        let config_path = Self::path()?;
         
-        let mut config = if config_path.exists() {
+        let (mut config, mut changed) = if config_path.exists() {
             let content = fs::read_to_string(&config_path)
                 .context("Failed to read transfer.toml")?;
                 
-            // Try to parse the existing config, if it fails, create a new default config
-            match toml::from_str::<Config>(&content) {
-                Ok(parsed_config) => {
-                    // Successfully parsed, use it
-                    parsed_config
-                }
-                Err(_) => {
-                    // Failed to parse, create new default config
-                    Config::default()
-                }
-            }
+            // A config that doesn't parse is reported and left alone, never replaced by defaults
+            let config = toml::from_str::<Config>(&content)
+                .with_context(|| format!("Failed to parse {}", config_path.display()))?;
+            let missing = has_missing_fields(&content, &config)?;
+            (config, missing)
         } else {
             // No config file exists, create default
-            Config::default()
+            (Config::default(), true)
         };
             
-        // Update bind with detected public IP on every startup
-            if let Ok(public_ip) = crate::ip::detect_public_ip() {
-                config.bind = public_ip;
+        // Update bind with the address of the configured (or detected) interface on every startup
+        match crate::ip::detect_bind_address(&config.interface) {
+            Ok(address) if address != config.bind => {
+                config.bind = address;
+                changed = true;
             }
+            Ok(_) => {}
+            Err(e) => warn!("Keeping bind address {}: {:#}", config.bind, e),
+        }
             
-        // Always save the config to ensure it's up to date and any missing fields are added
+        if changed {
             config.save_to_path(&config_path)?;
+        }
             
-            Ok(config)
+        Ok(config)
+    }
+
+    /// The config in use, read again only when transfer.toml changed
+    ///
+    /// While the file doesn't parse (e.g. half way through an edit), the last
+    /// good config stays in use.
+    pub fn current() -> Result<Self> {
+        let config_path = Self::path()?;
+        let mut current = CURRENT.lock().unwrap();
+        if let Some(loaded) = current.as_ref()
+            && loaded.modified == modified_time(&config_path) {
+            return Ok(loaded.config.clone());
+        }
+
+        let config = match (Self::load_or_create(), current.take()) {
+            (Ok(config), _) => config,
+            (Err(e), Some(loaded)) => {
+                warn!("Keeping the previous config: {:#}", e);
+                loaded.config
+            }
+            (Err(e), None) => return Err(e),
+        };
+        *current = Some(Loaded { config: config.clone(), modified: modified_time(&config_path) });
+        Ok(config)
+    }
+
+    /// Writes the config back to transfer.toml
+    pub fn save(&self) -> Result<()> {
+        let config_path = Self::path()?;
+        let mut current = CURRENT.lock().unwrap();
+        self.save_to_path(&config_path)?;
+        *current = Some(Loaded { config: self.clone(), modified: modified_time(&config_path) });
+        Ok(())
+    }
+
+    fn path() -> Result<PathBuf> {
+        Ok(structure::get_config_directory()?.join("transfer.toml"))
+    }
+
+    /// The config Config::current() returns to tests that use it: a receive
+    /// folder in a temporary directory, reads allowed, files up to 4 KiB and no bans
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        static SAVED: Lazy<(tempfile::TempDir, Config)> = Lazy::new(|| {
+            let folder = tempfile::tempdir().unwrap();
+            let config = Config {
+                folder: folder.path().to_string_lossy().to_string(),
+                allow_read: true,
+                max_file_size: 4096,
+                limits: LimitsConfig { max_auth_failures: 0, ..Default::default() },
+                ..Default::default()
+            };
+            config.save().unwrap();
+            (folder, config)
+        });
+        SAVED.1.clone()
+    }
+
+    /// Switches to the inbox selected by `transfer_id`, if it names one
+    ///
+    /// The inbox's transfer ID, folder, size limits and conflict policy
+    /// replace the top-level ones. Returns the inbox, or None for any other ID.
+    pub fn select_inbox(&mut self, transfer_id: &str) -> Option<InboxConfig> {
+        if crate::control::constant_time_eq(transfer_id.as_bytes(), self.transfer_id.as_bytes()) {
+            return None;
+        }
+        let inbox = self.inboxes.iter()
+            .find(|inbox| crate::control::constant_time_eq(transfer_id.as_bytes(), inbox.transfer_id.as_bytes()))
+            .cloned()?;
+
+        log::info!("Transfer ID selects inbox {}", inbox.name);
+        self.use_inbox(&inbox);
+        Some(inbox)
+    }
+
+    /// Switches to the inbox called `name`; returns None for the default inbox (empty name)
+    pub fn select_inbox_named(&mut self, name: &str) -> Result<Option<InboxConfig>> {
+        if name.is_empty() {
+            return Ok(None);
+        }
+        let inbox = self.inboxes.iter()
+            .find(|inbox| inbox.name == name)
+            .cloned()
+            .with_context(|| format!("Unknown inbox: {}", name))?;
+        self.use_inbox(&inbox);
+        Ok(Some(inbox))
+    }
+
+    fn use_inbox(&mut self, inbox: &InboxConfig) {
+        self.folder = self.inbox_folder(inbox);
+        self.inbox_root = None;
+        self.transfer_id = inbox.transfer_id.clone();
+        self.max_folder_size = inbox.max_folder_size;
+        self.max_file_size = inbox.max_file_size;
+        self.conflict = inbox.conflict.clone();
+    }
+
+    /// Limits the receive folder to `subfolder` (if not empty) and files to
+    /// `max_size` bytes (if not 0 and below max_file_size)
+    pub fn narrow(&mut self, subfolder: &str, max_size: u64) {
+        if !subfolder.is_empty() {
+            self.inbox_root.get_or_insert_with(|| self.folder.clone());
+            self.folder = Path::new(&self.folder).join(subfolder).to_string_lossy().to_string();
+        }
+        if max_size > 0 && (self.max_file_size == 0 || max_size < self.max_file_size) {
+            self.max_file_size = max_size;
+        }
+    }
+
+    /// Root folder of the inbox in use, even after `narrow`
+    ///
+    /// Batches are staged here, where the startup cleanup looks for them.
+    pub fn inbox_root(&self) -> &str {
+        self.inbox_root.as_deref().unwrap_or(&self.folder)
+    }
+
+    /// Root folder of an inbox
+    ///
+    /// Defaults to a sibling of the default inbox's folder, never a folder
+    /// inside it, so the default transfer ID can't reach other inboxes' files.
+    pub fn inbox_folder(&self, inbox: &InboxConfig) -> String {
+        if inbox.folder.is_empty() {
+            format!("{}-{}", self.folder.trim_end_matches(['/', '\\']), inbox.name)
+        } else {
+            inbox.folder.clone()
+        }
+    }
+
+    /// Checks that every inbox has a name and a folder of its own
+    ///
+    /// An inbox folder inside another inbox's folder (or the other way round)
+    /// would let one inbox's transfer ID list, download and delete the other's files.
+    pub fn check_inboxes(&self) -> Result<()> {
+        let mut folders = vec![("default".to_string(), self.folder.clone())];
+        for inbox in &self.inboxes {
+            if inbox.name.is_empty() {
+                return Err(anyhow::anyhow!("Every inbox needs a name"));
+            }
+            folders.push((inbox.name.clone(), self.inbox_folder(inbox)));
+        }
+
+        for (i, (name, folder)) in folders.iter().enumerate() {
+            for (other_name, other_folder) in &folders[i + 1..] {
+                let (a, b) = (real_path(folder), real_path(other_folder));
+                if a.starts_with(&b) || b.starts_with(&a) {
+                    return Err(anyhow::anyhow!(
+                        "Inbox {} ({}) and inbox {} ({}) overlap, every inbox needs a folder of its own",
+                        name, folder, other_name, other_folder,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Socket addresses the transfer server listens on
+    pub fn listen_addresses(&self) -> Result<Vec<SocketAddr>> {
+        if self.listen.is_empty() {
+            return crate::ip::parse_listen_address(&self.bind, self.port).map(|addr| vec![addr]);
+        }
+        self.listen.iter()
+            .map(|address| crate::ip::parse_listen_address(address, self.port))
+            .collect()
+    }
+
+    /// The listen addresses with another port, for the WebSocket and HTTP upload servers
+    pub fn listen_addresses_at(&self, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(self.listen_addresses()?
+            .into_iter()
+            .map(|address| SocketAddr::new(address.ip(), port))
+            .collect())
     }
 
     /// Helper method to save config to a specific path
@@ -113,16 +759,60 @@ impl Config {
         let toml_content = toml::to_string(self)
             .context("Failed to serialize config to TOML")?;
             
-        let mut file = File::create(path)
-            .context("Failed to create transfer.toml")?;
-            
-        file.write_all(toml_content.as_bytes())
-            .context("Failed to write to transfer.toml")?;
-            
-        Ok(())
+        // Written in one step: a reader seeing an empty file would start over with a new ID and no settings
+        structure::write_atomic(path, toml_content.as_bytes())
+    }
+}
+
+/// Absolute path of `folder` with the part that exists resolved, so symlinks
+/// and ".." don't hide an overlap, even with folders that aren't created yet
+fn real_path(folder: &str) -> PathBuf {
+    let path = std::path::absolute(folder).unwrap_or_else(|_| PathBuf::from(folder));
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(mut real) = fs::canonicalize(existing) {
+            // What doesn't exist yet can't be a symlink, so ".." is resolved by name
+            for component in missing.iter().rev() {
+                match component {
+                    Component::ParentDir => { real.pop(); }
+                    component => real.push(component),
+                }
+            }
+            return real;
+        }
+        match (existing.parent(), existing.components().next_back()) {
+            (Some(parent), Some(component)) => {
+                missing.push(component);
+                existing = parent;
+            }
+            _ => return path,
+        }
     }
 }
 
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Whether transfer.toml lacks settings that `config` has, e.g. ones added in a newer version
+fn has_missing_fields(content: &str, config: &Config) -> Result<bool> {
+    fn missing(file: &toml::Value, full: &toml::Value) -> bool {
+        match (file, full) {
+            (toml::Value::Table(file), toml::Value::Table(full)) => full.iter()
+                .any(|(key, value)| file.get(key).is_none_or(|present| missing(present, value))),
+            (toml::Value::Array(file), toml::Value::Array(full)) => file.iter()
+                .zip(full)
+                .any(|(present, value)| missing(present, value)),
+            _ => false,
+        }
+    }
+
+    let file: toml::Value = toml::from_str(content).context("Failed to parse transfer.toml")?;
+    let full = toml::Value::try_from(config).context("Failed to serialize config to TOML")?;
+    Ok(missing(&file, &full))
+}
+
 /// Helper function to generate transfer ID in format xxxxxxx-xxxxxxx-xxxxxxx-xxxxxxx
 fn generate_transfer_id() -> String {
     let mut rng = rand::thread_rng();
@@ -140,4 +830,75 @@ fn generate_transfer_id() -> String {
         .collect();
     
     sections.join("-")
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_file_needs_no_migration() {
+        let config = Config::default();
+        let content = toml::to_string(&config).unwrap();
+        assert!(!has_missing_fields(&content, &config).unwrap());
+    }
+
+    #[test]
+    fn finds_missing_settings() {
+        let config = Config::default();
+        let mut file: toml::Table = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        file.remove("allow_delete");
+        assert!(has_missing_fields(&toml::to_string(&file).unwrap(), &config).unwrap());
+
+        let mut file: toml::Table = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        file["limits"].as_table_mut().unwrap().remove("max_connections");
+        assert!(has_missing_fields(&toml::to_string(&file).unwrap(), &config).unwrap());
+    }
+
+    #[test]
+    fn finds_missing_inbox_settings() {
+        let content = r#"
+            [[inboxes]]
+            name = "photos"
+            transfer_id = "aaaaaaa-bbbbbbb-ccccccc-ddddddd"
+        "#;
+        let config: Config = toml::from_str(content).unwrap();
+        assert!(has_missing_fields(content, &config).unwrap());
+
+        let complete = toml::to_string(&config).unwrap();
+        assert!(!has_missing_fields(&complete, &config).unwrap());
+    }
+
+    #[test]
+    fn narrowing_keeps_the_inbox_root() {
+        let mut config = Config { folder: "/srv/in".to_string(), ..Config::default() };
+        assert_eq!(config.inbox_root(), "/srv/in");
+
+        config.narrow("a", 0);
+        config.narrow("b", 0);
+        assert_eq!(Path::new(&config.folder), Path::new("/srv/in").join("a").join("b"));
+        assert_eq!(config.inbox_root(), "/srv/in");
+    }
+
+    #[test]
+    fn finds_overlapping_inboxes() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("in");
+        fs::create_dir_all(&base).unwrap();
+        let inbox = |name: &str, folder: &Path| InboxConfig {
+            name: name.to_string(),
+            folder: folder.to_string_lossy().to_string(),
+            ..toml::from_str("").unwrap()
+        };
+        let mut config = Config { folder: base.to_string_lossy().to_string(), ..Config::default() };
+
+        // Neither inbox folder exists yet
+        config.inboxes = vec![inbox("a", &base.join("a")), inbox("b", &dir.path().join("in-b"))];
+        assert!(config.check_inboxes().is_err());
+
+        config.folder = base.join("default").to_string_lossy().to_string();
+        assert!(config.check_inboxes().is_ok());
+
+        config.inboxes.push(inbox("c", &base.join("x").join("..").join("a").join("c")));
+        assert!(config.check_inboxes().is_err());
+    }
+}
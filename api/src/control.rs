@@ -0,0 +1,362 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path as UrlPath, Query, Request, State,
+    },
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use log::info;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    time::Instant,
+};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+use crate::activity::{self, ActiveInfo};
+use crate::api::{self, CAPABILITIES, PROTOCOL_VERSION};
+use crate::approval::{self, PendingTransfer};
+use crate::config::{Config, ControlConfig};
+use crate::firewall;
+use crate::history::{self, HistoryEntry};
+use crate::storage;
+
+/// Shown instead of the token when the config is read
+const TOKEN_MASK: &str = "********";
+
+/// Settings that are only read when the server starts
+const RESTART_SETTINGS: &[&str] = &[
+    "interface", "port", "listen", "name", "mdns", "beacon", "beacon_port",
+    "metrics", "metrics_address", "websocket", "websocket_port", "http_upload", "http_upload_port", "encryption", "approval", "control",
+    "allow", "deny", "limits", "inboxes",
+];
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// An error answered as `{"error": "..."}`
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+/// Serves the control API on the configured loopback address
+///
+/// Every request needs `Authorization: Bearer <token>` with the token from
+/// the [control] table of transfer.toml.
+pub async fn serve(control: &ControlConfig) -> Result<()> {
+    Lazy::force(&STARTED);
+
+    let address: SocketAddr = control.address.parse()
+        .with_context(|| format!("Invalid control API address: {}", control.address))?;
+    if !address.ip().is_loopback() {
+        return Err(anyhow::anyhow!("Control API address {} is not a loopback address", address));
+    }
+    if control.token.is_empty() {
+        return Err(anyhow::anyhow!("Control API token is empty"));
+    }
+
+    let listener = TcpListener::bind(address).await
+        .with_context(|| format!("Failed to bind control API to {}", address))?;
+
+    let app = Router::new()
+        .route("/api/status", get(status))
+        .route("/api/config", get(read_config).patch(update_config))
+        .route("/api/history", get(read_history))
+        .route("/api/transfers", get(active_transfers))
+        .route("/api/events", get(events))
+        .route("/api/quota", get(quota))
+        .route("/api/approvals", get(pending_approvals))
+        .route("/api/approvals/{id}", post(decide_approval))
+        .layer(middleware::from_fn_with_state(control.token.clone(), authorize));
+
+    info!("Control API listening on http://{}", address);
+
+    axum::serve(listener, app).await
+        .context("Control API stopped")
+}
+
+/// Rejects requests without the right bearer token
+async fn authorize(State(token): State<String>, request: Request, next: Next) -> Response {
+    let presented = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => next.run(request).await,
+        _ => ApiError(StatusCode::UNAUTHORIZED, "Missing or invalid token".to_string()).into_response(),
+    }
+}
+
+/// Compares without returning early, so the token can't be guessed by timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn load_config() -> std::result::Result<Config, ApiError> {
+    Ok(Config::current().context("Failed to load config")?)
+}
+
+#[derive(Serialize)]
+struct Status {
+    name: String,
+    version: &'static str,
+    protocol_version: u32,
+    capabilities: &'static [&'static str],
+    transfer_id: String,
+    listen: Vec<String>,
+    folder: String,
+    uptime_secs: u64,
+    active_transfers: usize,
+    pending_approvals: usize,
+    approval: bool,
+    allow_read: bool,
+    allow_delete: bool,
+}
+
+/// GET /api/status
+async fn status() -> ApiResult<Status> {
+    let config = load_config()?;
+    let listen = config.listen_addresses()?
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect();
+
+    Ok(Json(Status {
+        name: config.name,
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+        transfer_id: config.transfer_id,
+        listen,
+        folder: config.folder,
+        uptime_secs: STARTED.elapsed().as_secs(),
+        active_transfers: activity::active().len(),
+        pending_approvals: approval::pending().len(),
+        approval: config.approval.enabled,
+        allow_read: config.allow_read,
+        allow_delete: config.allow_delete,
+    }))
+}
+
+/// The config as JSON, without the control token
+fn config_json(config: &Config) -> std::result::Result<Value, ApiError> {
+    let mut value = serde_json::to_value(config).context("Failed to serialize config")?;
+    value["control"]["token"] = json!(TOKEN_MASK);
+    Ok(value)
+}
+
+/// GET /api/config
+async fn read_config() -> ApiResult<Value> {
+    Ok(Json(config_json(&load_config()?)?))
+}
+
+/// PATCH /api/config - merges the given fields into transfer.toml
+///
+/// Limits, folder and similar settings apply to the next transfer; the
+/// response lists the changed settings that need a restart.
+async fn update_config(Json(patch): Json<Value>) -> ApiResult<Value> {
+    if !patch.is_object() {
+        return Err(ApiError(StatusCode::BAD_REQUEST, "Expected a JSON object".to_string()));
+    }
+
+    let current = load_config()?;
+    let current_value = serde_json::to_value(&current).context("Failed to serialize config")?;
+    let mut merged = current_value.clone();
+    merge(&mut merged, patch);
+
+    let mut updated: Config = serde_json::from_value(merged)
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("Invalid config: {}", e)))?;
+    // The token can only be changed in the file, so the UI can't lock itself out
+    updated.control.token = current.control.token.clone();
+
+    // Checked like at startup, so a bad value is refused instead of being used by
+    // the running server or keeping it from starting again
+    updated.check_inboxes()
+        .and_then(|()| firewall::Firewall::new(&updated).map(drop))
+        .and_then(|()| updated.listen_addresses().map(drop))
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("Invalid config: {:#}", e)))?;
+    updated.save()?;
+
+    let updated_value = serde_json::to_value(&updated).context("Failed to serialize config")?;
+    let restart_required: Vec<&str> = RESTART_SETTINGS.iter()
+        .copied()
+        .filter(|key| current_value.get(key) != updated_value.get(key))
+        .collect();
+
+    info!("Config updated through the control API");
+
+    Ok(Json(json!({
+        "config": config_json(&updated)?,
+        "restart_required": restart_required,
+    })))
+}
+
+/// Recursively merges `patch` into `target`, replacing everything but objects
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+/// Query parameters of GET /api/history, see `transfer history`
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<String>,
+    until: Option<String>,
+    peer: Option<String>,
+    name: Option<String>,
+    result: Option<String>,
+    limit: Option<usize>,
+}
+
+/// GET /api/history
+async fn read_history(Query(query): Query<HistoryQuery>) -> ApiResult<Vec<HistoryEntry>> {
+    let bad_request = |e: anyhow::Error| ApiError(StatusCode::BAD_REQUEST, format!("{:#}", e));
+    let filter = history::Filter {
+        since: query.since.as_deref().map(history::parse_time).transpose().map_err(bad_request)?,
+        until: query.until.as_deref().map(history::parse_time).transpose().map_err(bad_request)?,
+        peer: query.peer,
+        name: query.name,
+        result: query.result,
+    };
+
+    let mut entries = history::read(&filter)?;
+    if let Some(limit) = query.limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+    Ok(Json(entries))
+}
+
+/// GET /api/transfers
+async fn active_transfers() -> ApiResult<Vec<ActiveInfo>> {
+    Ok(Json(activity::active()))
+}
+
+/// GET /api/events - progress of incoming transfers over a WebSocket
+///
+/// Every message is a transfer as listed by /api/transfers, sent when its
+/// state changes and a few times a second while data arrives. The transfers
+/// already in progress are sent when the socket opens.
+async fn events(upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(stream_events)
+}
+
+async fn stream_events(mut socket: WebSocket) {
+    // Subscribe first so nothing is missed between the snapshot and the events
+    let mut events = activity::subscribe();
+    for info in activity::active() {
+        if send_event(&mut socket, &info).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(info) => {
+                    if send_event(&mut socket, &info).await.is_err() {
+                        return;
+                    }
+                }
+                // A slow client skips events; the next one has the current totals
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+}
+
+async fn send_event(socket: &mut WebSocket, info: &ActiveInfo) -> Result<()> {
+    let text = serde_json::to_string(info).context("Failed to serialize event")?;
+    socket.send(Message::Text(text.into())).await
+        .context("Failed to send event")
+}
+
+#[derive(Serialize)]
+struct FolderUsage {
+    name: String,
+    used: u64,
+}
+
+#[derive(Serialize)]
+struct Quota {
+    folder: String,
+    used: u64,
+    max_folder_size: u64,
+    max_file_size: u64,
+    /// Usage of each subfolder senders can target
+    folders: Vec<FolderUsage>,
+}
+
+/// GET /api/quota
+async fn quota() -> ApiResult<Quota> {
+    let config = load_config()?;
+    let base = Path::new(&config.folder);
+
+    let mut folders = Vec::new();
+    if base.is_dir() {
+        let mut entries = tokio::fs::read_dir(base).await
+            .with_context(|| format!("Failed to read directory: {}", base.display()))?;
+        while let Some(entry) = entries.next_entry().await.context("Failed to read directory entry")? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if storage::is_internal_name(&name) || !entry.file_type().await.is_ok_and(|kind| kind.is_dir()) {
+                continue;
+            }
+            folders.push(FolderUsage { used: api::calculate_folder_size(&entry.path()).await?, name });
+        }
+    }
+    folders.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Json(Quota {
+        used: api::calculate_folder_size(base).await?,
+        folder: config.folder,
+        max_folder_size: config.max_folder_size,
+        max_file_size: config.max_file_size,
+        folders,
+    }))
+}
+
+/// GET /api/approvals
+async fn pending_approvals() -> ApiResult<Vec<PendingTransfer>> {
+    Ok(Json(approval::pending()))
+}
+
+#[derive(Deserialize)]
+struct ApprovalDecision {
+    accept: bool,
+}
+
+/// POST /api/approvals/{id} with `{"accept": true|false}`
+async fn decide_approval(UrlPath(id): UrlPath<u64>, Json(decision): Json<ApprovalDecision>) -> ApiResult<Value> {
+    approval::decide(id, decision.accept)
+        .map_err(|e| ApiError(StatusCode::NOT_FOUND, format!("{:#}", e)))?;
+    Ok(Json(json!({ "id": id, "accepted": decision.accept })))
+}
@@ -0,0 +1,610 @@
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Write},
+    path::Path,
+};
+use crate::config::EncryptionConfig;
+
+/// Magic bytes at the start of every encrypted file
+const MAGIC: &[u8; 4] = b"TENC";
+
+/// Current encrypted file format version
+const VERSION: u8 = 1;
+
+/// Plaintext bytes sealed per record
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Poly1305 authentication tag length
+const TAG_SIZE: usize = 16;
+
+/// Key derivation used for a file (stored in the header)
+const KDF_KEY_FILE: u8 = 0;
+const KDF_PASSPHRASE: u8 = 1;
+
+/// Header length: magic + version + kdf + salt + nonce prefix
+pub const HEADER_SIZE: usize = 4 + 1 + 1 + 16 + 7;
+
+/// Environment variable checked for the passphrase before prompting
+pub const PASSPHRASE_ENV: &str = "TRANSFER_PASSPHRASE";
+
+/// Key used by the server for files received during this run
+static ACTIVE_KEY: OnceCell<Option<ActiveKey>> = OnceCell::new();
+
+/// Encryption key together with the parameters needed to rederive it
+#[derive(Clone)]
+pub struct ActiveKey {
+    key: [u8; 32],
+    kdf: u8,
+    salt: [u8; 16],
+}
+
+/// Initializes the at-rest encryption key from the configuration
+/// Must be called once at startup, before the server accepts connections
+pub fn init(config: &EncryptionConfig) -> Result<()> {
+    let key = load_key(config)?;
+    let _ = ACTIVE_KEY.set(key);
+    Ok(())
+}
+
+/// Returns the key used to encrypt incoming files, if encryption is enabled
+pub fn active_key() -> Option<ActiveKey> {
+    ACTIVE_KEY.get().cloned().flatten()
+}
+
+/// Loads or derives the key described by the encryption configuration
+pub fn load_key(config: &EncryptionConfig) -> Result<Option<ActiveKey>> {
+    match config.mode.as_str() {
+        "off" | "" => Ok(None),
+        "keyfile" => {
+            let key = load_or_create_key_file(Path::new(&config.key_file))?;
+            Ok(Some(ActiveKey { key, kdf: KDF_KEY_FILE, salt: [0u8; 16] }))
+        }
+        "passphrase" => {
+            let salt = parse_salt(&config.salt)?;
+            let passphrase = read_passphrase()?;
+            let key = derive_key(&passphrase, &salt)?;
+            Ok(Some(ActiveKey { key, kdf: KDF_PASSPHRASE, salt }))
+        }
+        other => Err(anyhow::anyhow!("Unknown encryption mode: {}. Expected off, keyfile or passphrase", other)),
+    }
+}
+
+/// Generates a random salt for passphrase key derivation, hex encoded
+pub fn generate_salt() -> String {
+    let salt: [u8; 16] = rand::thread_rng().r#gen();
+    salt.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a hex encoded 16 byte salt
+fn parse_salt(salt: &str) -> Result<[u8; 16]> {
+    let bytes = parse_hex(salt.trim()).context("Invalid encryption salt in config")?;
+    bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Encryption salt must be 16 bytes (32 hex characters)"))
+}
+
+/// Decodes a hex string into bytes
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("Hex string has odd length"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).context("Invalid hex character"))
+        .collect()
+}
+
+/// Reads the passphrase from the environment or prompts for it on the terminal
+fn read_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) && !passphrase.is_empty() {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password("Encryption passphrase: ")
+        .context("Failed to read encryption passphrase")
+}
+
+/// Derives a 32 byte key from a passphrase and salt using Argon2id
+fn derive_key(passphrase: &str, salt: &[u8; 16]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive encryption key: {}", e))?;
+    Ok(key)
+}
+
+/// Loads a hex encoded key file, creating a new random key if it does not exist
+fn load_or_create_key_file(path: &Path) -> Result<[u8; 32]> {
+    if path.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!("Encryption mode keyfile requires encryption.key_file to be set"));
+    }
+
+    if !path.exists() {
+        let key: [u8; 32] = rand::thread_rng().r#gen();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create key file directory: {}", parent.display()))?;
+        }
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create key file: {}", path.display()))?;
+        let encoded: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+        file.write_all(encoded.as_bytes())
+            .with_context(|| format!("Failed to write key file: {}", path.display()))?;
+        restrict_permissions(path)?;
+        return Ok(key);
+    }
+
+    read_key_file(path)
+}
+
+/// Reads a hex encoded 32 byte key file
+fn read_key_file(path: &Path) -> Result<[u8; 32]> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read key file: {}", path.display()))?;
+    let bytes = parse_hex(content.trim()).context("Key file is not valid hex")?;
+    bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Key file must contain 32 bytes (64 hex characters)"))
+}
+
+/// Makes the key file readable by its owner only
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions on key file: {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Builds the nonce for a record: prefix || counter || last flag
+fn record_nonce(prefix: &[u8; 7], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(prefix);
+    nonce[7..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Streaming encryptor producing the on-disk encrypted format
+///
+/// The plaintext is split into records of `CHUNK_SIZE` bytes. Each record is
+/// sealed with ChaCha20-Poly1305 and written as a 4 byte length followed by
+/// the ciphertext. The final record carries a flag in its nonce so that a
+/// truncated file is detected on decryption.
+pub struct StreamEncryptor {
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; 7],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    /// Creates a new encryptor and returns it with the file header to write first
+    pub fn new(key: &ActiveKey) -> (Self, Vec<u8>) {
+        let nonce_prefix: [u8; 7] = rand::thread_rng().r#gen();
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.push(key.kdf);
+        header.extend_from_slice(&key.salt);
+        header.extend_from_slice(&nonce_prefix);
+
+        let encryptor = Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key.key)),
+            nonce_prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        };
+        (encryptor, header)
+    }
+
+    /// Buffers plaintext and returns any complete records ready to be written
+    pub fn update(&mut self, mut data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        while !data.is_empty() {
+            let take = (CHUNK_SIZE - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            // Full chunks are sealed right away; the final record is sealed
+            // by finalize(), even when it ends up empty
+            if self.buffer.len() == CHUNK_SIZE {
+                self.seal_buffer(false, &mut output)?;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Seals the remaining plaintext as the final record
+    pub fn finalize(mut self) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.seal_buffer(true, &mut output)?;
+        Ok(output)
+    }
+
+    fn seal_buffer(&mut self, last: bool, output: &mut Vec<u8>) -> Result<()> {
+        let nonce = record_nonce(&self.nonce_prefix, self.counter, last);
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), self.buffer.as_slice())
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt record"))?;
+        self.counter = self.counter.checked_add(1)
+            .context("Encrypted file exceeds maximum record count")?;
+        self.buffer.clear();
+
+        output.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        output.extend_from_slice(&ciphertext);
+        Ok(())
+    }
+}
+
+/// Returns true if the file starts with the encrypted file magic
+pub fn is_encrypted_file(path: &Path) -> Result<bool> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut magic = [0u8; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("Failed to read file header"),
+    }
+}
+
+/// Resolves decryption keys from file headers, asking for the passphrase at most once
+pub struct KeyResolver<'a> {
+    config: &'a EncryptionConfig,
+    passphrase: Option<String>,
+    derived: HashMap<[u8; 16], [u8; 32]>,
+    /// Whether the passphrase may be read from the terminal
+    interactive: bool,
+}
+
+impl<'a> KeyResolver<'a> {
+    pub fn new(config: &'a EncryptionConfig) -> Self {
+        Self { config, passphrase: None, derived: HashMap::new(), interactive: true }
+    }
+
+    /// Resolver for the running server: uses the key loaded at startup and never prompts
+    pub fn for_server(config: &'a EncryptionConfig) -> Self {
+        let mut derived = HashMap::new();
+        if let Some(key) = active_key().filter(|key| key.kdf == KDF_PASSPHRASE) {
+            derived.insert(key.salt, key.key);
+        }
+        Self { config, passphrase: None, derived, interactive: false }
+    }
+
+    /// Returns the key for the key derivation and salt found in a file header
+    fn key_for(&mut self, kdf: u8, salt: &[u8; 16]) -> Result<[u8; 32]> {
+        match kdf {
+            KDF_KEY_FILE => read_key_file(Path::new(&self.config.key_file)),
+            KDF_PASSPHRASE => {
+                if let Some(key) = self.derived.get(salt) {
+                    return Ok(*key);
+                }
+                if self.passphrase.is_none() {
+                    if !self.interactive {
+                        return Err(anyhow::anyhow!("File was encrypted with a different passphrase"));
+                    }
+                    self.passphrase = Some(read_passphrase()?);
+                }
+                let key = derive_key(self.passphrase.as_deref().unwrap_or_default(), salt)?;
+                self.derived.insert(*salt, key);
+                Ok(key)
+            }
+            other => Err(anyhow::anyhow!("Unknown key derivation in file header: {}", other)),
+        }
+    }
+}
+
+/// Streaming decryptor for the on-disk encrypted format
+///
+/// Records are fed one at a time with `decrypt_record`; the final record is
+/// recognized by the flag in its nonce.
+pub struct StreamDecryptor {
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: [u8; 7],
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamDecryptor {
+    /// Checks the file header and resolves the key it was encrypted with
+    pub fn new(keys: &mut KeyResolver, header: &[u8; HEADER_SIZE]) -> Result<Self> {
+        if &header[..4] != MAGIC {
+            return Err(anyhow::anyhow!("Not an encrypted transfer file"));
+        }
+        if header[4] != VERSION {
+            return Err(anyhow::anyhow!("Unsupported encrypted file version: {}", header[4]));
+        }
+
+        let kdf = header[5];
+        let salt: [u8; 16] = header[6..22].try_into().unwrap();
+        let nonce_prefix: [u8; 7] = header[22..29].try_into().unwrap();
+
+        let key = keys.key_for(kdf, &salt)?;
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            nonce_prefix,
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    /// Validates the 4 byte length prefix of the next record
+    pub fn record_len(len_bytes: [u8; 4]) -> Result<usize> {
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if !(TAG_SIZE..=CHUNK_SIZE + TAG_SIZE).contains(&len) {
+            return Err(anyhow::anyhow!("Encrypted file is corrupted (invalid record length)"));
+        }
+        Ok(len)
+    }
+
+    /// Authenticates and decrypts one record
+    pub fn decrypt_record(&mut self, record: &[u8]) -> Result<Vec<u8>> {
+        if self.finished {
+            return Err(anyhow::anyhow!("Encrypted file has trailing data after final record"));
+        }
+
+        // Try as a regular record first, then as the final record
+        let plaintext = match self.cipher.decrypt(Nonce::from_slice(&record_nonce(&self.nonce_prefix, self.counter, false)), record) {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                let plaintext = self.cipher
+                    .decrypt(Nonce::from_slice(&record_nonce(&self.nonce_prefix, self.counter, true)), record)
+                    .map_err(|_| anyhow::anyhow!("Decryption failed: wrong key or corrupted file"))?;
+                self.finished = true;
+                plaintext
+            }
+        };
+
+        self.counter = self.counter.checked_add(1)
+            .context("Encrypted file exceeds maximum record count")?;
+        Ok(plaintext)
+    }
+
+    /// True once the final record has been decrypted
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Computes the plaintext size of an encrypted file from its size on disk
+///
+/// Every record but the last holds exactly `CHUNK_SIZE` bytes, so the record
+/// count follows from the length alone.
+pub fn plaintext_size(encrypted_size: u64) -> Result<u64> {
+    let body = encrypted_size.checked_sub(HEADER_SIZE as u64)
+        .context("Encrypted file is truncated")?;
+    let overhead = (4 + TAG_SIZE) as u64;
+    let records = body / (CHUNK_SIZE as u64 + overhead) + 1;
+    body.checked_sub(records * overhead)
+        .context("Encrypted file is truncated")
+}
+
+/// Decrypts an encrypted file to the output path
+///
+/// The key is taken from the configuration: the key file in keyfile mode, or
+/// the passphrase combined with the salt stored in the file header.
+pub fn decrypt_file(keys: &mut KeyResolver, input: &Path, output: &Path) -> Result<()> {
+    let mut reader = File::open(input)
+        .with_context(|| format!("Failed to open encrypted file: {}", input.display()))?;
+
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header)
+        .context("File is too short to be an encrypted transfer file")?;
+    let mut decryptor = StreamDecryptor::new(keys, &header)
+        .with_context(|| format!("Cannot decrypt {}", input.display()))?;
+
+    // Write to a temporary file so a failed decryption never leaves partial output;
+    // its name is unique, so it never replaces a file or collides with another decryption
+    let name = output.file_name()
+        .with_context(|| format!("Not a file path: {}", output.display()))?
+        .to_string_lossy();
+    let temp_output = output.with_file_name(format!(".{}.{}.decrypting", name, uuid::Uuid::new_v4()));
+    let mut writer = File::options().write(true).create_new(true).open(&temp_output)
+        .with_context(|| format!("Failed to create output file: {}", temp_output.display()))?;
+
+    let result = decrypt_records(&mut decryptor, &mut reader, &mut writer);
+    drop(writer);
+
+    match result {
+        Ok(()) => {
+            fs::rename(&temp_output, output)
+                .with_context(|| format!("Failed to move decrypted file to {}", output.display()))
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp_output);
+            Err(e)
+        }
+    }
+}
+
+/// Reads and authenticates all records, writing the plaintext
+fn decrypt_records(decryptor: &mut StreamDecryptor, reader: &mut File, writer: &mut File) -> Result<()> {
+    while !decryptor.is_finished() {
+        let mut len_bytes = [0u8; 4];
+        reader.read_exact(&mut len_bytes)
+            .context("Encrypted file is truncated")?;
+        let len = StreamDecryptor::record_len(len_bytes)?;
+
+        let mut record = vec![0u8; len];
+        reader.read_exact(&mut record)
+            .context("Encrypted file is truncated")?;
+
+        let plaintext = decryptor.decrypt_record(&record)?;
+        writer.write_all(&plaintext)
+            .context("Failed to write decrypted data")?;
+    }
+
+    let mut trailing = [0u8; 1];
+    if reader.read(&mut trailing).context("Failed to read encrypted file")? != 0 {
+        return Err(anyhow::anyhow!("Encrypted file has trailing data after final record"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> ActiveKey {
+        ActiveKey { key: [byte; 32], kdf: KDF_PASSPHRASE, salt: [byte; 16] }
+    }
+
+    /// A resolver that knows `key` without asking for a passphrase
+    fn resolver<'a>(config: &'a EncryptionConfig, key: &ActiveKey) -> KeyResolver<'a> {
+        let mut resolver = KeyResolver::for_server(config);
+        resolver.derived.insert(key.salt, key.key);
+        resolver
+    }
+
+    /// Encrypts `data`, feeding it in pieces of `piece` bytes
+    fn encrypt(key: &ActiveKey, data: &[u8], piece: usize) -> Vec<u8> {
+        let (mut encryptor, mut output) = StreamEncryptor::new(key);
+        for chunk in data.chunks(piece.max(1)) {
+            output.extend(encryptor.update(chunk).unwrap());
+        }
+        output.extend(encryptor.finalize().unwrap());
+        output
+    }
+
+    fn decrypt(key: &ActiveKey, encrypted: &[u8]) -> Result<Vec<u8>> {
+        let config = EncryptionConfig::default();
+        let mut keys = resolver(&config, key);
+        let header: &[u8; HEADER_SIZE] = encrypted.get(..HEADER_SIZE)
+            .context("Encrypted file is truncated")?
+            .try_into()?;
+        let mut decryptor = StreamDecryptor::new(&mut keys, header)?;
+
+        let mut rest = &encrypted[HEADER_SIZE..];
+        let mut output = Vec::new();
+        while !decryptor.is_finished() {
+            let len_bytes = rest.get(..4).context("Encrypted file is truncated")?;
+            let len = StreamDecryptor::record_len(len_bytes.try_into()?)?;
+            let record = rest.get(4..4 + len).context("Encrypted file is truncated")?;
+            output.extend(decryptor.decrypt_record(record)?);
+            rest = &rest[4 + len..];
+        }
+        if !rest.is_empty() {
+            return Err(anyhow::anyhow!("Encrypted file has trailing data after final record"));
+        }
+        Ok(output)
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = key(1);
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            let data = sample(len);
+            let encrypted = encrypt(&key, &data, 10_000);
+            assert_eq!(decrypt(&key, &encrypted).unwrap(), data, "length {}", len);
+            assert_eq!(plaintext_size(encrypted.len() as u64).unwrap(), len as u64);
+        }
+    }
+
+    #[test]
+    fn same_data_encrypts_differently() {
+        let key = key(1);
+        assert_ne!(encrypt(&key, b"hello", 5), encrypt(&key, b"hello", 5));
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let encrypted = encrypt(&key(1), b"secret", 6);
+        let mut wrong = key(2);
+        wrong.salt = key(1).salt;
+        assert!(decrypt(&wrong, &encrypted).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let key = key(1);
+        let encrypted = encrypt(&key, &sample(2 * CHUNK_SIZE + 5), CHUNK_SIZE);
+
+        let mut flipped = encrypted.clone();
+        flipped[HEADER_SIZE + 10] ^= 1;
+        assert!(decrypt(&key, &flipped).is_err());
+
+        let mut trailing = encrypted.clone();
+        trailing.push(0);
+        assert!(decrypt(&key, &trailing).is_err());
+
+        let mut bad_magic = encrypted.clone();
+        bad_magic[0] = b'X';
+        assert!(decrypt(&key, &bad_magic).is_err());
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let key = key(1);
+        let encrypted = encrypt(&key, &sample(2 * CHUNK_SIZE + 5), CHUNK_SIZE);
+
+        // Cut inside a record, and cleanly after the first record (the final one is missing)
+        let first_record_end = HEADER_SIZE + 4 + CHUNK_SIZE + TAG_SIZE;
+        for len in [0, HEADER_SIZE - 1, HEADER_SIZE, HEADER_SIZE + 100, first_record_end, encrypted.len() - 1] {
+            assert!(decrypt(&key, &encrypted[..len]).is_err(), "truncated to {} was accepted", len);
+        }
+        assert!(plaintext_size(HEADER_SIZE as u64 - 1).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_records() {
+        let key = key(1);
+        let encrypted = encrypt(&key, &sample(3 * CHUNK_SIZE), CHUNK_SIZE);
+        let record = 4 + CHUNK_SIZE + TAG_SIZE;
+        let mut swapped = encrypted[..HEADER_SIZE].to_vec();
+        swapped.extend_from_slice(&encrypted[HEADER_SIZE + record..HEADER_SIZE + 2 * record]);
+        swapped.extend_from_slice(&encrypted[HEADER_SIZE..HEADER_SIZE + record]);
+        swapped.extend_from_slice(&encrypted[HEADER_SIZE + 2 * record..]);
+        assert!(decrypt(&key, &swapped).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_record_lengths() {
+        assert!(StreamDecryptor::record_len(0u32.to_be_bytes()).is_err());
+        assert!(StreamDecryptor::record_len(((CHUNK_SIZE + TAG_SIZE + 1) as u32).to_be_bytes()).is_err());
+        assert!(StreamDecryptor::record_len((TAG_SIZE as u32).to_be_bytes()).is_ok());
+    }
+
+    #[test]
+    fn decrypts_files() {
+        let key = key(3);
+        let data = sample(CHUNK_SIZE + 100);
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let (input, output) = (dir.join("data.bin"), dir.join("plain.bin"));
+
+        let config = EncryptionConfig::default();
+        let mut encrypted = encrypt(&key, &data, 4096);
+        fs::write(&input, &encrypted).unwrap();
+        // A file named like a temporary output is left alone
+        fs::write(dir.join("plain.decrypting"), "keep").unwrap();
+        decrypt_file(&mut resolver(&config, &key), &input, &output).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert_eq!(fs::read_to_string(dir.join("plain.decrypting")).unwrap(), "keep");
+        fs::remove_file(dir.join("plain.decrypting")).unwrap();
+        assert!(is_encrypted_file(&input).unwrap());
+        assert!(!is_encrypted_file(&output).unwrap());
+
+        // A failed decryption leaves no output behind
+        encrypted.truncate(encrypted.len() - 1);
+        fs::write(&input, &encrypted).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert!(decrypt_file(&mut resolver(&config, &key), &input, &output).is_err());
+        assert!(fs::read_dir(dir).unwrap().count() == 1);
+    }
+}
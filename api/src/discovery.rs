@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::Duration,
+};
+use crate::api::{CAPABILITIES, PROTOCOL_VERSION};
+use crate::config::Config;
+
+/// DNS-SD service type advertised by transfer servers
+pub const SERVICE_TYPE: &str = "_transfer._tcp.local.";
+
+/// A receiver found on the local network
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    /// Device name chosen by the receiver
+    pub name: String,
+    /// Host name the receiver advertised
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// Protocol version of the receiver, if it announced one
+    pub version: Option<String>,
+    /// Optional protocol features the receiver supports
+    pub capabilities: Vec<String>,
+}
+
+/// Advertises this server over mDNS as long as the returned daemon is alive
+pub fn advertise(config: &Config) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()
+        .context("Failed to start mDNS daemon")?;
+
+    let host_name = format!("{}.local.", local_host_label());
+    let properties = HashMap::from([
+        ("name".to_string(), config.name.clone()),
+        ("version".to_string(), PROTOCOL_VERSION.to_string()),
+        ("caps".to_string(), CAPABILITIES.join(",")),
+    ]);
+
+    // Advertise the bind address, or every address of the host for a wildcard bind
+    let bind: IpAddr = config.bind.parse()
+        .with_context(|| format!("Invalid bind address: {}", config.bind))?;
+    let service = if bind.is_unspecified() {
+        ServiceInfo::new(SERVICE_TYPE, &config.name, &host_name, (), config.port, properties)
+            .map(ServiceInfo::enable_addr_auto)
+    } else {
+        ServiceInfo::new(SERVICE_TYPE, &config.name, &host_name, bind, config.port, properties)
+    }
+    .context("Failed to build mDNS service record")?;
+
+    daemon.register(service)
+        .context("Failed to register mDNS service")?;
+
+    info!("Advertising {} as {} over mDNS", config.name, SERVICE_TYPE);
+
+    Ok(daemon)
+}
+
+/// Browses the local network for receivers, collecting answers until `timeout` elapses
+pub async fn browse(timeout: Duration) -> Result<Vec<Peer>> {
+    let daemon = ServiceDaemon::new()
+        .context("Failed to start mDNS daemon")?;
+    let receiver = daemon.browse(SERVICE_TYPE)
+        .context("Failed to browse for receivers")?;
+
+    // Keyed by full service name so repeated answers don't show up twice
+    let mut peers: HashMap<String, Peer> = HashMap::new();
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+
+        let instance = service.fullname.strip_suffix(SERVICE_TYPE)
+            .map(|name| name.trim_end_matches('.'))
+            .unwrap_or(&service.fullname);
+        let mut addresses: Vec<IpAddr> = service.addresses.iter()
+            .map(|address| address.to_ip_addr())
+            .collect();
+        addresses.sort();
+
+        peers.insert(service.fullname.clone(), Peer {
+            name: service.get_property_val_str("name").unwrap_or(instance).to_string(),
+            host: service.host.clone(),
+            addresses,
+            port: service.port,
+            version: service.get_property_val_str("version").map(str::to_string),
+            capabilities: service.get_property_val_str("caps")
+                .map(|caps| caps.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+        });
+    }
+
+    if let Err(e) = daemon.shutdown() {
+        warn!("Failed to shut down mDNS daemon: {}", e);
+    }
+
+    let mut peers: Vec<Peer> = peers.into_values().collect();
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peers)
+}
+
+/// Combines peers found by different discovery methods
+/// Entries with the same name and port are merged into one with all their addresses
+pub fn merge_peers(lists: impl IntoIterator<Item = Vec<Peer>>) -> Vec<Peer> {
+    let mut merged: Vec<Peer> = Vec::new();
+    for peer in lists.into_iter().flatten() {
+        match merged.iter_mut().find(|p| p.name == peer.name && p.port == peer.port) {
+            Some(existing) => {
+                for address in peer.addresses {
+                    if !existing.addresses.contains(&address) {
+                        existing.addresses.push(address);
+                    }
+                }
+            }
+            None => merged.push(peer),
+        }
+    }
+    merged.sort_by(|a, b| a.name.cmp(&b.name));
+    merged
+}
+
+/// Single DNS label for this host, used as the advertised `<label>.local.` host name
+fn local_host_label() -> String {
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let label: String = hostname.split('.').next().unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+        .collect();
+
+    if label.is_empty() { "transfer".to_string() } else { label }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use crate::access;
+use crate::api::{self, TransferOptions};
+use crate::bans;
+use crate::compression::{self, FrameEncoder};
+use crate::config::Config;
+use crate::crypto::{self, KeyResolver};
+use crate::shares;
+use crate::storage::{self, OutgoingFile};
+use crate::structure;
+
+/// Handle LIST command - lists a folder below the transfer folder
+///
+/// Every entry is sent as `ENTRY <file|dir> <size> <mtime> <name>`, followed by
+/// a `LIST_COMPLETE` summary. Sizes of encrypted files are their original size.
+pub async fn handle_list_command<S: AsyncRead + AsyncWrite + Unpin>(transfer_id: &str, folder: Option<&str>, peer: SocketAddr, stream: &mut S) -> Result<String> {
+    info!("Handling LIST command - transfer_id: {}, folder: {:?}", transfer_id, folder);
+
+    let mut config = Config::current()
+        .context("Failed to load config")?;
+    access::authorize(&mut config, transfer_id, access::PERMISSION_LIST, peer.ip())?;
+
+    let dir = resolve_path(&config, folder.unwrap_or(""))?;
+    if !dir.is_dir() {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder.unwrap_or("")));
+    }
+
+    let mut entries: Vec<_> = std::fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut count = 0;
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Names are sent on one line, so ones with line breaks can't be listed
+        if storage::is_internal_name(&name) || name.contains(['\n', '\r']) {
+            continue;
+        }
+
+        // Symlinks are not listed, they could point outside the transfer folder
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_symlink() {
+            continue;
+        }
+        let kind = if metadata.is_dir() { "dir" } else { "file" };
+        let size = if metadata.is_dir() {
+            0
+        } else if crypto::is_encrypted_file(&entry.path()).unwrap_or(false) {
+            crypto::plaintext_size(metadata.len()).unwrap_or(0)
+        } else {
+            metadata.len()
+        };
+        let mtime = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        stream.write_all(format!("ENTRY {} {} {} {}\n", kind, size, mtime, name).as_bytes()).await
+            .context("Failed to send list entry")?;
+        count += 1;
+    }
+
+    Ok(format!("LIST_COMPLETE: {} entries", count))
+}
+
+/// Handle GET command - sends a stored file to the client
+///
+/// The server answers with the ACK line (announcing compression if agreed),
+/// the file size (8 bytes, big-endian) and the contents, decrypted if the
+/// file is stored encrypted, then a `GET_COMPLETE` line.
+///
+/// With a share token instead of the transfer ID the shared file is sent,
+/// even if reads are disabled; the path may then be left out.
+pub async fn handle_get_command<S: AsyncRead + AsyncWrite + Unpin>(transfer_id: &str, path: &str, options: &TransferOptions, peer: SocketAddr, stream: &mut S) -> Result<String> {
+    info!("Handling GET command - transfer_id: {}, path: {}", transfer_id, path);
+
+    let mut config = Config::current()
+        .context("Failed to load config")?;
+
+    // Share tokens are checked first, so addresses that failed too often may not try them either
+    bans::check(&config.limits, peer.ip())?;
+    let share = shares::find(transfer_id)?;
+    let file_path = match &share {
+        Some(share) => share.resolve(&config, path)?,
+        None => {
+            access::authorize(&mut config, transfer_id, access::PERMISSION_DOWNLOAD, peer.ip())?;
+            let file_path = resolve_path(&config, path)?;
+            if !file_path.is_file() {
+                return Err(anyhow::anyhow!("File not found: {}", path));
+            }
+            file_path
+        }
+    };
+
+    let mut keys = KeyResolver::for_server(&config.encryption);
+    let mut file = OutgoingFile::open(&file_path, &mut keys).await?;
+    let size = file.size();
+
+    // The first chunk doubles as the sample for the compression decision
+    let first_chunk = file.read_chunk().await?;
+    let compressed = api::negotiate_compression(&config, options)
+        && compression::should_compress(&file_path, first_chunk.as_deref().unwrap_or_default());
+
+    if let Some(share) = &share {
+        share.redeem()?;
+    }
+    stream.write_all(api::ack_line(compressed).as_bytes()).await
+        .context("Failed to send ACK")?;
+    stream.write_all(&size.to_be_bytes()).await
+        .context("Failed to send file size")?;
+
+    info!("Sending {} ({} bytes, compressed: {})", path, size, compressed);
+
+    let mut encoder = if compressed { Some(FrameEncoder::new()?) } else { None };
+    let mut sent = 0u64;
+    let mut chunk = first_chunk;
+    while let Some(data) = chunk {
+        sent += data.len() as u64;
+        if sent > size {
+            break;
+        }
+        match encoder.as_mut() {
+            Some(encoder) => {
+                if let Some(frame) = encoder.update(&data)? {
+                    stream.write_all(&frame).await
+                        .context("Failed to send compressed frame")?;
+                }
+            }
+            None => stream.write_all(&data).await
+                .context("Failed to send file data")?,
+        }
+        chunk = file.read_chunk().await?;
+    }
+
+    // The size was already announced, so a file that changed can't be recovered from
+    if sent != size {
+        warn!("{} changed size while sending", file_path.display());
+        return Err(anyhow::anyhow!("{} changed size while sending", path));
+    }
+    if let Some(encoder) = encoder {
+        stream.write_all(&encoder.finish()?).await
+            .context("Failed to send compressed frame")?;
+    }
+
+    Ok(format!("GET_COMPLETE: {}", path))
+}
+
+/// Handle DELETE command - removes a stored file or directory
+///
+/// Needs the delete permission; the folder itself can't be deleted.
+pub async fn handle_delete_command(transfer_id: &str, path: &str, peer: SocketAddr) -> Result<String> {
+    info!("Handling DELETE command - transfer_id: {}, path: {}", transfer_id, path);
+
+    let mut config = Config::current()
+        .context("Failed to load config")?;
+    access::authorize(&mut config, transfer_id, access::PERMISSION_DELETE, peer.ip())?;
+
+    if path.trim().is_empty() {
+        return Err(anyhow::anyhow!("DELETE needs a path"));
+    }
+
+    // A symlink is removed itself, never the file it points to; its directory
+    // is resolved like any other path, so it has to be inside the folder
+    let relative = structure::sanitize_relative_path(path.trim())?;
+    let parent = resolve_path(&config, &relative.parent().unwrap_or(Path::new("")).to_string_lossy())?;
+    let link = parent.join(relative.file_name().context("DELETE needs a path")?);
+    if tokio::fs::symlink_metadata(&link).await.is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        tokio::fs::remove_file(&link).await
+            .with_context(|| format!("Failed to delete {}", path))?;
+        info!("Deleted symlink {}", link.display());
+        return Ok(format!("DELETE_COMPLETE: {}", path));
+    }
+
+    let target = resolve_path(&config, path)?;
+    if target.is_dir() {
+        tokio::fs::remove_dir_all(&target).await
+            .with_context(|| format!("Failed to delete {}", path))?;
+    } else {
+        tokio::fs::remove_file(&target).await
+            .with_context(|| format!("Failed to delete {}", path))?;
+    }
+
+    info!("Deleted {}", target.display());
+    Ok(format!("DELETE_COMPLETE: {}", path))
+}
+
+/// Resolves a client-supplied relative path below the transfer folder
+/// Internal files are hidden and symlinks may not lead outside the folder
+///
+/// Names are checked both as requested and as stored, so neither a symlink
+/// nor a different spelling on a case-insensitive file system reaches them.
+pub fn resolve_path(config: &Config, relative: &str) -> Result<PathBuf> {
+    let base = Path::new(&config.folder);
+    let relative = relative.trim();
+    let path = if relative.is_empty() {
+        base.to_path_buf()
+    } else {
+        let relative = structure::sanitize_relative_path(relative)?;
+        if has_internal_name(&relative) {
+            return Err(anyhow::anyhow!("File not found: {}", relative.display()));
+        }
+        base.join(relative)
+    };
+
+    let canonical_base = base.canonicalize()
+        .with_context(|| format!("Transfer folder not found: {}", base.display()))?;
+    match path.canonicalize() {
+        Ok(canonical) if canonical.strip_prefix(&canonical_base).is_ok_and(|inside| !has_internal_name(inside)) => Ok(canonical),
+        _ => Err(anyhow::anyhow!("File not found: {}", path.strip_prefix(base).unwrap_or(&path).display())),
+    }
+}
+
+fn has_internal_name(path: &Path) -> bool {
+    path.components().any(|c| storage::is_internal_name(&c.as_os_str().to_string_lossy()))
+}
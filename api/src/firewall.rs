@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::{error, warn};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use crate::bans;
+use crate::config::Config;
+
+/// A network in CIDR notation, e.g. "192.168.1.0/24" or "fd00::/8"
+///
+/// A plain address is a network of one host.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let network: IpAddr = address.parse()
+            .with_context(|| format!("Invalid network address: {}", value))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("Invalid prefix length: {}", value))?,
+            None => max,
+        };
+
+        // IPv4-mapped networks like ::ffff:10.0.0.0/104 are matched as IPv4
+        match network.to_canonical() {
+            IpAddr::V4(v4) if network.is_ipv6() && prefix >= 96 => Ok(Self { network: IpAddr::V4(v4), prefix: prefix - 96 }),
+            IpAddr::V4(_) if network.is_ipv6() => Ok(Self { network, prefix }),
+            canonical => Ok(Self { network: canonical, prefix }),
+        }
+    }
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network; IPv4-mapped IPv6 addresses count as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a list of networks from transfer.toml
+pub fn parse_networks(networks: &[String]) -> Result<Vec<Cidr>> {
+    networks.iter().map(|network| network.parse()).collect()
+}
+
+/// Whether `ip` may use something restricted to `networks`; an empty list allows everyone
+pub fn in_networks(networks: &[String], ip: IpAddr) -> Result<bool> {
+    Ok(networks.is_empty() || parse_networks(networks)?.iter().any(|network| network.contains(ip)))
+}
+
+/// Decides which peers may connect, from the top-level allow and deny lists
+///
+/// Deny wins over allow; with an empty allow list everyone who isn't denied may connect.
+/// Banned addresses are refused as well.
+#[derive(Debug, Clone)]
+pub struct Firewall {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Firewall {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            allow: parse_networks(&config.allow).context("Invalid allow list")?,
+            deny: parse_networks(&config.deny).context("Invalid deny list")?,
+        })
+    }
+
+    /// Checks a new connection, logging it if refused
+    pub fn permits(&self, peer: SocketAddr) -> bool {
+        let ip = peer.ip();
+        if self.deny.iter().any(|network| network.contains(ip)) {
+            warn!("Refused connection from {}: denied", peer);
+            return false;
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(ip)) {
+            warn!("Refused connection from {}: not in the allow list", peer);
+            return false;
+        }
+        match bans::active_ban(ip) {
+            Ok(Some(ban)) => {
+                warn!("Refused connection from {}: banned until {}", peer, ban.until);
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                error!("Failed to check bans for {}: {:#}", peer, e);
+                true
+            }
+        }
+    }
+}
+
+/// Rejects HTTP and WebSocket requests from peers the firewall refuses
+pub async fn filter(State(firewall): State<Arc<Firewall>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    if !firewall.permits(peer) {
+        return (StatusCode::FORBIDDEN, "Connections from your address are not allowed\n").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_hosts() {
+        let network: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(network.contains(ip("192.168.1.77")));
+        assert!(!network.contains(ip("192.168.2.1")));
+
+        let host: Cidr = " 10.0.0.5 ".parse().unwrap();
+        assert!(host.contains(ip("10.0.0.5")));
+        assert!(!host.contains(ip("10.0.0.6")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn prefix_edges() {
+        let everyone: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everyone.contains(ip("203.0.113.9")));
+        assert!(!everyone.contains(ip("2001:db8::1")));
+
+        let every_v6: Cidr = "::/0".parse().unwrap();
+        assert!(every_v6.contains(ip("2001:db8::1")));
+
+        let odd: Cidr = "10.0.0.0/9".parse().unwrap();
+        assert!(odd.contains(ip("10.127.255.255")));
+        assert!(!odd.contains(ip("10.128.0.0")));
+    }
+
+    #[test]
+    fn mapped_ipv4_counts_as_ipv4() {
+        let network: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(network.contains(ip("::ffff:192.168.1.10")));
+
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert!(mapped.contains(ip("10.1.2.3")));
+        assert!(mapped.contains(ip("::ffff:10.1.2.3")));
+        assert!(!mapped.contains(ip("11.0.0.1")));
+
+        let mapped_host: Cidr = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(mapped_host.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for value in ["", "/24", "192.168.1.0/", "192.168.1.0/33", "fd00::/129", "192.168.1.0/-1", "192.168.1.0/x", "192.168.1", "example.com/24", "10.0.0.0/8/8"] {
+            assert!(value.parse::<Cidr>().is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn empty_network_list_allows_everyone() {
+        assert!(in_networks(&[], ip("198.51.100.1")).unwrap());
+
+        let networks = vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string()];
+        assert!(in_networks(&networks, ip("10.9.8.7")).unwrap());
+        assert!(in_networks(&networks, ip("fd00::1")).unwrap());
+        assert!(!in_networks(&networks, ip("192.168.0.1")).unwrap());
+
+        assert!(in_networks(&["not a network".to_string()], ip("10.0.0.1")).is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let firewall = Firewall {
+            allow: parse_networks(&["10.0.0.0/8".to_string()]).unwrap(),
+            deny: parse_networks(&["10.0.0.13".to_string()]).unwrap(),
+        };
+        assert!(firewall.permits("10.0.0.12:5000".parse().unwrap()));
+        assert!(!firewall.permits("10.0.0.13:5000".parse().unwrap()));
+        assert!(!firewall.permits("192.168.0.1:5000".parse().unwrap()));
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, SecondsFormat, Utc};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::Instant,
+};
+use crate::config::Config;
+use crate::metrics;
+use crate::structure;
+
+/// Result codes stored in the history
+pub const RESULT_OK: &str = "ok";
+pub const RESULT_FAILED: &str = "failed";
+pub const RESULT_REJECTED: &str = "rejected";
+pub const RESULT_SIZE_LIMIT: &str = "size_limit";
+pub const RESULT_ABORTED: &str = "aborted";
+pub const RESULT_DISCARDED: &str = "discarded";
+
+/// Serializes appends from concurrent connections
+static WRITE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// One received (or refused) file, stored as a line of history.jsonl
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the transfer started (RFC 3339, UTC)
+    pub timestamp: String,
+    pub peer: String,
+    pub transfer_id: String,
+    /// "file", "tree", "batch" or "http"
+    pub kind: String,
+    /// Name the sender used (path inside the directory for trees)
+    pub filename: String,
+    /// Name the file was saved as
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_name: Option<String>,
+    pub size: u64,
+    pub duration_ms: u64,
+    /// "ok" or one of the error codes above
+    pub result: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// SHA-256 of the received contents (hex)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// One TRANSFER, TREE or SESSION command being recorded
+pub struct Attempt {
+    enabled: bool,
+    timestamp: DateTime<Utc>,
+    started: Instant,
+    peer: SocketAddr,
+    transfer_id: String,
+    kind: &'static str,
+}
+
+impl Attempt {
+    pub fn start(config: &Config, peer: SocketAddr, transfer_id: &str, kind: &'static str) -> Self {
+        Self {
+            enabled: config.history,
+            timestamp: Utc::now(),
+            started: Instant::now(),
+            peer,
+            transfer_id: transfer_id.to_string(),
+            kind,
+        }
+    }
+
+    /// Restarts the duration measurement (batches time each file separately)
+    pub fn restart_timer(&mut self) {
+        self.started = Instant::now();
+    }
+
+    /// Entry for a file that was received
+    pub fn succeeded(&self, filename: &str, stored_name: &str, size: u64, checksum: String) -> HistoryEntry {
+        let mut entry = self.entry(filename, size, RESULT_OK);
+        entry.stored_name = Some(stored_name.to_string());
+        entry.checksum = Some(checksum);
+        entry
+    }
+
+    /// Entry for a file that was not received
+    pub fn failed(&self, filename: &str, size: u64, code: &str, error: &anyhow::Error) -> HistoryEntry {
+        let mut entry = self.entry(filename, size, code);
+        entry.error = Some(format!("{:#}", error));
+        entry
+    }
+
+    /// Counts the entry in the metrics and appends it to the history file
+    /// (a failure is only logged)
+    pub fn record(&self, entry: HistoryEntry) {
+        metrics::transfer_finished(&entry);
+        if !self.enabled {
+            return;
+        }
+        if let Err(e) = append(&entry) {
+            warn!("Failed to record transfer history: {:#}", e);
+        }
+    }
+
+    fn entry(&self, filename: &str, size: u64, result: &str) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: self.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            peer: self.peer.ip().to_canonical().to_string(),
+            transfer_id: self.transfer_id.clone(),
+            kind: self.kind.to_string(),
+            filename: filename.to_string(),
+            stored_name: None,
+            size,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            result: result.to_string(),
+            error: None,
+            checksum: None,
+        }
+    }
+}
+
+/// The history is kept next to transfer.toml
+pub fn history_path() -> Result<PathBuf> {
+    Ok(structure::get_config_directory()?.join("history.jsonl"))
+}
+
+fn append(entry: &HistoryEntry) -> Result<()> {
+    let mut line = serde_json::to_string(entry)
+        .context("Failed to serialize history entry")?;
+    line.push('\n');
+
+    let path = history_path()?;
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open history file: {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed to write history file: {}", path.display()))
+}
+
+/// Criteria for `transfer history`; unset fields match everything
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Sender address
+    pub peer: Option<String>,
+    /// Case-insensitive substring of the sent or stored name
+    pub name: Option<String>,
+    /// Result code; "error" matches every result except "ok"
+    pub result: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let timestamp = DateTime::parse_from_rfc3339(&entry.timestamp).ok()
+            .map(|t| t.with_timezone(&Utc));
+        if self.since.is_some_and(|since| timestamp.is_none_or(|t| t < since)) {
+            return false;
+        }
+        if self.until.is_some_and(|until| timestamp.is_none_or(|t| t >= until)) {
+            return false;
+        }
+        if self.peer.as_ref().is_some_and(|peer| *peer != entry.peer) {
+            return false;
+        }
+        if let Some(name) = &self.name {
+            let name = name.to_lowercase();
+            let stored = entry.stored_name.as_deref().unwrap_or_default().to_lowercase();
+            if !entry.filename.to_lowercase().contains(&name) && !stored.contains(&name) {
+                return false;
+            }
+        }
+        match self.result.as_deref() {
+            None => true,
+            Some("error") => entry.result != RESULT_OK,
+            Some(result) => entry.result == result,
+        }
+    }
+}
+
+/// Reads the recorded entries matching `filter`, oldest first
+pub fn read(filter: &Filter) -> Result<Vec<HistoryEntry>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read history file: {}", path.display()))?;
+
+    Ok(contents.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str::<HistoryEntry>(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("Skipping invalid history line: {}", e);
+                None
+            }
+        })
+        .filter(|entry| filter.matches(entry))
+        .collect())
+}
+
+/// Parses a point in time for the history filters
+///
+/// Accepts `today`, `yesterday`, a local date (`2024-05-01`), an RFC 3339
+/// timestamp or an age such as `30m`, `12h` or `7d`.
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    let local_midnight = |date: NaiveDate| {
+        date.and_hms_opt(0, 0, 0)
+            .and_then(|time| time.and_local_timezone(Local).earliest())
+            .map(|time| time.with_timezone(&Utc))
+            .with_context(|| format!("Invalid date: {}", value))
+    };
+
+    let today = Local::now().date_naive();
+    match value {
+        "today" => return local_midnight(today),
+        "yesterday" => return local_midnight(today.pred_opt().context("Invalid date")?),
+        _ => {}
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return local_midnight(date);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let age = parse_age(value)
+        .with_context(|| format!("Invalid time: {} (use today, yesterday, YYYY-MM-DD or an age like 12h)", value))?;
+    Utc::now().checked_sub_signed(age)
+        .with_context(|| format!("Invalid time: {}", value))
+}
+
+/// Parses a duration like 30m, 12h or 7d
+pub fn parse_age(value: &str) -> Result<chrono::Duration> {
+    let unit = value.chars().last()
+        .with_context(|| format!("Invalid duration: {}", value))?;
+    let amount: i64 = value[..value.len() - unit.len_utf8()].parse().ok()
+        .filter(|amount| *amount >= 0)
+        .with_context(|| format!("Invalid duration: {}", value))?;
+    let duration = match unit {
+        'm' => chrono::Duration::try_minutes(amount),
+        'h' => chrono::Duration::try_hours(amount),
+        'd' => chrono::Duration::try_days(amount),
+        _ => return Err(anyhow::anyhow!("Invalid time unit in {} (use m, h or d)", value)),
+    };
+    duration.with_context(|| format!("Duration too long: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("30m").unwrap(), chrono::Duration::minutes(30));
+        assert_eq!(parse_age("12h").unwrap(), chrono::Duration::hours(12));
+        assert_eq!(parse_age("7d").unwrap(), chrono::Duration::days(7));
+        assert_eq!(parse_age("0d").unwrap(), chrono::Duration::zero());
+    }
+
+    #[test]
+    fn rejects_invalid_ages() {
+        for value in ["", "m", "5", "5s", "-5d", "+-5d", "1.5h", "5 d", "d5"] {
+            assert!(parse_age(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn rejects_multibyte_units() {
+        assert!(parse_age("5µ").is_err());
+        assert!(parse_age("µ").is_err());
+        assert!(parse_age("5日").is_err());
+    }
+
+    #[test]
+    fn rejects_ages_that_overflow() {
+        assert!(parse_age("9223372036854775807d").is_err());
+        assert!(parse_age("99999999999999999999m").is_err());
+        assert!(parse_time("200000000d").is_err());
+    }
+
+    #[test]
+    fn parses_times() {
+        assert!(parse_time("today").unwrap() <= Utc::now());
+        assert!(parse_time("yesterday").unwrap() < parse_time("today").unwrap());
+        assert_eq!(parse_time("2024-05-01T12:00:00Z").unwrap().to_rfc3339(), "2024-05-01T12:00:00+00:00");
+        assert!(parse_time("2024-02-30").is_err());
+        assert!(parse_time("soon").is_err());
+    }
+}
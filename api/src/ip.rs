@@ -1,5 +1,5 @@
 use anyhow::{Context, Result};
-use std::net::UdpSocket;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
 /// Determines the public-facing IP address using UDP ping method with 1.1.1.1
 /// This method connects to Cloudflare's DNS (1.1.1.1) to determine which
@@ -41,15 +41,215 @@ pub fn get_public_ip_fallback() -> Result<String> {
     Ok(ip)
 }
 
+/// Determines the public-facing IPv6 address the same way, for IPv6-only networks
+pub fn get_public_ipv6() -> Result<String> {
+    let socket = UdpSocket::bind("[::]:0")
+        .context("Failed to bind IPv6 UDP socket")?;
+    
+    // Cloudflare DNS first, Google DNS as fallback
+    socket.connect("[2606:4700:4700::1111]:53")
+        .or_else(|_| socket.connect("[2001:4860:4860::8888]:53"))
+        .context("Failed to connect to an IPv6 DNS server")?;
+    
+    let local_addr = socket.local_addr()
+        .context("Failed to get local IPv6 address")?;
+    
+    Ok(local_addr.ip().to_string())
+}
+
 /// Get public IP with fallback mechanism
 pub fn detect_public_ip() -> Result<String> {
     // Try primary method first (1.1.1.1)
     match get_public_ip() {
         Ok(ip) => Ok(ip),
         Err(_) => {
-            // Fallback to Google DNS
+            // Fallback to Google DNS, then to IPv6
             get_public_ip_fallback()
+                .or_else(|_| get_public_ipv6())
                 .context("Both primary and fallback IP detection methods failed")
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parses a listen address: an IP with an optional port ("::", "[::]:1000",
+/// "0.0.0.0", "192.168.1.5:1000"), using `default_port` when none is given
+pub fn parse_listen_address(address: &str, default_port: u16) -> Result<SocketAddr> {
+    let address = address.trim();
+    if let Ok(socket_addr) = address.parse::<SocketAddr>() {
+        return Ok(socket_addr);
+    }
+    
+    let ip = address.strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .with_context(|| format!("Invalid listen address: {}", address))
+}
+
+/// Turns a user-supplied destination into a "host:port" string that can be connected to
+///
+/// Accepts "host:port", "host", "[v6]:port", "[v6]" and bare IPv6 literals
+/// (which can't carry a port without brackets); `default_port` fills in a missing port.
+pub fn normalize_destination(destination: &str, default_port: u16) -> Result<String> {
+    let destination = destination.trim();
+    if destination.is_empty() {
+        return Err(anyhow::anyhow!("Destination is empty"));
+    }
+    
+    if let Some(rest) = destination.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')
+            .with_context(|| format!("Missing closing bracket in {}", destination))?;
+        let ip: Ipv6Addr = host.parse()
+            .with_context(|| format!("Invalid IPv6 address: {}", host))?;
+        let port = match port.strip_prefix(':') {
+            Some(port) => port.parse().with_context(|| format!("Invalid port: {}", port))?,
+            None if port.is_empty() => default_port,
+            None => return Err(anyhow::anyhow!("Invalid destination: {}", destination)),
+        };
+        return Ok(SocketAddr::new(IpAddr::V6(ip), port).to_string());
+    }
+    
+    // More than one colon without brackets can only be a bare IPv6 literal
+    if let Ok(ip) = destination.parse::<Ipv6Addr>() {
+        return Ok(SocketAddr::new(IpAddr::V6(ip), default_port).to_string());
+    }
+    
+    match destination.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => {
+            let port: u16 = port.parse().with_context(|| format!("Invalid port: {}", port))?;
+            Ok(format!("{}:{}", host, port))
+        }
+        Some(_) => Err(anyhow::anyhow!("Invalid destination: {}", destination)),
+        None => Ok(format!("{}:{}", destination, default_port)),
+    }
+}
+
+/// Config value for `interface` that binds to every interface
+pub const ANY_INTERFACE: &str = "any";
+
+/// An address assigned to a local network interface
+#[derive(Debug, Clone)]
+pub struct InterfaceAddress {
+    pub interface: String,
+    pub ip: IpAddr,
+    pub is_loopback: bool,
+    pub is_up: bool,
+}
+
+/// Lists the addresses of every local network interface, IPv4 and IPv6
+/// This only asks the operating system, so it works without any network route
+pub fn list_interfaces() -> Result<Vec<InterfaceAddress>> {
+    let interfaces = if_addrs::get_if_addrs()
+        .context("Failed to enumerate network interfaces")?;
+
+    Ok(interfaces.into_iter()
+        .map(|interface| InterfaceAddress {
+            is_loopback: interface.is_loopback(),
+            is_up: interface.is_oper_up(),
+            ip: interface.ip(),
+            interface: interface.name,
+        })
+        .collect())
+}
+
+/// Determines the address to bind to and advertise
+///
+/// `interface` is the configured interface name: empty picks automatically
+/// (the address routing to the internet, else the best LAN address), "any"
+/// binds to every interface and anything else selects that interface.
+pub fn detect_bind_address(interface: &str) -> Result<String> {
+    match interface {
+        "" => detect_public_ip()
+            .or_else(|_| best_address(&list_interfaces()?)
+                .context("No usable network interface found"))
+            .or_else(|_| Ok(Ipv4Addr::LOCALHOST.to_string())),
+        ANY_INTERFACE => Ok(Ipv4Addr::UNSPECIFIED.to_string()),
+        name => {
+            let addresses: Vec<InterfaceAddress> = list_interfaces()?
+                .into_iter()
+                .filter(|address| address.interface == name)
+                .collect();
+            if addresses.is_empty() {
+                return Err(anyhow::anyhow!("Network interface not found: {}", name));
+            }
+            best_address(&addresses)
+                .with_context(|| format!("Network interface {} has no usable address", name))
+        }
+    }
+}
+
+/// Lists the addresses the server can be reached at when bound to `bind`
+/// (a dual-stack IPv6 wildcard is reachable on IPv4 addresses too)
+pub fn reachable_addresses(bind: IpAddr) -> Vec<InterfaceAddress> {
+    let interfaces = list_interfaces().unwrap_or_default();
+
+    if !bind.is_unspecified() {
+        return match interfaces.into_iter().find(|address| address.ip == bind) {
+            Some(address) => vec![address],
+            None => vec![InterfaceAddress { interface: String::new(), ip: bind, is_loopback: bind.is_loopback(), is_up: true }],
+        };
+    }
+
+    // A wildcard bind is reachable on every interface that is up
+    let mut reachable: Vec<InterfaceAddress> = interfaces.into_iter()
+        .filter(|address| address.is_up && (address.ip.is_ipv4() || bind.is_ipv6()))
+        .collect();
+    reachable.sort_by_key(address_rank);
+    reachable
+}
+
+/// Picks the most useful address: up, not loopback, IPv4 before IPv6, link-local last
+fn best_address(addresses: &[InterfaceAddress]) -> Option<String> {
+    addresses.iter()
+        .min_by_key(|address| address_rank(address))
+        .filter(|address| !address.is_loopback || addresses.iter().all(|a| a.is_loopback))
+        .map(|address| address.ip.to_string())
+}
+
+/// Sort key for `best_address`, lower is better
+fn address_rank(address: &InterfaceAddress) -> (bool, bool, bool, bool) {
+    let link_local = match address.ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    };
+    (!address.is_up, address.is_loopback, address.ip.is_ipv6(), link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_listen_addresses() {
+        assert_eq!(parse_listen_address("0.0.0.0", 1000).unwrap(), "0.0.0.0:1000".parse().unwrap());
+        assert_eq!(parse_listen_address("192.168.1.5:2000", 1000).unwrap(), "192.168.1.5:2000".parse().unwrap());
+        assert_eq!(parse_listen_address("::", 1000).unwrap(), "[::]:1000".parse().unwrap());
+        assert_eq!(parse_listen_address("[::]", 1000).unwrap(), "[::]:1000".parse().unwrap());
+        assert_eq!(parse_listen_address(" [fe80::1]:2000 ", 1000).unwrap(), "[fe80::1]:2000".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_listen_addresses() {
+        for address in ["", "localhost", "example.com:1000", "1.2.3", "192.168.1.5:70000", "[::", "::]", "[192.168.1.5]:x"] {
+            assert!(parse_listen_address(address, 1000).is_err(), "{:?} was accepted", address);
+        }
+    }
+
+    #[test]
+    fn normalizes_destinations() {
+        assert_eq!(normalize_destination("host", 1000).unwrap(), "host:1000");
+        assert_eq!(normalize_destination("host:2000", 1000).unwrap(), "host:2000");
+        assert_eq!(normalize_destination("192.168.1.5", 1000).unwrap(), "192.168.1.5:1000");
+        assert_eq!(normalize_destination("fe80::1", 1000).unwrap(), "[fe80::1]:1000");
+        assert_eq!(normalize_destination("[fe80::1]", 1000).unwrap(), "[fe80::1]:1000");
+        assert_eq!(normalize_destination(" [::1]:2000 ", 1000).unwrap(), "[::1]:2000");
+    }
+
+    #[test]
+    fn rejects_invalid_destinations() {
+        for destination in ["", " ", ":1000", "host:", "host:x", "host:70000", "[::1", "[::1]2000", "[::1]:", "[host]:1000", "[::1]:x"] {
+            assert!(normalize_destination(destination, 1000).is_err(), "{:?} was accepted", destination);
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use futures_util::Stream;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+use crate::config::LimitsConfig;
+use crate::metrics;
+
+/// Time spent waiting for data over which limits.min_rate is averaged
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// A connection limit that was hit; its message is sent to the client as ERROR line
+#[derive(Debug)]
+pub struct LimitExceeded {
+    message: String,
+}
+
+impl LimitExceeded {
+    /// `limit` is one of the metrics::LIMIT_* constants
+    fn new(limit: &'static str, message: String) -> Self {
+        metrics::limit_exceeded(limit);
+        Self { message }
+    }
+
+    /// Finds the limit error among the causes of `e`, e.g. below "Failed to read file data"
+    pub fn find(e: &anyhow::Error) -> Option<&LimitExceeded> {
+        e.chain().find_map(|cause| {
+            cause.downcast_ref::<LimitExceeded>().or_else(|| {
+                cause.downcast_ref::<io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .and_then(|inner| inner.downcast_ref::<LimitExceeded>())
+            })
+        })
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl From<LimitExceeded> for io::Error {
+    fn from(e: LimitExceeded) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// Seconds from transfer.toml as a duration, None for 0 (no limit)
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// How long to wait for the next command
+pub fn command_timeout(limits: &LimitsConfig) -> Duration {
+    seconds(limits.command_timeout).unwrap_or(Duration::MAX)
+}
+
+/// Error for a client that didn't send a command in time
+pub fn command_timed_out(limits: &LimitsConfig) -> LimitExceeded {
+    LimitExceeded::new(metrics::LIMIT_COMMAND_TIMEOUT, format!("No command received within {}s", limits.command_timeout))
+}
+
+/// Open connections, overall and per IP address
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+static CONNECTIONS: Lazy<Mutex<Connections>> = Lazy::new(Default::default);
+
+/// Counts an open connection until dropped
+pub struct ConnectionSlot {
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        connections.total -= 1;
+        if let Some(count) = connections.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Takes a connection slot for `ip`, unless max_connections or max_connections_per_ip is reached
+pub fn admit(limits: &LimitsConfig, ip: IpAddr) -> Result<ConnectionSlot, LimitExceeded> {
+    let ip = ip.to_canonical();
+    let mut connections = CONNECTIONS.lock().unwrap();
+    if limits.max_connections > 0 && connections.total >= limits.max_connections {
+        return Err(LimitExceeded::new(metrics::LIMIT_CONNECTIONS, "Too many connections, try again later".to_string()));
+    }
+    let from_ip = connections.per_ip.get(&ip).copied().unwrap_or(0);
+    if limits.max_connections_per_ip > 0 && from_ip >= limits.max_connections_per_ip {
+        return Err(LimitExceeded::new(metrics::LIMIT_CONNECTIONS_PER_IP, "Too many connections from your address".to_string()));
+    }
+
+    connections.total += 1;
+    *connections.per_ip.entry(ip).or_insert(0) += 1;
+    Ok(ConnectionSlot { ip })
+}
+
+/// A client stream that enforces the idle timeout, minimum rate and session length
+///
+/// The idle timeout and minimum rate only apply while a command is in progress,
+/// so time spent between commands is left to the command timeout. Only time
+/// spent waiting for the client counts, not time the server is busy (e.g.
+/// asking for approval).
+///
+/// Also wraps HTTP request bodies, see `filter`.
+pub struct LimitedStream<S> {
+    inner: S,
+    idle_timeout: Option<Duration>,
+    min_rate: u64,
+    max_session: u64,
+    session: Option<Pin<Box<Sleep>>>,
+    session_expired: bool,
+    in_command: bool,
+    read_idle: Option<Pin<Box<Sleep>>>,
+    write_idle: Option<Pin<Box<Sleep>>>,
+    waiting_since: Option<Instant>,
+    waited: Duration,
+    received: u64,
+}
+
+impl<S> LimitedStream<S> {
+    pub fn new(inner: S, limits: &LimitsConfig) -> Self {
+        Self {
+            inner,
+            idle_timeout: seconds(limits.idle_timeout),
+            min_rate: limits.min_rate,
+            max_session: limits.max_session,
+            session: seconds(limits.max_session).map(|duration| Box::pin(tokio::time::sleep(duration))),
+            session_expired: false,
+            in_command: false,
+            read_idle: None,
+            write_idle: None,
+            waiting_since: None,
+            waited: Duration::ZERO,
+            received: 0,
+        }
+    }
+
+    /// Switches between handling a command and waiting for the next one
+    pub fn set_in_command(&mut self, in_command: bool) {
+        self.in_command = in_command;
+        self.read_idle = None;
+        self.write_idle = None;
+        self.waiting_since = None;
+        self.waited = Duration::ZERO;
+        self.received = 0;
+    }
+
+    /// Fails once the session is over
+    ///
+    /// Reads fail from then on, writes only until the first failure, so the
+    /// ERROR line still reaches the client.
+    fn check_session(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        if self.session_expired || session.as_mut().poll(cx).is_ready() {
+            self.session_expired = true;
+            return Err(LimitExceeded::new(
+                metrics::LIMIT_MAX_SESSION,
+                format!("Connection exceeded the maximum session length of {}s", self.max_session),
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// Fails once `idle` has been waiting longer than the idle timeout
+    fn check_idle(idle: &mut Option<Pin<Box<Sleep>>>, timeout: Option<Duration>, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(timeout) = timeout else {
+            return Ok(());
+        };
+        let sleep = idle.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if sleep.as_mut().poll(cx).is_ready() {
+            return Err(LimitExceeded::new(
+                metrics::LIMIT_IDLE_TIMEOUT,
+                format!("No data transferred for {}s", timeout.as_secs()),
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// Averages the rate over each RATE_WINDOW spent waiting and fails if it is below min_rate
+    fn count_received(&mut self, bytes: usize) -> io::Result<()> {
+        if let Some(since) = self.waiting_since.take() {
+            self.waited += since.elapsed();
+        }
+        self.received += bytes as u64;
+        if self.min_rate == 0 || self.waited < RATE_WINDOW {
+            return Ok(());
+        }
+
+        let rate = self.received as f64 / self.waited.as_secs_f64();
+        self.waited = Duration::ZERO;
+        self.received = 0;
+        if rate < self.min_rate as f64 {
+            return Err(LimitExceeded::new(
+                metrics::LIMIT_MIN_RATE,
+                format!("Transfer slower than the minimum of {} bytes/s", self.min_rate),
+            ).into());
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LimitedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.check_session(cx)?;
+
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.read_idle = None;
+                if this.in_command {
+                    this.count_received(buf.filled().len() - filled)?;
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                if this.in_command {
+                    this.waiting_since.get_or_insert_with(Instant::now);
+                    Self::check_idle(&mut this.read_idle, this.idle_timeout, cx)?;
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LimitedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.session_expired {
+            this.check_session(cx)?;
+        }
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.write_idle = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if this.in_command {
+                    Self::check_idle(&mut this.write_idle, this.idle_timeout, cx)?;
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S, E> Stream for LimitedStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<BoxError>,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Err(e) = this.check_session(cx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.read_idle = None;
+                if this.in_command && let Err(e) = this.count_received(chunk.len()) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::other(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                if this.in_command {
+                    this.waiting_since.get_or_insert_with(Instant::now);
+                    if let Err(e) = Self::check_idle(&mut this.read_idle, this.idle_timeout, cx) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Applies the connection limits to HTTP requests
+///
+/// Every request takes a connection slot until it is answered and has to be
+/// answered within max_session; the request body gets the idle timeout and
+/// minimum rate.
+pub async fn filter(State(limits): State<LimitsConfig>, ConnectInfo(peer): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    let _slot = match admit(&limits, peer.ip()) {
+        Ok(slot) => slot,
+        Err(e) => {
+            warn!("Refused HTTP request from {}: {}", peer, e);
+            return (StatusCode::SERVICE_UNAVAILABLE, format!("{}\n", e)).into_response();
+        }
+    };
+
+    let (parts, body) = request.into_parts();
+    let mut body = LimitedStream::new(body.into_data_stream(), &limits);
+    body.set_in_command(true);
+    let request = Request::from_parts(parts, Body::from_stream(body));
+
+    let Some(max_session) = seconds(limits.max_session) else {
+        return next.run(request).await;
+    };
+    match tokio::time::timeout(max_session, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            let e = LimitExceeded::new(
+                metrics::LIMIT_MAX_SESSION,
+                format!("Request exceeded the maximum session length of {}s", limits.max_session),
+            );
+            warn!("Stopped HTTP request from {}: {}", peer, e);
+            (StatusCode::REQUEST_TIMEOUT, format!("{}\n", e)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn limits(idle_timeout: u64, min_rate: u64) -> LimitsConfig {
+        LimitsConfig { idle_timeout, min_rate, max_session: 0, ..Default::default() }
+    }
+
+    /// A limited server end and the client end of an in-memory connection
+    fn connection(limits: &LimitsConfig, in_command: bool) -> (LimitedStream<DuplexStream>, DuplexStream) {
+        let (server, client) = duplex(64 * 1024);
+        let mut stream = LimitedStream::new(server, limits);
+        stream.set_in_command(in_command);
+        (stream, client)
+    }
+
+    /// Sends `chunk` every `interval` until the connection is closed
+    fn send_slowly(mut client: DuplexStream, chunk: usize, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if client.write_all(&vec![0; chunk]).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    async fn read_chunks(stream: &mut LimitedStream<DuplexStream>, count: usize) -> io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        for _ in 0..count {
+            if stream.read(&mut buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_read_times_out_during_a_command() {
+        let (mut stream, _client) = connection(&limits(5, 0), true);
+        let started = Instant::now();
+        let error = stream.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "No data transferred for 5s");
+        assert_eq!(started.elapsed().as_secs(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_write_times_out_during_a_command() {
+        let (server, _client) = duplex(16);
+        let mut stream = LimitedStream::new(server, &limits(5, 0));
+        stream.set_in_command(true);
+        // Nobody reads the client end, so the buffer fills up
+        let error = stream.write_all(&[0; 64]).await.unwrap_err();
+        assert_eq!(error.to_string(), "No data transferred for 5s");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_is_left_to_the_command_timeout_between_commands() {
+        let (mut stream, _client) = connection(&limits(5, 0), false);
+        let waited = tokio::time::timeout(Duration::from_secs(60), stream.read(&mut [0; 16])).await;
+        assert!(waited.is_err(), "read ended before the timeout");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn data_resets_the_idle_timeout() {
+        let (mut stream, client) = connection(&limits(5, 0), true);
+        send_slowly(client, 10, Duration::from_secs(4));
+        read_chunks(&mut stream, 5).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_transfer_fails_after_the_rate_window() {
+        let (mut stream, client) = connection(&limits(0, 100), true);
+        // 1 byte/s against a minimum of 100
+        send_slowly(client, 10, Duration::from_secs(10));
+        let started = Instant::now();
+        let error = read_chunks(&mut stream, 10).await.unwrap_err();
+        assert_eq!(error.to_string(), "Transfer slower than the minimum of 100 bytes/s");
+        assert!(started.elapsed() >= RATE_WINDOW);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fast_transfer_passes_the_rate_check() {
+        let (mut stream, client) = connection(&limits(0, 100), true);
+        send_slowly(client, 10_000, Duration::from_secs(10));
+        read_chunks(&mut stream, 10).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_is_only_checked_during_a_command() {
+        let (mut stream, client) = connection(&limits(0, 100), false);
+        send_slowly(client, 10, Duration::from_secs(10));
+        read_chunks(&mut stream, 10).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn time_the_server_is_busy_does_not_lower_the_rate() {
+        let (mut stream, mut client) = connection(&limits(0, 100), true);
+        client.write_all(&[0; 1000]).await.unwrap();
+        let mut buf = [0; 10];
+        for _ in 0..10 {
+            // The data is already there, so the stream never waits for it
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            stream.read_exact(&mut buf).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn connection_slots_are_limited() {
+        let ip = IpAddr::from([198, 51, 100, 1]);
+        let other = IpAddr::from([198, 51, 100, 2]);
+
+        let per_ip = LimitsConfig { max_connections: 0, max_connections_per_ip: 2, ..Default::default() };
+        let first = admit(&per_ip, ip).unwrap();
+        let _second = admit(&per_ip, ip).unwrap();
+        let error = admit(&per_ip, ip).err().unwrap();
+        assert_eq!(error.to_string(), "Too many connections from your address");
+        let _other = admit(&per_ip, other).unwrap();
+        drop(first);
+        let _third = admit(&per_ip, ip).unwrap();
+
+        // Three slots are taken now
+        let total = LimitsConfig { max_connections: 4, max_connections_per_ip: 0, ..Default::default() };
+        let fourth = admit(&total, other).unwrap();
+        let error = admit(&total, other).err().unwrap();
+        assert_eq!(error.to_string(), "Too many connections, try again later");
+        drop(fourth);
+        admit(&total, other).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use crate::config::Config;
+use crate::control;
+use crate::metrics;
+use crate::structure;
+
+/// Status of an upload link or share that can still be used
+pub const STATUS_ACTIVE: &str = "active";
+pub const STATUS_EXPIRED: &str = "expired";
+pub const STATUS_USED: &str = "used";
+pub const STATUS_REVOKED: &str = "revoked";
+
+/// An upload link: a token that senders present instead of the transfer ID
+///
+/// Uploads with the token are stored in `folder`, limited to `max_size` bytes
+/// per transfer and only accepted until `expires`, for `uses` transfers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Link {
+    pub token: String,
+    /// Subfolder of the receive folder, empty for the receive folder itself
+    #[serde(default)]
+    pub folder: String,
+    /// Largest transfer accepted in bytes (all files of a batch, tree or
+    /// request together), 0 to only apply max_file_size
+    #[serde(default)]
+    pub max_size: u64,
+    /// RFC 3339, UTC
+    pub created: String,
+    /// RFC 3339, UTC
+    pub expires: String,
+    /// Number of transfers the link can be used for
+    pub uses: u32,
+    #[serde(default)]
+    pub used: u32,
+    /// Revoked links stay listed so their token keeps being refused
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+impl Link {
+    /// One of the STATUS_* constants
+    pub fn status(&self) -> &'static str {
+        let expired = DateTime::parse_from_rfc3339(&self.expires)
+            .map_or(true, |expires| expires <= Utc::now());
+        if self.revoked {
+            STATUS_REVOKED
+        } else if self.used >= self.uses {
+            STATUS_USED
+        } else if expired {
+            STATUS_EXPIRED
+        } else {
+            STATUS_ACTIVE
+        }
+    }
+
+    /// Refuses a transfer of `size` bytes in total if it is larger than max_size
+    ///
+    /// A use covers a whole batch, tree or request, so the limit applies to
+    /// all of its files together, not to each of them.
+    pub fn check_size(&self, size: u64) -> Result<()> {
+        if self.max_size > 0 && size > self.max_size {
+            metrics::rejected(metrics::REASON_FILE_SIZE);
+            return Err(anyhow::anyhow!("Transfer of {} bytes exceeds the upload link's limit of {} bytes", size, self.max_size));
+        }
+        Ok(())
+    }
+
+    /// Counts one use of the link, refusing it if it was used up in the meantime
+    ///
+    /// Called once a transfer is accepted, right before its ACK.
+    pub fn redeem(&self) -> Result<()> {
+        structure::update_json_list(LINKS_FILE, |links: &mut Vec<Link>| {
+            let link = links.iter_mut()
+                .find(|link| link.token == self.token)
+                .context("Upload link was removed")?;
+            check_status(link)?;
+
+            link.used += 1;
+            info!("Upload link {} used ({}/{})", short_token(&link.token), link.used, link.uses);
+            Ok(())
+        })
+    }
+}
+
+/// Links are kept next to transfer.toml
+const LINKS_FILE: &str = "links.json";
+
+/// Creates a link for `uses` uploads into `folder` during the next `lifetime`
+pub fn create(folder: Option<&str>, lifetime: chrono::Duration, max_size: u64, uses: u32) -> Result<Link> {
+    let folder = match folder {
+        Some(folder) => structure::sanitize_relative_path(folder)?.to_string_lossy().to_string(),
+        None => String::new(),
+    };
+    if uses == 0 {
+        return Err(anyhow::anyhow!("A link needs at least one use"));
+    }
+
+    let now = Utc::now();
+    let expires = now.checked_add_signed(lifetime).context("Expiry is too far in the future")?;
+    let link = Link {
+        token: uuid::Uuid::new_v4().simple().to_string(),
+        folder,
+        max_size,
+        created: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        expires: expires.to_rfc3339_opts(SecondsFormat::Secs, true),
+        uses,
+        used: 0,
+        revoked: false,
+    };
+
+    structure::update_json_list(LINKS_FILE, |links| {
+        links.push(link.clone());
+        Ok(())
+    })?;
+
+    Ok(link)
+}
+
+/// All links, oldest first
+pub fn list() -> Result<Vec<Link>> {
+    load()
+}
+
+/// Revokes the link with `token`; returns false if there is none
+pub fn revoke(token: &str) -> Result<bool> {
+    structure::update_json_list(LINKS_FILE, |links: &mut Vec<Link>| {
+        let Some(link) = links.iter_mut().find(|link| link.token == token) else {
+            return Ok(false);
+        };
+        link.revoked = true;
+        Ok(true)
+    })
+}
+
+/// Looks up `transfer_id` as an upload link and narrows `config` to it
+///
+/// Returns None if it isn't a link. For a link, the receive folder becomes
+/// the link's folder and max_file_size its size limit, so the usual checks
+/// enforce both. Links that can't be used anymore are refused.
+pub fn apply(config: &mut Config, transfer_id: &str) -> Result<Option<Link>> {
+    if control::constant_time_eq(transfer_id.as_bytes(), config.transfer_id.as_bytes()) {
+        return Ok(None);
+    }
+
+    let link = load()?.into_iter()
+        .find(|link| control::constant_time_eq(transfer_id.as_bytes(), link.token.as_bytes()));
+    let Some(link) = link else {
+        return Ok(None);
+    };
+    if let Err(e) = check_status(&link) {
+        warn!("Refused upload link {}: {}", short_token(&link.token), e);
+        metrics::rejected(metrics::REASON_LINK);
+        return Err(e);
+    }
+
+    config.narrow(&link.folder, link.max_size);
+    info!("Upload link {} accepted (folder: {:?}, max size: {})", short_token(&link.token), link.folder, link.max_size);
+
+    Ok(Some(link))
+}
+
+fn check_status(link: &Link) -> Result<()> {
+    match link.status() {
+        STATUS_ACTIVE => Ok(()),
+        STATUS_EXPIRED => Err(anyhow::anyhow!("Upload link expired")),
+        STATUS_USED => Err(anyhow::anyhow!("Upload link has been used up")),
+        _ => Err(anyhow::anyhow!("Upload link was revoked")),
+    }
+}
+
+/// Enough of a token to tell links and shares apart in the log without revealing it
+pub fn short_token(token: &str) -> &str {
+    &token[..token.len().min(8)]
+}
+
+/// Changes are made with `structure::update_json_list`, which replaces the file in one step
+fn load() -> Result<Vec<Link>> {
+    structure::read_json_list(LINKS_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.folder = "/srv/transfer".to_string();
+        config.max_file_size = 5000;
+        config
+    }
+
+    fn status(token: &str) -> &'static str {
+        list().unwrap().into_iter().find(|link| link.token == token).unwrap().status()
+    }
+
+    #[test]
+    fn link_narrows_the_config_until_used_up() {
+        let link = create(Some("guests"), chrono::Duration::hours(1), 1000, 2).unwrap();
+
+        let mut config = config();
+        let applied = apply(&mut config, &link.token).unwrap().unwrap();
+        assert_eq!(Path::new(&config.folder), Path::new("/srv/transfer/guests"));
+        assert_eq!(config.max_file_size, 1000);
+
+        applied.redeem().unwrap();
+        assert_eq!(status(&link.token), STATUS_ACTIVE);
+        applied.redeem().unwrap();
+        assert_eq!(status(&link.token), STATUS_USED);
+
+        let error = apply(&mut self::config(), &link.token).unwrap_err().to_string();
+        assert_eq!(error, "Upload link has been used up");
+        assert!(applied.redeem().is_err());
+    }
+
+    #[test]
+    fn expired_link_is_refused() {
+        let link = create(None, chrono::Duration::seconds(-1), 0, 1).unwrap();
+        assert_eq!(status(&link.token), STATUS_EXPIRED);
+        let error = apply(&mut config(), &link.token).unwrap_err().to_string();
+        assert_eq!(error, "Upload link expired");
+    }
+
+    #[test]
+    fn revoked_link_is_refused() {
+        let link = create(None, chrono::Duration::hours(1), 0, 1).unwrap();
+        assert!(revoke(&link.token).unwrap());
+        assert!(!revoke("no-such-link").unwrap());
+        let error = apply(&mut config(), &link.token).unwrap_err().to_string();
+        assert_eq!(error, "Upload link was revoked");
+    }
+
+    #[test]
+    fn other_ids_are_not_links() {
+        let mut config = config();
+        assert!(apply(&mut config, "no-such-link").unwrap().is_none());
+        let transfer_id = config.transfer_id.clone();
+        assert!(apply(&mut config, &transfer_id).unwrap().is_none());
+        assert_eq!(config.folder, "/srv/transfer");
+    }
+
+    #[test]
+    fn create_refuses_bad_links() {
+        assert!(create(None, chrono::Duration::hours(1), 0, 0).is_err());
+        assert!(create(Some("../outside"), chrono::Duration::hours(1), 0, 1).is_err());
+    }
+
+    #[test]
+    fn size_limit_covers_the_whole_transfer() {
+        let link = Link { max_size: 1000, ..create(None, chrono::Duration::hours(1), 0, 1).unwrap() };
+        link.check_size(1000).unwrap();
+        let error = link.check_size(1001).unwrap_err().to_string();
+        assert_eq!(error, "Transfer of 1001 bytes exceeds the upload link's limit of 1000 bytes");
+
+        let unlimited = Link { max_size: 0, ..link };
+        unlimited.check_size(u64::MAX).unwrap();
+    }
+
+    #[test]
+    fn concurrent_creates_are_all_kept() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| create(None, chrono::Duration::hours(1), 0, 1).unwrap().token))
+            .collect();
+        let tokens: Vec<String> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+
+        let links = list().unwrap();
+        for token in tokens {
+            assert!(links.iter().any(|link| link.token == token));
+        }
+    }
+}
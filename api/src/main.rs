@@ -1,10 +1,34 @@
+mod access;
+mod activity;
+mod approval;
+mod bans;
+mod beacon;
+mod cli;
+mod client;
+mod compression;
 mod config;
+mod control;
+mod crypto;
+mod discovery;
+mod download;
+mod firewall;
+mod history;
 mod ip;
+mod limits;
+mod links;
+mod metadata;
+mod metrics;
 mod api;
+mod session;
+mod shares;
+mod storage;
 mod structure;
+mod tree;
+mod upload;
+mod websocket;
 
 use anyhow::{Context, Result};
-use log::info;
+use log::{error, info, warn};
 use crate::config::Config;
 
 #[tokio::main]
@@ -12,26 +36,143 @@ async fn main() -> Result<()> {
     // Initialize logger
     env_logger::init();
     
+    // Run a command line subcommand instead of the server if one was given
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
+    
     // Load or create configuration (this will also detect and update public IP)
-    let config = Config::load_or_create()
+    let config = Config::current()
         .context("Failed to load or create configuration")?;
     
     // Create config directory structure (for the config file itself)
     structure::create_directory_structure()
         .context("Failed to create config directory structure")?;
     
-    // Create the configured transfer directory
-    let transfer_dir = std::path::PathBuf::from(&config.folder);
-    if !transfer_dir.exists() {
-        std::fs::create_dir_all(&transfer_dir)
-            .with_context(|| format!("Failed to create transfer directory: {}", transfer_dir.display()))?;
+    // Create the configured transfer directories, one per inbox
+    config.check_inboxes()
+        .context("Invalid [[inboxes]] in transfer.toml")?;
+    let folders: Vec<String> = std::iter::once(config.folder.clone())
+        .chain(config.inboxes.iter().map(|inbox| config.inbox_folder(inbox)))
+        .collect();
+    for folder in &folders {
+        let transfer_dir = std::path::PathBuf::from(folder);
+        if !transfer_dir.exists() {
+            std::fs::create_dir_all(&transfer_dir)
+                .with_context(|| format!("Failed to create transfer directory: {}", transfer_dir.display()))?;
+        }
+        
+        // Discard transactional batches that were never committed; what can't be
+        // cleaned up stays for the user to look at, without keeping the server down
+        if let Err(e) = storage::cleanup_staging(folder) {
+            error!("Failed to clean up staging directory of {}: {:#}", folder, e);
+        }
     }
     
-    println!("Transfer running on {}:{}", config.bind, config.port);
+    // Load the at-rest encryption key before accepting any files
+    crypto::init(&config.encryption)
+        .context("Failed to initialize at-rest encryption")?;
+    
+    // Parse the allow and deny lists once, so a typo stops the server instead of letting everyone in
+    let firewall = std::sync::Arc::new(firewall::Firewall::new(&config)
+        .context("Failed to load the allow and deny lists")?);
+    
+    // Bans are kept in memory from here on; changes by `transfer unban` are picked up
+    bans::load()
+        .context("Failed to load bans")?;
+    tokio::spawn(bans::watch());
+    
+    for listen in config.listen_addresses()? {
+        println!("Transfer running on {}", listen);
+        for address in ip::reachable_addresses(listen.ip()) {
+            let socket_addr = std::net::SocketAddr::new(address.ip, listen.port());
+            if address.interface.is_empty() {
+                println!("  reachable at {}", socket_addr);
+            } else {
+                println!("  reachable at {} ({})", socket_addr, address.interface);
+            }
+        }
+    }
     info!("Transfer ID: {}", config.transfer_id);
+    if !config.allow.is_empty() || !config.deny.is_empty() {
+        info!("Connections allowed from {:?}, denied from {:?}", config.allow, config.deny);
+    }
+    for inbox in &config.inboxes {
+        info!("Inbox {}: {} (transfer ID {})", inbox.name, config.inbox_folder(inbox), inbox.transfer_id);
+        let duplicates = config.inboxes.iter().filter(|other| other.transfer_id == inbox.transfer_id).count();
+        if inbox.transfer_id == config.transfer_id || duplicates > 1 {
+            warn!("Inbox {} shares its transfer ID with another inbox, only the default or first one receives files", inbox.name);
+        }
+    }
+    
+    // Advertise on the local network; the server still works if this fails
+    let _mdns = if config.mdns {
+        discovery::advertise(&config)
+            .map_err(|e| warn!("mDNS advertisement unavailable: {:#}", e))
+            .ok()
+    } else {
+        None
+    };
+    
+    // Expose Prometheus metrics; the server still works if this fails
+    if config.metrics {
+        let address = config.metrics_address.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&address).await {
+                warn!("Metrics endpoint unavailable: {:#}", e);
+            }
+        });
+    }
+    
+    // Local HTTP API for the web UI; the server still works if this fails
+    if config.control.enabled {
+        let control = config.control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(&control).await {
+                warn!("Control API unavailable: {:#}", e);
+            }
+        });
+    }
+    
+    // Same protocol over WebSocket for browsers; the server still works if this fails
+    if config.websocket {
+        let addresses = config.listen_addresses_at(config.websocket_port)?;
+        let (firewall, limits) = (firewall.clone(), config.limits.clone());
+        tokio::spawn(async move {
+            if let Err(e) = websocket::serve(addresses, firewall, limits).await {
+                warn!("WebSocket transfer server unavailable: {:#}", e);
+            }
+        });
+    }
+    
+    // Uploads with curl from machines without the client; the server still works if this fails
+    if config.http_upload {
+        let addresses = config.listen_addresses_at(config.http_upload_port)?;
+        let (firewall, limits) = (firewall.clone(), config.limits.clone());
+        tokio::spawn(async move {
+            if let Err(e) = upload::serve(addresses, firewall, limits).await {
+                warn!("HTTP upload server unavailable: {:#}", e);
+            }
+        });
+    }
+    
+    // Let the local user accept or reject incoming transfers
+    if config.approval.enabled {
+        let (control_port, token) = (config.approval.control_port, config.control.token.clone());
+        tokio::spawn(async move {
+            if let Err(e) = approval::run_control_socket(control_port, token).await {
+                warn!("Approval control socket stopped: {:#}", e);
+            }
+        });
+        if std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            tokio::spawn(approval::run_prompt());
+        }
+        info!("Approval mode enabled, unanswered transfers are rejected after {}s", config.approval.timeout);
+    }
     
     // Start the transfer protocol server
-    api::start_tcp_server(&config.bind, config.port).await?;
+    api::start_tcp_server(&config, firewall).await?;
     
     Ok(())
 } 
\ No newline at end of file
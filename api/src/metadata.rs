@@ -0,0 +1,207 @@
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use crate::config::MetadataConfig;
+
+/// Prefix of extended attribute options in a TRANSFER command (xattr.<name>=<hex value>)
+const XATTR_OPTION_PREFIX: &str = "xattr.";
+
+/// Only extended attributes in this namespace are sent and applied; the others
+/// (security, trusted, system) can grant privileges or need them to be set
+const XATTR_NAMESPACE: &str = "user.";
+
+/// Permission policy values for `[metadata] permissions`
+pub const PERMISSIONS_PRESERVE: &str = "preserve";
+pub const PERMISSIONS_STRIP_SETUID: &str = "strip_setuid";
+pub const PERMISSIONS_IGNORE: &str = "ignore";
+
+/// File metadata recorded by the sender and applied after the file is written
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Unix permission bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    /// Extended attributes, values hex encoded
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    /// Reads the metadata of a local file or directory (failures leave fields empty)
+    pub fn from_path(path: &Path) -> Self {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return Self::default();
+        };
+
+        Self {
+            mode: file_mode(&metadata),
+            mtime: metadata.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64),
+            xattrs: read_xattrs(path),
+        }
+    }
+
+    /// Sets a field from a TRANSFER command option
+    /// Returns false if the key is not a metadata option
+    pub fn set_option(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "mtime" => match value.parse() {
+                Ok(mtime) => self.mtime = Some(mtime),
+                Err(_) => warn!("Ignoring invalid mtime: {}", value),
+            },
+            "mode" => match u32::from_str_radix(value, 8) {
+                Ok(mode) => self.mode = Some(mode & 0o7777),
+                Err(_) => warn!("Ignoring invalid mode: {}", value),
+            },
+            _ => {
+                let Some(name) = key.strip_prefix(XATTR_OPTION_PREFIX) else {
+                    return false;
+                };
+                self.xattrs.insert(name.to_string(), value.to_string());
+            }
+        }
+        true
+    }
+
+    /// Formats the metadata as TRANSFER command options, each with a leading space
+    /// Extended attributes are left out once the options would exceed `max_len`
+    pub fn to_options(&self, max_len: usize) -> String {
+        let mut options = String::new();
+        if let Some(mtime) = self.mtime {
+            options.push_str(&format!(" mtime={}", mtime));
+        }
+        if let Some(mode) = self.mode {
+            options.push_str(&format!(" mode={:o}", mode));
+        }
+        for (name, value) in &self.xattrs {
+            // Names must survive the space separated key=value syntax
+            if name.contains(char::is_whitespace) || name.contains('=') {
+                warn!("Not sending extended attribute {:?}", name);
+                continue;
+            }
+            let option = format!(" {}{}={}", XATTR_OPTION_PREFIX, name, value);
+            if options.len() + option.len() > max_len {
+                warn!("Not sending extended attribute {} (command too long)", name);
+                continue;
+            }
+            options.push_str(&option);
+        }
+        options
+    }
+}
+
+/// Applies the recorded metadata to a received file or directory as allowed by the policy
+/// Failures are logged, the file itself is kept
+pub fn apply(path: &Path, metadata: &FileMetadata, policy: &MetadataConfig) {
+    if policy.preserve_xattrs {
+        for (name, value) in &metadata.xattrs {
+            if !name.starts_with(XATTR_NAMESPACE) {
+                warn!("Ignoring extended attribute {} on {}", name, path.display());
+                continue;
+            }
+            let result = hex::decode(value)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                .and_then(|value| set_xattr(path, name, &value));
+            if let Err(e) = result {
+                warn!("Failed to set extended attribute {} on {}: {}", name, path.display(), e);
+            }
+        }
+    }
+
+    if let Some(mode) = metadata.mode {
+        let mode = match policy.permissions.as_str() {
+            PERMISSIONS_PRESERVE => Some(mode & 0o7777),
+            PERMISSIONS_IGNORE => None,
+            // Anything else falls back to the safe default
+            _ => Some(mode & 0o1777),
+        };
+        if let Some(mode) = mode && let Err(e) = set_mode(path, mode) {
+            warn!("Failed to set permissions on {}: {}", path.display(), e);
+        }
+    }
+
+    // Last, as writing attributes may touch the file
+    if policy.preserve_mtime && let Some(mtime) = metadata.mtime {
+        let time = if mtime >= 0 {
+            UNIX_EPOCH + Duration::from_secs(mtime as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+        };
+        if let Err(e) = set_modified(path, time) {
+            warn!("Failed to set modification time on {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> std::io::Result<()> {
+    // Files need write access on some platforms, directories can only be opened for reading
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(!path.is_dir())
+        .open(path)?
+        .set_modified(time)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Reads the user namespace extended attributes of a file, hex encoded
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> BTreeMap<String, String> {
+    let mut xattrs = BTreeMap::new();
+    let Ok(names) = xattr::list(path) else {
+        return xattrs;
+    };
+
+    for name in names {
+        let Some(name) = name.to_str().filter(|n| n.starts_with(XATTR_NAMESPACE)) else {
+            continue;
+        };
+        if let Ok(Some(value)) = xattr::get(path, name) {
+            xattrs.insert(name.to_string(), hex::encode(value));
+        }
+    }
+    xattrs
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+#[cfg(unix)]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(not(unix))]
+fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+    Ok(())
+}
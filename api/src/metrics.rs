@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use axum::{http::{header, StatusCode}, routing::get, Router};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::path::Path;
+use tokio::net::TcpListener;
+use crate::history::{self, HistoryEntry};
+
+/// Rejection reasons counted in transfer_rejections_total
+pub const REASON_FILE_SIZE: &str = "file_size";
+pub const REASON_FOLDER_SIZE: &str = "folder_size";
+pub const REASON_APPROVAL: &str = "approval";
+/// Expired, used up or revoked upload link
+pub const REASON_LINK: &str = "link";
+/// Name already taken with the "reject" conflict policy
+pub const REASON_CONFLICT: &str = "conflict";
+
+/// Limits counted in connection_limits_total
+pub const LIMIT_CONNECTIONS: &str = "connections";
+pub const LIMIT_CONNECTIONS_PER_IP: &str = "connections_per_ip";
+pub const LIMIT_COMMAND_TIMEOUT: &str = "command_timeout";
+pub const LIMIT_IDLE_TIMEOUT: &str = "idle_timeout";
+pub const LIMIT_MIN_RATE: &str = "min_rate";
+pub const LIMIT_MAX_SESSION: &str = "max_session";
+
+/// Collectors exposed on /metrics
+struct Metrics {
+    registry: Registry,
+    connections: IntCounter,
+    transfers: IntCounterVec,
+    received_bytes: IntCounter,
+    duration: HistogramVec,
+    transfer_size: Histogram,
+    active: IntGauge,
+    folder_usage: IntGaugeVec,
+    folder_limit: IntGaugeVec,
+    rejections: IntCounterVec,
+    limits: IntCounterVec,
+    auth_failures: IntCounter,
+    bans: IntCounter,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let registry = Registry::new_custom(Some("transfer".to_string()), None)
+        .expect("valid metrics prefix");
+
+    let metrics = Metrics {
+        connections: IntCounter::new("connections_total", "TCP connections accepted")
+            .expect("valid metric"),
+        transfers: IntCounterVec::new(Opts::new("transfers_total", "Files per transfer kind and result code"), &["kind", "result"])
+            .expect("valid metric"),
+        received_bytes: IntCounter::new("received_bytes_total", "Bytes of file contents received")
+            .expect("valid metric"),
+        duration: HistogramVec::new(
+            HistogramOpts::new("duration_seconds", "Time taken to receive a file")
+                .buckets(vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0]),
+            &["kind"],
+        )
+        .expect("valid metric"),
+        transfer_size: Histogram::with_opts(
+            HistogramOpts::new("file_size_bytes", "Size of received files")
+                .buckets(prometheus::exponential_buckets(1024.0, 8.0, 9).expect("valid buckets")),
+        )
+        .expect("valid metric"),
+        active: IntGauge::new("active_transfers", "TRANSFER, TREE and SESSION commands in progress")
+            .expect("valid metric"),
+        folder_usage: IntGaugeVec::new(Opts::new("folder_usage_bytes", "Size of a receive folder when its quota was last checked"), &["folder"])
+            .expect("valid metric"),
+        folder_limit: IntGaugeVec::new(Opts::new("folder_limit_bytes", "Configured max_folder_size of a receive folder"), &["folder"])
+            .expect("valid metric"),
+        rejections: IntCounterVec::new(Opts::new("rejections_total", "Transfers refused before the ACK, by reason"), &["reason"])
+            .expect("valid metric"),
+        limits: IntCounterVec::new(Opts::new("connection_limits_total", "Connections refused or ended by a limit, by limit"), &["limit"])
+            .expect("valid metric"),
+        auth_failures: IntCounter::new("auth_failures_total", "Unknown transfer IDs and tokens presented")
+            .expect("valid metric"),
+        bans: IntCounter::new("bans_total", "Addresses banned after too many failed authentications")
+            .expect("valid metric"),
+        registry,
+    };
+
+    let collectors: [Box<dyn prometheus::core::Collector>; 12] = [
+        Box::new(metrics.connections.clone()),
+        Box::new(metrics.transfers.clone()),
+        Box::new(metrics.received_bytes.clone()),
+        Box::new(metrics.duration.clone()),
+        Box::new(metrics.transfer_size.clone()),
+        Box::new(metrics.active.clone()),
+        Box::new(metrics.folder_usage.clone()),
+        Box::new(metrics.folder_limit.clone()),
+        Box::new(metrics.rejections.clone()),
+        Box::new(metrics.limits.clone()),
+        Box::new(metrics.auth_failures.clone()),
+        Box::new(metrics.bans.clone()),
+    ];
+    for collector in collectors {
+        metrics.registry.register(collector).expect("metric registered once");
+    }
+
+    metrics
+});
+
+/// Counts an accepted connection
+pub fn connection_accepted() {
+    METRICS.connections.inc();
+}
+
+/// Counts the outcome of one file
+pub fn transfer_finished(entry: &HistoryEntry) {
+    METRICS.transfers.with_label_values(&[entry.kind.as_str(), entry.result.as_str()]).inc();
+    if entry.result == history::RESULT_OK {
+        METRICS.received_bytes.inc_by(entry.size);
+        METRICS.transfer_size.observe(entry.size as f64);
+        METRICS.duration.with_label_values(&[entry.kind.as_str()]).observe(entry.duration_ms as f64 / 1000.0);
+    }
+}
+
+/// Counts a transfer refused before the ACK
+pub fn rejected(reason: &str) {
+    METRICS.rejections.with_label_values(&[reason]).inc();
+}
+
+/// Counts a connection refused or ended by one of the LIMIT_* limits
+pub fn limit_exceeded(limit: &str) {
+    METRICS.limits.with_label_values(&[limit]).inc();
+}
+
+/// Counts a failed authentication
+pub fn auth_failed() {
+    METRICS.auth_failures.inc();
+}
+
+/// Counts a ban
+pub fn banned() {
+    METRICS.bans.inc();
+}
+
+/// Reports the size of a receive folder against its limit
+pub fn folder_usage(folder: &Path, used: u64, limit: u64) {
+    let folder = folder.to_string_lossy();
+    METRICS.folder_usage.with_label_values(&[folder.as_ref()]).set(used as i64);
+    METRICS.folder_limit.with_label_values(&[folder.as_ref()]).set(limit as i64);
+}
+
+/// Reports the number of transfers in progress
+pub fn set_active_transfers(count: usize) {
+    METRICS.active.set(count as i64);
+}
+
+/// Renders every metric in the Prometheus text format
+fn render() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer)
+        .context("Failed to encode metrics")?;
+    String::from_utf8(buffer).context("Metrics are not valid UTF-8")
+}
+
+/// Serves GET /metrics on `address`
+pub async fn serve(address: &str) -> Result<()> {
+    let listener = TcpListener::bind(address).await
+        .with_context(|| format!("Failed to bind metrics endpoint to {}", address))?;
+
+    let app = Router::new().route("/metrics", get(|| async {
+        match render() {
+            Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body),
+            Err(e) => {
+                warn!("{:#}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], format!("{:#}", e))
+            }
+        }
+    }));
+
+    info!("Metrics available at http://{}/metrics", address);
+
+    axum::serve(listener, app).await
+        .context("Metrics endpoint stopped")
+}
@@ -0,0 +1,315 @@
+use anyhow::{Context, Result};
+use log::{info, error};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::Path,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use crate::access;
+use crate::activity::{self, Progress};
+use crate::api::{self, TransferOptions};
+use crate::approval::PendingTransfer;
+use crate::config::{Config, MetadataConfig};
+use crate::history::{self, HistoryEntry};
+use crate::metadata;
+use crate::storage::{self, IncomingFile, StagingArea};
+use crate::structure;
+use crate::tree::{self, EntryKind, Manifest};
+
+/// Handle SESSION command - receives a batch of files over one connection
+///
+/// The sender authenticates once with SESSION <transfer_id> [<folder>], then
+/// writes a manifest listing every file (same format as TREE, with plain file
+/// names). Size limits are checked for the whole batch before the ACK. The
+/// payloads follow in manifest order and the server answers each one with
+/// `FILE_COMPLETE <index>: <stored name>` or `FILE_FAILED <index>: <error>`,
+/// then ends with a `SESSION_COMPLETE` summary.
+///
+/// With `transactional=true` the files are staged instead, the server sends
+/// `SESSION_STAGED` and waits for the sender to send COMMIT or ABORT.
+pub async fn handle_session_command<S: AsyncRead + AsyncWrite + Unpin>(transfer_id: &str, folder: Option<&str>, options: &TransferOptions, peer: SocketAddr, stream: &mut S) -> Result<String> {
+    info!("Handling SESSION command - transfer_id: {}, folder: {:?}", transfer_id, folder);
+
+    // Load config to verify we can accept this batch
+    let mut config = Config::current()
+        .context("Failed to load config")?;
+
+    // Tokens and upload links narrow the folder and file size limit
+    let link = access::authorize(&mut config, transfer_id, access::PERMISSION_UPLOAD, peer.ip())?;
+
+    // Every attempt ends up in the transfer history, including refused ones
+    let mut attempt = history::Attempt::start(&config, peer, transfer_id, "batch");
+    let active = activity::ActiveTransfer::start(peer, "batch");
+
+    // Ensure target directory exists and get the path
+    let receive_dir = structure::ensure_directory_exists(&config.folder, folder).await
+        .context("Failed to ensure receive directory exists")
+        .inspect_err(|e| attempt.record(attempt.failed("", 0, history::RESULT_FAILED, e)))?;
+
+    let manifest = tree::read_manifest(stream).await
+        .inspect_err(|e| attempt.record(attempt.failed("", 0, history::RESULT_FAILED, e)))?;
+    validate_batch(&manifest)
+        .inspect_err(|e| record_batch_failure(&attempt, &manifest, history::RESULT_FAILED, e))?;
+
+    let first_name = manifest.entries.first().map(|entry| entry.path.as_str()).unwrap_or_default();
+    active.describe(first_name, manifest.entries.len(), manifest.total_size());
+    info!("Incoming batch: {} files ({} bytes)", manifest.entries.len(), manifest.total_size());
+
+    // Check size limits for the whole batch before sending ACK
+    if let Err(e) = tree::check_manifest_limits(&config, &manifest, &receive_dir, link.as_ref()).await {
+        error!("Size limit exceeded: {}", e);
+        record_batch_failure(&attempt, &manifest, history::RESULT_SIZE_LIMIT, &e);
+
+        stream.write_all(api::size_limit_response(&e).as_bytes()).await
+            .context("Failed to send error response")?;
+        stream.write_all(b"\n").await
+            .context("Failed to send newline")?;
+
+        return Err(e);
+    }
+
+    for entry in &manifest.entries {
+        storage::check_conflict(&receive_dir, &entry.path, &config.conflict).await
+            .inspect_err(|e| record_batch_failure(&attempt, &manifest, history::RESULT_REJECTED, e))?;
+    }
+
+    let transfer = PendingTransfer::new(peer, "batch", first_name, manifest.entries.len(), manifest.total_size(), folder);
+    api::require_approval(&config, transfer, stream).await
+        .inspect_err(|e| record_batch_failure(&attempt, &manifest, history::RESULT_REJECTED, e))?;
+    if let Some(link) = &link {
+        link.redeem()
+            .inspect_err(|e| record_batch_failure(&attempt, &manifest, history::RESULT_REJECTED, e))?;
+    }
+
+    let compressed = api::negotiate_compression(&config, options);
+    let mut ack = api::ack_line(compressed).trim_end().to_string();
+    if options.transactional {
+        ack.push_str(" transactional=true");
+    }
+    ack.push('\n');
+    stream.write_all(ack.as_bytes()).await
+        .context("Failed to send ACK")?;
+    active.acknowledged();
+
+    if !options.transactional {
+        let received = receive_batch(stream, &manifest, &receive_dir, compressed, &config.metadata, &config.conflict, None, &mut attempt, &active.progress()).await?;
+        let (succeeded, received_bytes) = (received.len(), received.iter().map(|entry| entry.size).sum::<u64>());
+
+        info!("Batch complete: {}/{} files", succeeded, manifest.entries.len());
+        active.complete();
+
+        return Ok(format!("SESSION_COMPLETE: {}/{} files ({} bytes)", succeeded, manifest.entries.len(), received_bytes));
+    }
+
+    // Transactional batch: receive into a staging directory first
+    let mut staging = StagingArea::create(config.inbox_root()).await?;
+    let staging_dir = staging.path();
+
+    let result = match receive_batch(stream, &manifest, &staging_dir, compressed, &config.metadata, &config.conflict, Some(&mut staging), &mut attempt, &active.progress()).await {
+        Ok(received) => finish_transaction(stream, staging, &receive_dir, &config.conflict, manifest.entries.len(), received, &attempt).await,
+        Err(e) => {
+            staging.discard().await;
+            Err(e)
+        }
+    };
+
+    // Committed files are recorded by finish_transaction, anything else discarded the whole batch
+    match &result {
+        Ok(response) if response.starts_with("SESSION_ABORTED") => {
+            record_batch_failure(&attempt, &manifest, history::RESULT_ABORTED, &anyhow::anyhow!("Aborted by sender"));
+        }
+        Err(e) => record_batch_failure(&attempt, &manifest, history::RESULT_DISCARDED, e),
+        Ok(_) => active.complete(),
+    }
+    result
+}
+
+/// Records every file of a batch that was not received
+fn record_batch_failure(attempt: &history::Attempt, manifest: &Manifest, code: &str, e: &anyhow::Error) {
+    for entry in &manifest.entries {
+        attempt.record(attempt.failed(&entry.path, entry.size, code, e));
+    }
+}
+
+/// Receives every file of the batch into `target_dir`, sending a result line per file
+/// Returns the history entries of the files received; without staging they
+/// are recorded right away, along with the failed files
+/// The `conflict` policy applies once the files reach their final directory
+#[allow(clippy::too_many_arguments)]
+async fn receive_batch<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, manifest: &Manifest, target_dir: &Path, compressed: bool, policy: &MetadataConfig, conflict: &str, mut staging: Option<&mut StagingArea>, attempt: &mut history::Attempt, progress: &Progress) -> Result<Vec<HistoryEntry>> {
+    let mut received = Vec::new();
+    let conflict = if staging.is_some() { storage::CONFLICT_RENAME } else { conflict };
+
+    for (index, entry) in manifest.entries.iter().enumerate() {
+        attempt.restart_timer();
+        progress.file(&entry.path);
+        let file_result = receive_batch_file(stream, target_dir, &entry.path, entry.size, compressed, conflict, progress).await
+            .inspect_err(|e| if staging.is_none() {
+                attempt.record(attempt.failed(&entry.path, entry.size, history::RESULT_FAILED, e));
+            })?;
+
+        let result = match file_result {
+            Ok((stored_name, checksum)) => {
+                info!("Batch file {} received as {}", index, stored_name);
+                metadata::apply(&target_dir.join(&stored_name), &entry.metadata, policy);
+                let line = format!("FILE_COMPLETE {}: {}\n", index, stored_name);
+                let history_entry = attempt.succeeded(&entry.path, &stored_name, entry.size, checksum);
+                match staging.as_deref_mut() {
+                    Some(staging) => staging.add(stored_name),
+                    None => attempt.record(history_entry.clone()),
+                }
+                received.push(history_entry);
+                line
+            }
+            Err(e) => {
+                error!("Batch file {} ({}) failed: {}", index, entry.path, e);
+                if staging.is_none() {
+                    attempt.record(attempt.failed(&entry.path, entry.size, history::RESULT_FAILED, &e));
+                }
+                format!("FILE_FAILED {}: {}\n", index, e)
+            }
+        };
+
+        stream.write_all(result.as_bytes()).await
+            .context("Failed to send file result")?;
+    }
+
+    Ok(received)
+}
+
+/// Waits for COMMIT or ABORT after a transactional batch has been staged
+///
+/// COMMIT moves the whole set into place, but only if every file arrived.
+/// ABORT, any other command or a disconnect discards the staged files.
+/// Committed files are recorded in the history under their final names.
+async fn finish_transaction<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, staging: StagingArea, receive_dir: &Path, conflict: &str, total: usize, received: Vec<HistoryEntry>, attempt: &history::Attempt) -> Result<String> {
+    let succeeded = received.len();
+    let received_bytes: u64 = received.iter().map(|entry| entry.size).sum();
+    let staged = format!("SESSION_STAGED: {}/{} files ({} bytes)\n", succeeded, total, received_bytes);
+    if let Err(e) = stream.write_all(staged.as_bytes()).await {
+        staging.discard().await;
+        return Err(e).context("Failed to send staged summary");
+    }
+
+    let command = match api::read_line(stream).await {
+        Ok(command) => command,
+        Err(e) => {
+            staging.discard().await;
+            return Err(e);
+        }
+    };
+
+    match command.as_deref().map(str::to_uppercase).as_deref() {
+        Some("COMMIT") => {
+            if succeeded != total {
+                staging.discard().await;
+                return Err(anyhow::anyhow!("Cannot commit: {} of {} files failed, batch discarded", total - succeeded, total));
+            }
+
+            let final_names = staging.commit(receive_dir, conflict).await?;
+            for (mut entry, name) in received.into_iter().zip(&final_names) {
+                entry.stored_name = Some(name.clone());
+                attempt.record(entry);
+            }
+            for (index, name) in final_names.iter().enumerate() {
+                stream.write_all(format!("FILE_COMMITTED {}: {}\n", index, name).as_bytes()).await
+                    .context("Failed to send commit result")?;
+            }
+
+            info!("Batch committed: {} files", final_names.len());
+
+            Ok(format!("SESSION_COMPLETE: committed {} files ({} bytes)", final_names.len(), received_bytes))
+        }
+        Some("ABORT") => {
+            staging.discard().await;
+            info!("Batch aborted by sender, {} staged files discarded", succeeded);
+
+            Ok(format!("SESSION_ABORTED: {} files discarded", succeeded))
+        }
+        Some(_) => {
+            staging.discard().await;
+            Err(anyhow::anyhow!("Expected COMMIT or ABORT, batch discarded"))
+        }
+        None => {
+            staging.discard().await;
+            info!("Client disconnected before COMMIT, {} staged files discarded", succeeded);
+
+            Err(anyhow::anyhow!("Client disconnected before COMMIT"))
+        }
+    }
+}
+
+/// Receives one file of the batch
+///
+/// The outer error means the connection is unusable and ends the session;
+/// the inner error is a per-file failure after which the batch continues.
+/// A received file comes back with its stored name and checksum.
+async fn receive_batch_file<S: AsyncRead + Unpin>(stream: &mut S, receive_dir: &Path, filename: &str, file_size: u64, compressed: bool, conflict: &str, progress: &Progress) -> Result<Result<(String, String)>> {
+    let mut incoming = match IncomingFile::create(receive_dir, filename).await {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            // Skip over the payload so the next file starts at the right place
+            api::discard_payload(stream, file_size, compressed).await?;
+            return Ok(Err(e));
+        }
+    };
+    incoming.track(progress.clone());
+
+    if let Err(e) = api::receive_payload(stream, &mut incoming, file_size, compressed).await {
+        incoming.abort().await;
+        return Err(e);
+    }
+
+    let checksum = incoming.checksum();
+    Ok(incoming.finish(conflict).await.map(|stored_name| (stored_name, checksum)))
+}
+
+/// Checks that the manifest only lists plain files with valid names
+fn validate_batch(manifest: &Manifest) -> Result<()> {
+    if manifest.entries.is_empty() {
+        return Err(anyhow::anyhow!("Batch manifest contains no files"));
+    }
+
+    // A second file of the same name would replace the first one in staging
+    let mut seen = HashSet::new();
+    for entry in &manifest.entries {
+        if entry.kind != EntryKind::File {
+            return Err(anyhow::anyhow!("Batch manifest may only contain files: {}", entry.path));
+        }
+        let name = structure::sanitize_filename(&entry.path)?;
+        if !seen.insert(name) {
+            return Err(anyhow::anyhow!("Duplicate manifest entry: {}", entry.path));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(json: &str) -> Manifest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn accepts_batches_of_files() {
+        let batch = manifest(r#"{"entries": [{"path": "a.txt", "kind": "file", "size": 1}, {"path": "b.txt", "kind": "file"}]}"#);
+        assert!(validate_batch(&batch).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_batches() {
+        for json in [
+            r#"{"entries": []}"#,
+            r#"{"entries": [{"path": "a.txt", "kind": "file"}, {"path": "a.txt", "kind": "file"}]}"#,
+            r#"{"entries": [{"path": "dir", "kind": "dir"}]}"#,
+            r#"{"entries": [{"path": "dir/a.txt", "kind": "file"}]}"#,
+            r#"{"entries": [{"path": ".staging", "kind": "file"}]}"#,
+        ] {
+            assert!(validate_batch(&manifest(json)).is_err(), "{} was accepted", json);
+        }
+    }
+}
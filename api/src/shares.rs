@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use crate::config::Config;
+use crate::control;
+use crate::download;
+use crate::links::{self, STATUS_ACTIVE, STATUS_EXPIRED, STATUS_REVOKED, STATUS_USED};
+use crate::structure;
+
+/// A received file published behind a token
+///
+/// Anyone with the token can download `path` until `expires`, up to
+/// `downloads` times, even if the server doesn't allow reads otherwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub token: String,
+    /// Path of the file below the receive folder
+    pub path: String,
+    /// RFC 3339, UTC
+    pub created: String,
+    /// RFC 3339, UTC
+    pub expires: String,
+    /// Number of downloads allowed, 0 for no limit
+    #[serde(default)]
+    pub downloads: u32,
+    #[serde(default)]
+    pub downloaded: u32,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+impl Share {
+    /// One of the links::STATUS_* constants
+    pub fn status(&self) -> &'static str {
+        let expired = DateTime::parse_from_rfc3339(&self.expires)
+            .map_or(true, |expires| expires <= Utc::now());
+        if self.revoked {
+            STATUS_REVOKED
+        } else if self.downloads > 0 && self.downloaded >= self.downloads {
+            STATUS_USED
+        } else if expired {
+            STATUS_EXPIRED
+        } else {
+            STATUS_ACTIVE
+        }
+    }
+
+    /// File name offered to the downloader
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    /// Resolves the shared file for a download of `requested`
+    ///
+    /// `requested` may be empty; otherwise it has to name the shared file.
+    pub fn resolve(&self, config: &Config, requested: &str) -> Result<PathBuf> {
+        let requested = requested.trim().trim_start_matches('/');
+        if !requested.is_empty() && requested != self.path {
+            return Err(anyhow::anyhow!("File not found: {}", requested));
+        }
+        let path = download::resolve_path(config, &self.path)?;
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("File not found: {}", self.path));
+        }
+        Ok(path)
+    }
+
+    /// Counts one download, refusing it if the limit was reached in the meantime
+    ///
+    /// Called when the download starts, right before its ACK.
+    pub fn redeem(&self) -> Result<()> {
+        structure::update_json_list(SHARES_FILE, |shares: &mut Vec<Share>| {
+            let share = shares.iter_mut()
+                .find(|share| share.token == self.token)
+                .context("Share was removed")?;
+            check_status(share)?;
+
+            share.downloaded += 1;
+            info!("Share {} downloaded ({}/{})", links::short_token(&share.token), share.downloaded, share.downloads);
+            Ok(())
+        })
+    }
+}
+
+/// Shares are kept next to transfer.toml
+const SHARES_FILE: &str = "shares.json";
+
+/// Shares the file at `path` (below the receive folder) for `downloads` downloads during the next `lifetime`
+pub fn create(config: &Config, path: &str, lifetime: chrono::Duration, downloads: u32) -> Result<Share> {
+    let relative = structure::sanitize_relative_path(path)?;
+    if !download::resolve_path(config, path)?.is_file() {
+        return Err(anyhow::anyhow!("Not a file: {}", path));
+    }
+
+    let now = Utc::now();
+    let expires = now.checked_add_signed(lifetime).context("Expiry is too far in the future")?;
+    let share = Share {
+        token: uuid::Uuid::new_v4().simple().to_string(),
+        path: relative.to_string_lossy().replace('\\', "/"),
+        created: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        expires: expires.to_rfc3339_opts(SecondsFormat::Secs, true),
+        downloads,
+        downloaded: 0,
+        revoked: false,
+    };
+
+    structure::update_json_list(SHARES_FILE, |shares| {
+        shares.push(share.clone());
+        Ok(())
+    })?;
+
+    Ok(share)
+}
+
+/// All shares, oldest first
+pub fn list() -> Result<Vec<Share>> {
+    load()
+}
+
+/// Revokes the share with `token`; returns false if there is none
+pub fn revoke(token: &str) -> Result<bool> {
+    structure::update_json_list(SHARES_FILE, |shares: &mut Vec<Share>| {
+        let Some(share) = shares.iter_mut().find(|share| share.token == token) else {
+            return Ok(false);
+        };
+        share.revoked = true;
+        Ok(true)
+    })
+}
+
+/// Looks up `token` as a share
+///
+/// Returns None if it isn't one; shares that can't be downloaded anymore are refused.
+pub fn find(token: &str) -> Result<Option<Share>> {
+    let share = load()?.into_iter()
+        .find(|share| control::constant_time_eq(token.as_bytes(), share.token.as_bytes()));
+    let Some(share) = share else {
+        return Ok(None);
+    };
+    if let Err(e) = check_status(&share) {
+        warn!("Refused share {}: {}", links::short_token(&share.token), e);
+        return Err(e);
+    }
+
+    Ok(Some(share))
+}
+
+fn check_status(share: &Share) -> Result<()> {
+    match share.status() {
+        STATUS_ACTIVE => Ok(()),
+        STATUS_EXPIRED => Err(anyhow::anyhow!("Share expired")),
+        STATUS_USED => Err(anyhow::anyhow!("Share reached its download limit")),
+        _ => Err(anyhow::anyhow!("Share was revoked")),
+    }
+}
+
+/// Changes are made with `structure::update_json_list`, which replaces the file in one step
+fn load() -> Result<Vec<Share>> {
+    structure::read_json_list(SHARES_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A receive folder holding docs/report.txt
+    fn receive_folder() -> (tempfile::TempDir, Config) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs").join("report.txt"), "report").unwrap();
+        let mut config = Config::default();
+        config.folder = dir.path().to_string_lossy().to_string();
+        (dir, config)
+    }
+
+    #[test]
+    fn share_can_be_downloaded_until_its_limit() {
+        let (dir, config) = receive_folder();
+        let share = create(&config, "docs/report.txt", chrono::Duration::hours(1), 2).unwrap();
+        assert_eq!(share.path, "docs/report.txt");
+        assert_eq!(share.file_name(), "report.txt");
+
+        let found = find(&share.token).unwrap().unwrap();
+        let path = found.resolve(&config, "").unwrap();
+        assert_eq!(fs::canonicalize(path).unwrap(), fs::canonicalize(dir.path().join("docs").join("report.txt")).unwrap());
+        assert!(found.resolve(&config, "/docs/report.txt").is_ok());
+        assert!(found.resolve(&config, "docs/other.txt").is_err());
+
+        found.redeem().unwrap();
+        found.redeem().unwrap();
+        let error = find(&share.token).unwrap_err().to_string();
+        assert_eq!(error, "Share reached its download limit");
+        assert!(found.redeem().is_err());
+    }
+
+    #[test]
+    fn share_without_limit_keeps_working() {
+        let (_dir, config) = receive_folder();
+        let share = create(&config, "docs/report.txt", chrono::Duration::hours(1), 0).unwrap();
+        for _ in 0..3 {
+            share.redeem().unwrap();
+        }
+        assert!(find(&share.token).unwrap().is_some());
+    }
+
+    #[test]
+    fn share_past_expiry_is_refused() {
+        let (_dir, config) = receive_folder();
+        let share = create(&config, "docs/report.txt", chrono::Duration::seconds(-1), 0).unwrap();
+        assert_eq!(share.status(), STATUS_EXPIRED);
+        let error = find(&share.token).unwrap_err().to_string();
+        assert_eq!(error, "Share expired");
+        assert!(share.redeem().is_err());
+    }
+
+    #[test]
+    fn revoked_share_is_refused() {
+        let (_dir, config) = receive_folder();
+        let share = create(&config, "docs/report.txt", chrono::Duration::hours(1), 0).unwrap();
+        assert!(revoke(&share.token).unwrap());
+        let error = find(&share.token).unwrap_err().to_string();
+        assert_eq!(error, "Share was revoked");
+        assert!(find("no-such-share").unwrap().is_none());
+    }
+
+    #[test]
+    fn only_files_in_the_receive_folder_can_be_shared() {
+        let (_dir, config) = receive_folder();
+        assert!(create(&config, "docs", chrono::Duration::hours(1), 0).is_err());
+        assert!(create(&config, "docs/missing.txt", chrono::Duration::hours(1), 0).is_err());
+        assert!(create(&config, "../etc/passwd", chrono::Duration::hours(1), 0).is_err());
+    }
+}
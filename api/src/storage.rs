@@ -0,0 +1,715 @@
+use anyhow::{Context, Result};
+use log::{error, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::{fs, io::{AsyncReadExt, AsyncWriteExt}};
+use crate::activity::{self, Progress};
+use crate::crypto::{self, KeyResolver, StreamDecryptor, StreamEncryptor};
+use crate::metrics;
+
+/// A file being received into a transfer directory
+///
+/// Data is written to a hidden temporary file next to the destination and
+/// encrypted on the fly when at-rest encryption is enabled. The file only
+/// appears under its final name once `finish` is called.
+pub struct IncomingFile {
+    file: fs::File,
+    temp_path: PathBuf,
+    transfer_dir: PathBuf,
+    filename: String,
+    encryptor: Option<StreamEncryptor>,
+    hasher: Sha256,
+    progress: Option<Progress>,
+}
+
+impl IncomingFile {
+    /// Creates the temporary file for an incoming transfer
+    pub async fn create(transfer_dir: &Path, filename: &str) -> Result<Self> {
+        let temp_path = transfer_dir.join(format!(".{}.{}.part", filename, uuid::Uuid::new_v4()));
+        let mut file = fs::File::create(&temp_path).await
+            .with_context(|| format!("Failed to create temporary file: {}", temp_path.display()))?;
+
+        let encryptor = match crypto::active_key() {
+            Some(key) => {
+                let (encryptor, header) = StreamEncryptor::new(&key);
+                file.write_all(&header).await
+                    .context("Failed to write encryption header")?;
+                Some(encryptor)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            file,
+            temp_path,
+            transfer_dir: transfer_dir.to_path_buf(),
+            filename: filename.to_string(),
+            encryptor,
+            hasher: Sha256::new(),
+            progress: None,
+        })
+    }
+
+    /// Reports the bytes written to an active transfer
+    pub fn track(&mut self, progress: Progress) {
+        self.progress = Some(progress);
+    }
+
+    /// Appends received data to the file
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.hasher.update(data);
+        if let Some(progress) = &self.progress {
+            progress.add(data.len());
+        }
+        match self.encryptor.as_mut() {
+            Some(encryptor) => {
+                let sealed = encryptor.update(data)?;
+                self.file.write_all(&sealed).await
+            }
+            None => self.file.write_all(data).await,
+        }
+        .with_context(|| format!("Failed to write file: {}", self.temp_path.display()))
+    }
+
+    /// SHA-256 of the data written so far (the original contents, before encryption)
+    pub fn checksum(&self) -> String {
+        hex::encode(self.hasher.clone().finalize())
+    }
+
+    /// Completes the file and moves it into the transfer directory, resolving
+    /// a name that is taken according to the `conflict` policy
+    /// Returns the name the file was saved as
+    pub async fn finish(mut self, conflict: &str) -> Result<String> {
+        self.complete().await?;
+
+        let stored_name = match stored_name(&self.transfer_dir, &self.filename, conflict).await {
+            Ok(stored_name) => stored_name,
+            Err(e) => {
+                self.abort().await;
+                return Err(e);
+            }
+        };
+        let file_path = self.transfer_dir.join(&stored_name);
+        fs::rename(&self.temp_path, &file_path).await
+            .with_context(|| format!("Failed to move received file to {}", file_path.display()))?;
+
+        Ok(stored_name)
+    }
+
+    /// Completes the file and moves it to an exact path
+    pub async fn finish_at(mut self, file_path: &Path) -> Result<()> {
+        self.complete().await?;
+
+        fs::rename(&self.temp_path, file_path).await
+            .with_context(|| format!("Failed to move received file to {}", file_path.display()))
+    }
+
+    /// Writes the final encrypted record (if any) and flushes the file to disk
+    async fn complete(&mut self) -> Result<()> {
+        if let Some(progress) = &self.progress {
+            progress.set_state(activity::STATE_VERIFYING);
+        }
+        if let Some(encryptor) = self.encryptor.take() {
+            let sealed = encryptor.finalize()?;
+            self.file.write_all(&sealed).await
+                .context("Failed to write final encrypted record")?;
+        }
+        self.file.flush().await
+            .context("Failed to flush received file")?;
+        self.file.sync_all().await
+            .context("Failed to sync received file")
+    }
+
+    /// Discards the partially received file
+    pub async fn abort(self) {
+        drop(self.file);
+        if let Err(e) = fs::remove_file(&self.temp_path).await {
+            warn!("Failed to remove partial file {}: {}", self.temp_path.display(), e);
+        }
+    }
+}
+
+/// Size of the chunks returned when reading an unencrypted stored file
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A stored file being read back for a download
+///
+/// Encrypted files are decrypted record by record, so `size` and the data
+/// returned by `read_chunk` are always the original contents.
+pub struct OutgoingFile {
+    file: fs::File,
+    size: u64,
+    decryptor: Option<StreamDecryptor>,
+}
+
+impl OutgoingFile {
+    /// Opens a stored file, resolving its key if it is encrypted
+    pub async fn open(path: &Path, keys: &mut KeyResolver<'_>) -> Result<Self> {
+        let mut file = fs::File::open(path).await
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+        let stored_size = file.metadata().await
+            .with_context(|| format!("Failed to read file metadata: {}", path.display()))?
+            .len();
+
+        if !crypto::is_encrypted_file(path)? {
+            return Ok(Self { file, size: stored_size, decryptor: None });
+        }
+
+        let mut header = [0u8; crypto::HEADER_SIZE];
+        file.read_exact(&mut header).await
+            .context("File is too short to be an encrypted transfer file")?;
+        let decryptor = StreamDecryptor::new(keys, &header)
+            .with_context(|| format!("Cannot decrypt {}", path.display()))?;
+
+        Ok(Self {
+            file,
+            size: crypto::plaintext_size(stored_size)?,
+            decryptor: Some(decryptor),
+        })
+    }
+
+    /// Size of the file contents
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the next chunk of the contents, or None at the end of the file
+    pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+            let n = self.file.read(&mut buffer).await
+                .context("Failed to read file")?;
+            buffer.truncate(n);
+            return Ok((n > 0).then_some(buffer));
+        };
+
+        if decryptor.is_finished() {
+            return Ok(None);
+        }
+
+        let mut len_bytes = [0u8; 4];
+        self.file.read_exact(&mut len_bytes).await
+            .context("Encrypted file is truncated")?;
+        let mut record = vec![0u8; StreamDecryptor::record_len(len_bytes)?];
+        self.file.read_exact(&mut record).await
+            .context("Encrypted file is truncated")?;
+
+        decryptor.decrypt_record(&record).map(Some)
+    }
+}
+
+/// True for files and directories the server keeps for itself inside the
+/// transfer folder (partial uploads `.<name>.<uuid>.part` and staged batches)
+pub fn is_internal_name(name: &str) -> bool {
+    (name.starts_with('.') && name.ends_with(".part")) || name == STAGING_DIR
+}
+
+/// Conflict policies: what happens when a received name is already taken
+/// Store the new file under a numbered name
+pub const CONFLICT_RENAME: &str = "rename";
+/// Replace the existing file (trees are merged into the existing directory)
+pub const CONFLICT_OVERWRITE: &str = "overwrite";
+/// Refuse the transfer before any data is sent
+pub const CONFLICT_REJECT: &str = "reject";
+
+/// Refuses a transfer whose name is taken if the policy is to reject
+/// Called before the ACK, so the sender doesn't send data that can't be stored
+pub async fn check_conflict(transfer_dir: &Path, filename: &str, conflict: &str) -> Result<()> {
+    if conflict == CONFLICT_REJECT && fs::try_exists(transfer_dir.join(filename)).await.unwrap_or(false) {
+        metrics::rejected(metrics::REASON_CONFLICT);
+        return Err(anyhow::anyhow!("File already exists: {}", filename));
+    }
+    Ok(())
+}
+
+/// Name a received file or tree is stored under according to the `conflict` policy
+pub async fn stored_name(transfer_dir: &Path, filename: &str, conflict: &str) -> Result<String> {
+    match conflict {
+        CONFLICT_OVERWRITE => Ok(filename.to_string()),
+        CONFLICT_REJECT => {
+            check_conflict(transfer_dir, filename, conflict).await?;
+            Ok(filename.to_string())
+        }
+        _ => Ok(generate_unique_filename(transfer_dir, filename).await),
+    }
+}
+
+/// Generate a unique filename if the original already exists
+pub async fn generate_unique_filename(transfer_dir: &Path, filename: &str) -> String {
+    let file_path = transfer_dir.join(filename);
+
+    // If file doesn't exist, use original name
+    if !fs::try_exists(&file_path).await.unwrap_or(false) {
+        return filename.to_string();
+    }
+
+    // Split filename into name and extension
+    let path = Path::new(filename);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(filename);
+    let extension = path.extension().and_then(|s| s.to_str());
+
+    // Try incrementing numbers until we find a unique name
+    let mut counter = 1;
+    loop {
+        let new_filename = if let Some(ext) = extension {
+            format!("{}{}.{}", stem, counter, ext)
+        } else {
+            format!("{}{}", stem, counter)
+        };
+
+        let new_file_path = transfer_dir.join(&new_filename);
+        if !fs::try_exists(&new_file_path).await.unwrap_or(false) {
+            return new_filename;
+        }
+
+        counter += 1;
+
+        // Safety check to prevent infinite loop
+        if counter > 9999 {
+            // Fallback to timestamp-based naming
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            return if let Some(ext) = extension {
+                format!("{}{}.{}", stem, timestamp, ext)
+            } else {
+                format!("{}{}", stem, timestamp)
+            };
+        }
+    }
+}
+
+/// Name of the directory (inside the base transfer folder) holding staged batches
+pub const STAGING_DIR: &str = ".staging";
+
+/// Inside a staging area: the received files, the commit journal, the marker
+/// written once a commit is complete and the files replaced by the commit
+const STAGED_FILES: &str = "files";
+const JOURNAL_FILE: &str = "journal";
+const COMMITTED_MARKER: &str = "committed";
+const REPLACED_DIR: &str = "replaced";
+
+/// Staging directory for a transactional batch
+///
+/// Files are received here first and only moved into the target directory on
+/// commit. Discarding (or never committing) removes everything.
+///
+/// Every step of a commit is written to a journal before it is taken, so a
+/// commit interrupted by a crash is rolled back by `cleanup_staging` at the
+/// next start. Files replaced with the overwrite policy are kept until the
+/// commit is complete, so they can be put back.
+pub struct StagingArea {
+    root: PathBuf,
+    /// Staged file names, in the order they were received
+    files: Vec<String>,
+}
+
+/// A step of a commit, recorded in the journal before it is taken
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Step {
+    /// `target` was created as an empty directory
+    Created { target: PathBuf },
+    /// `staged` was moved to `target`, after moving what was there to `replaced`
+    Moved { staged: PathBuf, target: PathBuf, replaced: Option<PathBuf> },
+}
+
+/// Journal of a commit in progress, one JSON step per line
+struct Journal {
+    root: PathBuf,
+    file: std::fs::File,
+    steps: Vec<Step>,
+}
+
+impl Journal {
+    fn create(root: &Path) -> Result<Self> {
+        let path = root.join(JOURNAL_FILE);
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("Failed to create commit journal: {}", path.display()))?;
+        Ok(Self { root: root.to_path_buf(), file, steps: Vec::new() })
+    }
+
+    /// Writes `step` to disk before it is taken
+    fn record(&mut self, step: Step) -> Result<()> {
+        let mut line = serde_json::to_vec(&step).context("Failed to serialize commit step")?;
+        line.push(b'\n');
+        std::io::Write::write_all(&mut self.file, &line)
+            .and_then(|()| self.file.sync_data())
+            .context("Failed to write commit journal")?;
+        self.steps.push(step);
+        Ok(())
+    }
+
+    /// Moves `staged` to `target`; an existing file at `target` is kept aside while `replace` is set
+    async fn move_into_place(&mut self, staged: &Path, target: &Path, replace: bool) -> Result<()> {
+        let replaced = if replace && fs::try_exists(target).await.unwrap_or(false) {
+            Some(self.root.join(REPLACED_DIR).join(self.steps.len().to_string()))
+        } else {
+            None
+        };
+        self.record(Step::Moved { staged: staged.to_path_buf(), target: target.to_path_buf(), replaced: replaced.clone() })?;
+
+        if let Some(replaced) = &replaced {
+            fs::create_dir_all(self.root.join(REPLACED_DIR)).await
+                .context("Failed to create directory for replaced files")?;
+            fs::rename(target, replaced).await
+                .with_context(|| format!("Failed to set aside {}", target.display()))?;
+        }
+        fs::rename(staged, target).await
+            .with_context(|| format!("Failed to move received file to {}", target.display()))
+    }
+
+    /// Creates the directory `target` unless it exists
+    async fn create_dir(&mut self, target: &Path) -> Result<()> {
+        if fs::try_exists(target).await.unwrap_or(false) {
+            return Ok(());
+        }
+        self.record(Step::Created { target: target.to_path_buf() })?;
+        fs::create_dir(target).await
+            .with_context(|| format!("Failed to create directory: {}", target.display()))
+    }
+
+    /// Marks the commit as complete, so it is never rolled back
+    fn complete(&self) -> Result<()> {
+        let marker = self.root.join(COMMITTED_MARKER);
+        std::fs::File::create(&marker)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Failed to write {}", marker.display()))
+    }
+
+    fn roll_back(self) {
+        roll_back(self.steps);
+    }
+}
+
+/// Undoes the steps of an incomplete commit, last step first
+fn roll_back(steps: Vec<Step>) {
+    for step in steps.into_iter().rev() {
+        match step {
+            Step::Created { target } => {
+                // Only removed if nothing else was put there in the meantime
+                let _ = std::fs::remove_dir(&target);
+            }
+            Step::Moved { staged, target, replaced } => {
+                if !staged.exists() && target.exists() && let Err(e) = std::fs::rename(&target, &staged) {
+                    warn!("Failed to roll back {}: {}", target.display(), e);
+                    continue;
+                }
+                if let Some(replaced) = replaced && replaced.exists() && let Err(e) = std::fs::rename(&replaced, &target) {
+                    warn!("Failed to restore {}: {}", target.display(), e);
+                }
+            }
+        }
+    }
+}
+
+impl StagingArea {
+    /// Creates a fresh staging directory below the base transfer folder
+    pub async fn create(base_path: &str) -> Result<Self> {
+        let root = PathBuf::from(base_path)
+            .join(STAGING_DIR)
+            .join(uuid::Uuid::new_v4().to_string());
+        let dir = root.join(STAGED_FILES);
+        fs::create_dir_all(&dir).await
+            .with_context(|| format!("Failed to create staging directory: {}", dir.display()))?;
+
+        Ok(Self { root, files: Vec::new() })
+    }
+
+    /// Directory files of the batch are received into
+    pub fn path(&self) -> PathBuf {
+        self.root.join(STAGED_FILES)
+    }
+
+    /// Records a file that was received into the staging directory
+    pub fn add(&mut self, staged_name: String) {
+        self.files.push(staged_name);
+    }
+
+    /// Moves every staged file into the target directory, resolving names
+    /// that are taken according to the `conflict` policy
+    /// If any move fails, the files already moved are taken back out so the
+    /// target directory never ends up with part of the batch
+    /// Returns the final names in the order the files were staged
+    pub async fn commit(mut self, target_dir: &Path, conflict: &str) -> Result<Vec<String>> {
+        let files = std::mem::take(&mut self.files);
+        let dir = self.path();
+        let mut journal = Journal::create(&self.root)?;
+        let mut final_names = Vec::with_capacity(files.len());
+
+        for staged_name in &files {
+            let moved = async {
+                let final_name = stored_name(target_dir, staged_name, conflict).await?;
+                journal.move_into_place(&dir.join(staged_name), &target_dir.join(&final_name), conflict == CONFLICT_OVERWRITE).await?;
+                Ok::<_, anyhow::Error>(final_name)
+            }.await;
+
+            match moved {
+                Ok(final_name) => final_names.push(final_name),
+                Err(e) => {
+                    journal.roll_back();
+                    self.discard().await;
+                    return Err(e).with_context(|| format!("Failed to move {} into place", staged_name));
+                }
+            }
+        }
+
+        self.finish(journal).await?;
+        Ok(final_names)
+    }
+
+    /// Moves the tree received into the staging directory as `name` into the
+    /// target directory, resolving a taken name according to the `conflict` policy
+    /// A new tree is moved in one rename; with the overwrite policy an existing
+    /// directory is merged, replacing files of the same name and keeping the rest
+    /// Returns the name the tree was stored as
+    pub async fn commit_tree(self, name: &str, target_dir: &Path, conflict: &str) -> Result<String> {
+        let mut journal = Journal::create(&self.root)?;
+        let result = async {
+            let root_name = stored_name(target_dir, name, conflict).await?;
+            let root = target_dir.join(&root_name);
+            if fs::try_exists(&root).await.unwrap_or(false) {
+                merge_tree(&mut journal, &self.path().join(name), &root).await?;
+            } else {
+                journal.move_into_place(&self.path().join(name), &root, false).await?;
+            }
+            Ok(root_name)
+        }.await;
+
+        match result {
+            Ok(root_name) => {
+                self.finish(journal).await?;
+                Ok(root_name)
+            }
+            Err(e) => {
+                journal.roll_back();
+                self.discard().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Marks the commit as complete and removes the staging directory
+    async fn finish(self, journal: Journal) -> Result<()> {
+        if let Err(e) = journal.complete() {
+            // Without the marker a restart would roll the commit back, so undo it now
+            journal.roll_back();
+            self.discard().await;
+            return Err(e);
+        }
+        self.discard().await;
+        Ok(())
+    }
+
+    /// Removes the staging directory and everything in it
+    pub async fn discard(self) {
+        if let Err(e) = fs::remove_dir_all(&self.root).await {
+            warn!("Failed to remove staging directory {}: {}", self.root.display(), e);
+        }
+    }
+}
+
+/// Moves everything below `source` into the existing directory `target`, replacing files
+async fn merge_tree(journal: &mut Journal, source: &Path, target: &Path) -> Result<()> {
+    let mut pending = vec![(source.to_path_buf(), target.to_path_buf())];
+    while let Some((source_dir, target_dir)) = pending.pop() {
+        journal.create_dir(&target_dir).await?;
+
+        let mut entries = fs::read_dir(&source_dir).await
+            .with_context(|| format!("Failed to read staged directory: {}", source_dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let target_path = target_dir.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                pending.push((entry.path(), target_path));
+            } else {
+                journal.move_into_place(&entry.path(), &target_path, true).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Steps recorded in the journal of the staging area at `root`
+fn read_journal(root: &Path) -> Result<Vec<Step>> {
+    let path = root.join(JOURNAL_FILE);
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // A crash while writing can leave the last line incomplete; that step was never taken
+    let lines: Vec<&str> = content.lines().collect();
+    let mut steps = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(step) => steps.push(step),
+            Err(_) if i + 1 == lines.len() => break,
+            Err(e) => return Err(e).with_context(|| format!("Corrupt step {} in {}", i + 1, path.display())),
+        }
+    }
+    Ok(steps)
+}
+
+/// Finishes or rolls back commits interrupted by a crash and removes the
+/// staging directories left behind by a previous run
+///
+/// A commit with the complete marker is kept, any other is undone; batches
+/// that were never committed are discarded. A staging area whose journal
+/// can't be read is logged and left in place for the user to look at.
+pub fn cleanup_staging(base_path: &str) -> Result<()> {
+    let staging = PathBuf::from(base_path).join(STAGING_DIR);
+    if !staging.exists() {
+        return Ok(());
+    }
+
+    let areas = std::fs::read_dir(&staging)
+        .with_context(|| format!("Failed to read staging directory: {}", staging.display()))?;
+    let mut kept = false;
+    for area in areas {
+        let root = area.context("Failed to read staging directory entry")?.path();
+        if root.join(JOURNAL_FILE).exists() && !root.join(COMMITTED_MARKER).exists() {
+            match read_journal(&root) {
+                Ok(steps) => {
+                    warn!("Rolling back a commit interrupted in {}", root.display());
+                    roll_back(steps);
+                }
+                Err(e) => {
+                    error!("Leaving {} in place, its commit can't be rolled back: {:#}", root.display(), e);
+                    kept = true;
+                    continue;
+                }
+            }
+        }
+        if let Err(e) = std::fs::remove_dir_all(&root) {
+            warn!("Failed to remove stale staging directory {}: {}", root.display(), e);
+            kept = true;
+        }
+    }
+
+    if !kept {
+        std::fs::remove_dir(&staging)
+            .with_context(|| format!("Failed to remove stale staging directory: {}", staging.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn stage(base: &Path, files: &[(&str, &str)]) -> StagingArea {
+        let mut staging = StagingArea::create(base.to_str().unwrap()).await.unwrap();
+        for (name, content) in files {
+            std::fs::write(staging.path().join(name), content).unwrap();
+            staging.add(name.to_string());
+        }
+        staging
+    }
+
+    #[tokio::test]
+    async fn commit_moves_files_and_removes_staging() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::write(base.join("a.txt"), "old").unwrap();
+
+        let staging = stage(base, &[("a.txt", "new"), ("b.txt", "b")]).await;
+        let names = staging.commit(base, CONFLICT_RENAME).await.unwrap();
+
+        assert_eq!(names, ["a1.txt", "b.txt"]);
+        assert_eq!(std::fs::read_to_string(base.join("a.txt")).unwrap(), "old");
+        assert_eq!(std::fs::read_to_string(base.join("a1.txt")).unwrap(), "new");
+        assert!(std::fs::read_dir(base.join(STAGING_DIR)).unwrap().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn interrupted_commit_is_rolled_back_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::write(base.join("a.txt"), "old").unwrap();
+
+        // Take the first two steps of an overwriting commit and "crash"
+        let staging = stage(base, &[("a.txt", "new"), ("b.txt", "b")]).await;
+        let mut journal = Journal::create(&staging.root).unwrap();
+        journal.move_into_place(&staging.path().join("a.txt"), &base.join("a.txt"), true).await.unwrap();
+        journal.move_into_place(&staging.path().join("b.txt"), &base.join("b.txt"), true).await.unwrap();
+        drop(journal);
+        assert_eq!(std::fs::read_to_string(base.join("a.txt")).unwrap(), "new");
+
+        cleanup_staging(base.to_str().unwrap()).unwrap();
+
+        assert_eq!(std::fs::read_to_string(base.join("a.txt")).unwrap(), "old");
+        assert!(!base.join("b.txt").exists());
+        assert!(!base.join(STAGING_DIR).exists());
+    }
+
+    #[tokio::test]
+    async fn completed_commit_is_kept_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let staging = stage(base, &[("a.txt", "new")]).await;
+        let mut journal = Journal::create(&staging.root).unwrap();
+        journal.move_into_place(&staging.path().join("a.txt"), &base.join("a.txt"), true).await.unwrap();
+        journal.complete().unwrap();
+
+        cleanup_staging(base.to_str().unwrap()).unwrap();
+
+        assert_eq!(std::fs::read_to_string(base.join("a.txt")).unwrap(), "new");
+        assert!(!base.join(STAGING_DIR).exists());
+    }
+
+    #[tokio::test]
+    async fn interrupted_tree_merge_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::create_dir_all(base.join("proj")).unwrap();
+        std::fs::write(base.join("proj/keep.txt"), "keep").unwrap();
+
+        let staging = stage(base, &[]).await;
+        std::fs::create_dir_all(staging.path().join("proj/sub")).unwrap();
+        std::fs::write(staging.path().join("proj/keep.txt"), "replaced").unwrap();
+        std::fs::write(staging.path().join("proj/sub/new.txt"), "new").unwrap();
+        let mut journal = Journal::create(&staging.root).unwrap();
+        merge_tree(&mut journal, &staging.path().join("proj"), &base.join("proj")).await.unwrap();
+        drop(journal);
+
+        cleanup_staging(base.to_str().unwrap()).unwrap();
+
+        assert_eq!(std::fs::read_to_string(base.join("proj/keep.txt")).unwrap(), "keep");
+        assert!(!base.join("proj/sub").exists());
+    }
+
+    #[tokio::test]
+    async fn corrupt_journal_is_left_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let broken = stage(base, &[("a.txt", "a")]).await;
+        std::fs::write(broken.root.join(JOURNAL_FILE), "not json\n{}\n").unwrap();
+        let stale = stage(base, &[("b.txt", "b")]).await;
+
+        cleanup_staging(base.to_str().unwrap()).unwrap();
+
+        assert!(broken.root.join(JOURNAL_FILE).exists());
+        assert!(!stale.root.exists());
+    }
+
+    #[test]
+    fn journal_may_end_with_an_incomplete_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let step = serde_json::to_string(&Step::Created { target: base.join("x") }).unwrap();
+        std::fs::write(base.join(JOURNAL_FILE), format!("{}\n{{\"created\":", step)).unwrap();
+        assert_eq!(read_journal(base).unwrap().len(), 1);
+
+        std::fs::write(base.join(JOURNAL_FILE), format!("{{\"created\":\n{}\n", step)).unwrap();
+        assert!(read_journal(base).is_err());
+    }
+
+    #[test]
+    fn internal_names() {
+        assert!(is_internal_name(".staging"));
+        assert!(is_internal_name(".report.pdf.0b7e.part"));
+        assert!(!is_internal_name("report.pdf"));
+        assert!(!is_internal_name("staging"));
+        assert!(!is_internal_name("video.part"));
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
     env,
-    fs,
-    path::PathBuf,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
 };
+use crate::storage;
 
 /// Creates all necessary directory structures for the application
 pub fn create_directory_structure() -> Result<()> {
@@ -47,6 +50,7 @@ fn create_windows_directories() -> Result<()> {
 }
 
 /// Returns the AppData config directory path
+#[cfg(not(test))]
 pub fn get_config_directory() -> Result<PathBuf> {
     let os = env::consts::OS;
     
@@ -75,12 +79,126 @@ pub fn get_config_directory() -> Result<PathBuf> {
     Ok(config_path)
 }
 
+/// Tests keep bans.json, links.json and the like in a temporary directory shared by the whole run
+#[cfg(test)]
+pub fn get_config_directory() -> Result<PathBuf> {
+    static DIRECTORY: once_cell::sync::Lazy<tempfile::TempDir> = once_cell::sync::Lazy::new(|| tempfile::tempdir().unwrap());
+    Ok(DIRECTORY.path().to_path_buf())
+}
+
+/// Reads a list kept as JSON in the config directory, empty if there is no file yet
+pub fn read_json_list<T: DeserializeOwned>(file_name: &str) -> Result<Vec<T>> {
+    let path = get_config_directory()?.join(file_name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Replaces a JSON list in the config directory in one step, so readers never see a partial file
+pub fn write_json_list<T: Serialize>(file_name: &str, items: &[T]) -> Result<()> {
+    let path = get_config_directory()?.join(file_name);
+    let content = serde_json::to_string_pretty(items)
+        .with_context(|| format!("Failed to serialize {}", file_name))?;
+    write_atomic(&path, content.as_bytes())
+}
+
+/// Reads a JSON list in the config directory, lets `update` change it and writes it back
+///
+/// A lock on `<file_name>.lock` is held meanwhile, so the server and CLI
+/// commands running in other processes never lose each other's changes.
+/// Nothing is written if `update` fails.
+pub fn update_json_list<T, R>(file_name: &str, update: impl FnOnce(&mut Vec<T>) -> Result<R>) -> Result<R>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let lock_path = get_config_directory()?.join(format!("{}.lock", file_name));
+    let lock = File::options().create(true).truncate(false).write(true).open(&lock_path)
+        .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+    lock.lock()
+        .with_context(|| format!("Failed to lock {}", lock_path.display()))?;
+
+    let mut items = read_json_list(file_name)?;
+    let result = update(&mut items)?;
+    write_json_list(file_name, &items)?;
+    Ok(result)
+}
+
+/// Replaces `path` in one step, so readers never see a partial file
+///
+/// The content is written to a uniquely named file next to `path` and renamed
+/// over it, so concurrent writers never share a temporary file either.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let name = path.file_name()
+        .with_context(|| format!("Not a file path: {}", path.display()))?
+        .to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", name, uuid::Uuid::new_v4()));
+
+    let written = File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .with_context(|| format!("Failed to write {}", temp_path.display()))
+        .and_then(|_| fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to replace {}", path.display())));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Validates a relative path received from a sender
+/// Rejects absolute paths, drive prefixes and `..` components so the result
+/// always stays inside the directory it is joined to
+pub fn sanitize_relative_path(path: &str) -> Result<PathBuf> {
+    if path.starts_with('/') || path.starts_with('\\') {
+        return Err(anyhow::anyhow!("Path must be relative: {}", path));
+    }
+    
+    let mut clean = PathBuf::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(anyhow::anyhow!("Path must not contain '..': {}", path)),
+            // Drive prefixes and alternate data streams on Windows
+            c if cfg!(windows) && c.contains(':') => return Err(anyhow::anyhow!("Path must not contain ':': {}", path)),
+            c if storage::is_internal_name(c) => return Err(anyhow::anyhow!("Path uses a name reserved by the server: {}", path)),
+            c => clean.push(c),
+        }
+    }
+    
+    if clean.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!("Path is empty"));
+    }
+    
+    Ok(clean)
+}
+
+/// Validates a file name received from a sender (a single path component)
+pub fn sanitize_filename(filename: &str) -> Result<&str> {
+    if filename.is_empty() || filename == "." || filename == ".." || filename.contains(['/', '\\'])
+        || (cfg!(windows) && filename.contains(':')) {
+        return Err(anyhow::anyhow!("Invalid file name: {}", filename));
+    }
+    // Staging areas and partial files share the folder with received files
+    if storage::is_internal_name(filename) {
+        return Err(anyhow::anyhow!("File name is reserved by the server: {}", filename));
+    }
+    
+    Ok(filename)
+}
+
 /// Ensures that a directory exists, creating it if necessary
-/// This function handles both the base transfer directory and optional subfolders
+/// This function handles both the base transfer directory and optional subfolders,
+/// which may be nested (e.g. "projects/2024") but never escape the base directory
 pub async fn ensure_directory_exists(base_path: &str, subfolder: Option<&str>) -> Result<PathBuf> {
     let target_dir = if let Some(folder) = subfolder {
         // Use specified subfolder within the base directory
-        PathBuf::from(base_path).join(folder)
+        PathBuf::from(base_path).join(sanitize_relative_path(folder)?)
     } else {
         // Use the base directory directly
         PathBuf::from(base_path)
@@ -93,4 +211,52 @@ pub async fn ensure_directory_exists(base_path: &str, subfolder: Option<&str>) -
     }
     
     Ok(target_dir)
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_relative_paths_inside() {
+        assert_eq!(sanitize_relative_path("a/b/c.txt").unwrap(), Path::new("a").join("b").join("c.txt"));
+        assert_eq!(sanitize_relative_path("a\\b").unwrap(), Path::new("a").join("b"));
+        assert_eq!(sanitize_relative_path("./a//./b/").unwrap(), Path::new("a").join("b"));
+        assert_eq!(sanitize_relative_path("..a/b..").unwrap(), Path::new("..a").join("b.."));
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        for path in ["/etc/passwd", "\\Windows", "../x", "a/../../x", "a\\..\\x", "..", "", ".", "./", "//"] {
+            assert!(sanitize_relative_path(path).is_err(), "{:?} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn rejects_internal_names() {
+        for path in [".staging", "a/.staging/b", ".report.pdf.part", "a/.x.part"] {
+            assert!(sanitize_relative_path(path).is_err(), "{:?} was accepted", path);
+        }
+        for name in [".staging", ".report.pdf.part"] {
+            assert!(sanitize_filename(name).is_err(), "{:?} was accepted", name);
+        }
+        assert!(sanitize_filename("report.part").is_ok());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn rejects_drive_prefixes_and_streams() {
+        for path in ["C:\\x", "C:x", "a/file.txt:stream"] {
+            assert!(sanitize_relative_path(path).is_err(), "{:?} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn checks_file_names() {
+        assert_eq!(sanitize_filename("report.pdf").unwrap(), "report.pdf");
+        assert_eq!(sanitize_filename(".hidden").unwrap(), ".hidden");
+        for name in ["", ".", "..", "a/b", "a\\b", "/"] {
+            assert!(sanitize_filename(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{Context, Result};
+use log::{info, warn, error};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::access;
+use crate::activity::{self, Progress};
+use crate::api::{self, TransferOptions};
+use crate::approval::PendingTransfer;
+use crate::config::{Config, MetadataConfig};
+use crate::history;
+use crate::links::Link;
+use crate::metadata::{self, FileMetadata};
+use crate::storage::{self, IncomingFile, StagingArea};
+use crate::structure;
+
+/// Largest manifest accepted from a sender
+const MAX_MANIFEST_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Kind of a manifest entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// A file or directory in a tree transfer, relative to the tree root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+}
+
+/// Manifest sent before the entries of a directory tree
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Builds a manifest for a local directory (symlinks are skipped)
+    pub fn from_directory(root: &Path) -> Result<Self> {
+        let mut manifest = Manifest::default();
+        collect_entries(root, root, &mut manifest.entries)?;
+        Ok(manifest)
+    }
+
+    /// Total size of all files in the manifest, saturating at u64::MAX for logging
+    pub fn total_size(&self) -> u64 {
+        self.files().fold(0, |total, e| total.saturating_add(e.size))
+    }
+
+    /// Total size of all files in the manifest, or an error if it doesn't fit in a u64
+    pub fn checked_total_size(&self) -> Result<u64> {
+        self.files()
+            .try_fold(0u64, |total, e| total.checked_add(e.size))
+            .context("Total size of the manifest is too large")
+    }
+
+    /// Iterates over the file entries in transfer order
+    pub fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.iter().filter(|e| e.kind == EntryKind::File)
+    }
+}
+
+/// Recursively adds the entries below `dir` in a stable order
+fn collect_entries(root: &Path, dir: &Path, entries: &mut Vec<ManifestEntry>) -> Result<()> {
+    let mut children: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .collect::<std::io::Result<_>>()
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        let path = child.path();
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+
+        let kind = if metadata.is_dir() {
+            EntryKind::Dir
+        } else if metadata.is_file() {
+            EntryKind::File
+        } else {
+            warn!("Skipping {} (not a regular file or directory)", path.display());
+            continue;
+        };
+
+        // Manifest paths always use forward slashes
+        let relative = path.strip_prefix(root)
+            .context("Entry outside of tree root")?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        entries.push(ManifestEntry {
+            path: relative,
+            kind,
+            size: if kind == EntryKind::File { metadata.len() } else { 0 },
+            metadata: FileMetadata::from_path(&path),
+        });
+
+        if kind == EntryKind::Dir {
+            collect_entries(root, &path, entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle TREE command - receives a directory tree described by a manifest
+///
+/// After the command the sender writes the manifest length (8 bytes, big-endian)
+/// and the manifest as JSON. Once the server answers ACK, the contents of every
+/// file entry follow in manifest order, each exactly `size` bytes (or as
+/// compressed frames when compression was agreed).
+pub async fn handle_tree_command<S: AsyncRead + AsyncWrite + Unpin>(transfer_id: &str, name: &str, folder: Option<&str>, options: &TransferOptions, peer: SocketAddr, stream: &mut S) -> Result<String> {
+    info!("Handling TREE command - transfer_id: {}, directory: {}, folder: {:?}", transfer_id, name, folder);
+
+    // Load config to verify we can accept this transfer
+    let mut config = Config::current()
+        .context("Failed to load config")?;
+
+    // Tokens and upload links narrow the folder and file size limit
+    let link = access::authorize(&mut config, transfer_id, access::PERMISSION_UPLOAD, peer.ip())?;
+
+    // Every attempt ends up in the transfer history, including refused ones
+    let attempt = history::Attempt::start(&config, peer, transfer_id, "tree");
+    let active = activity::ActiveTransfer::start(peer, "tree");
+    let record_failure = |size: u64, code: &str, e: &anyhow::Error| attempt.record(attempt.failed(name, size, code, e));
+
+    let name = structure::sanitize_filename(name)
+        .inspect_err(|e| record_failure(0, history::RESULT_FAILED, e))?;
+
+    // Ensure target directory exists and get the path
+    let receive_dir = structure::ensure_directory_exists(&config.folder, folder).await
+        .context("Failed to ensure receive directory exists")
+        .inspect_err(|e| record_failure(0, history::RESULT_FAILED, e))?;
+
+    let manifest = read_manifest(stream).await
+        .inspect_err(|e| record_failure(0, history::RESULT_FAILED, e))?;
+    let paths = validate_manifest(&manifest)
+        .inspect_err(|e| record_failure(manifest.total_size(), history::RESULT_FAILED, e))?;
+
+    active.describe(name, manifest.files().count(), manifest.total_size());
+    info!("Incoming tree: {} ({} entries, {} bytes)", name, manifest.entries.len(), manifest.total_size());
+
+    // Check size limits before sending ACK
+    if let Err(e) = check_manifest_limits(&config, &manifest, &receive_dir, link.as_ref()).await {
+        error!("Size limit exceeded: {}", e);
+        record_failure(manifest.total_size(), history::RESULT_SIZE_LIMIT, &e);
+
+        stream.write_all(api::size_limit_response(&e).as_bytes()).await
+            .context("Failed to send error response")?;
+        stream.write_all(b"\n").await
+            .context("Failed to send newline")?;
+
+        return Err(e);
+    }
+
+    storage::check_conflict(&receive_dir, name, &config.conflict).await
+        .inspect_err(|e| record_failure(manifest.total_size(), history::RESULT_REJECTED, e))?;
+
+    let transfer = PendingTransfer::new(peer, "tree", name, manifest.files().count(), manifest.total_size(), folder);
+    api::require_approval(&config, transfer, stream).await
+        .inspect_err(|e| record_failure(manifest.total_size(), history::RESULT_REJECTED, e))?;
+    if let Some(link) = &link {
+        link.redeem()
+            .inspect_err(|e| record_failure(manifest.total_size(), history::RESULT_REJECTED, e))?;
+    }
+
+    let compressed = api::negotiate_compression(&config, options);
+    stream.write_all(api::ack_line(compressed).as_bytes()).await
+        .context("Failed to send ACK")?;
+    active.acknowledged();
+
+    // Receive into a staging directory, so a failed transfer never touches what's already stored
+    let staging = StagingArea::create(config.inbox_root()).await?;
+    let staged_root = staging.path().join(name);
+    tokio::fs::create_dir_all(&staged_root).await
+        .with_context(|| format!("Failed to create directory: {}", staged_root.display()))?;
+
+    let received = match receive_tree(stream, &manifest, &paths, &staged_root, compressed, &config.metadata, &active.progress()).await {
+        Ok(checksums) => staging.commit_tree(name, &receive_dir, &config.conflict).await
+            .map(|root_name| (root_name, checksums)),
+        Err(e) => {
+            staging.discard().await;
+            Err(e)
+        }
+    };
+
+    match received {
+        Ok((root_name, checksums)) => {
+            let file_count = manifest.files().count();
+            info!("Successfully received tree: {} ({} files)", root_name, file_count);
+
+            // One history entry per file, named by its path inside the tree
+            for (entry, checksum) in manifest.files().zip(checksums) {
+                let sent_name = format!("{}/{}", name, entry.path);
+                let stored_name = format!("{}/{}", root_name, entry.path);
+                attempt.record(attempt.succeeded(&sent_name, &stored_name, entry.size, checksum));
+            }
+            active.complete();
+
+            Ok(format!("TREE_COMPLETE: {} ({} files)", root_name, file_count))
+        }
+        Err(e) => {
+            error!("Failed to receive tree: {}", e);
+            record_failure(manifest.total_size(), history::RESULT_FAILED, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Reads the length-prefixed JSON manifest
+pub async fn read_manifest<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Manifest> {
+    let manifest_len = stream.read_u64().await
+        .context("Failed to read manifest length")?;
+    if manifest_len > MAX_MANIFEST_SIZE {
+        return Err(anyhow::anyhow!("Manifest of {} bytes exceeds maximum of {} bytes", manifest_len, MAX_MANIFEST_SIZE));
+    }
+
+    let mut manifest = vec![0u8; manifest_len as usize];
+    stream.read_exact(&mut manifest).await
+        .context("Failed to read manifest")?;
+
+    serde_json::from_slice(&manifest)
+        .context("Invalid manifest")
+}
+
+/// Checks every manifest path and returns them as safe relative paths
+fn validate_manifest(manifest: &Manifest) -> Result<Vec<PathBuf>> {
+    let mut seen = HashSet::new();
+
+    manifest.entries.iter()
+        .map(|entry| {
+            let path = structure::sanitize_relative_path(&entry.path)?;
+            if !seen.insert(path.clone()) {
+                return Err(anyhow::anyhow!("Duplicate manifest entry: {}", entry.path));
+            }
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Applies the file size limit to every entry, and the folder limit and the
+/// size limit of an upload link to the whole manifest
+pub async fn check_manifest_limits(config: &Config, manifest: &Manifest, receive_dir: &Path, link: Option<&Link>) -> Result<()> {
+    for entry in manifest.files() {
+        api::check_file_size_limit(config, entry.size)?;
+    }
+    let total_size = manifest.checked_total_size()?;
+    if let Some(link) = link {
+        link.check_size(total_size)?;
+    }
+    api::check_folder_size_limit(config, total_size, receive_dir).await
+}
+
+/// Creates the directories, receives every file and applies the recorded metadata
+/// Returns the checksums of the files in manifest order
+async fn receive_tree<S: AsyncRead + Unpin>(stream: &mut S, manifest: &Manifest, paths: &[PathBuf], root: &Path, compressed: bool, policy: &MetadataConfig, progress: &Progress) -> Result<Vec<String>> {
+    let mut checksums = Vec::new();
+
+    for (entry, path) in manifest.entries.iter().zip(paths) {
+        if entry.kind == EntryKind::Dir {
+            let dir = root.join(path);
+            tokio::fs::create_dir_all(&dir).await
+                .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        }
+    }
+
+    for (entry, path) in manifest.entries.iter().zip(paths) {
+        if entry.kind != EntryKind::File {
+            continue;
+        }
+
+        let file_path = root.join(path);
+        let parent = file_path.parent().unwrap_or(root);
+        tokio::fs::create_dir_all(parent).await
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+
+        let file_name = path.file_name()
+            .and_then(|n| n.to_str())
+            .context("Invalid file name in manifest")?;
+        progress.file(&entry.path);
+        let mut incoming = IncomingFile::create(parent, file_name).await?;
+        incoming.track(progress.clone());
+        if let Err(e) = api::receive_payload(stream, &mut incoming, entry.size, compressed).await {
+            incoming.abort().await;
+            return Err(e).with_context(|| format!("Failed to receive {}", entry.path));
+        }
+        checksums.push(incoming.checksum());
+        incoming.finish_at(&file_path).await?;
+
+        metadata::apply(&file_path, &entry.metadata, policy);
+    }
+
+    // Directories last and deepest first, so creating their contents doesn't
+    // reset the mtime and read-only directories don't block the files inside
+    for (entry, path) in manifest.entries.iter().zip(paths).rev() {
+        if entry.kind == EntryKind::Dir {
+            metadata::apply(&root.join(path), &entry.metadata, policy);
+        }
+    }
+
+    Ok(checksums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(sizes: &[u64]) -> Manifest {
+        let entries = sizes.iter().enumerate()
+            .map(|(i, size)| serde_json::from_value(serde_json::json!({
+                "path": format!("file{}", i), "kind": "file", "size": size,
+            })).unwrap())
+            .collect();
+        Manifest { entries }
+    }
+
+    #[test]
+    fn total_size_adds_file_sizes() {
+        let manifest = manifest(&[1, 2, 3]);
+        assert_eq!(manifest.total_size(), 6);
+        assert_eq!(manifest.checked_total_size().unwrap(), 6);
+    }
+
+    #[test]
+    fn overflowing_total_size_is_rejected() {
+        let manifest = manifest(&[u64::MAX, 1]);
+        assert_eq!(manifest.total_size(), u64::MAX);
+        assert!(manifest.checked_total_size().is_err());
+    }
+}
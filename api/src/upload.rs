@@ -0,0 +1,443 @@
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path as UrlPath},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::put,
+    Router,
+};
+use futures_util::{Stream, StreamExt};
+use log::{error, info, warn};
+use std::{net::SocketAddr, pin::pin, sync::Arc};
+use crate::access;
+use crate::activity::ActiveTransfer;
+use crate::api;
+use crate::approval::{self, Decision, PendingTransfer};
+use crate::bans;
+use crate::config::{Config, LimitsConfig};
+use crate::crypto::KeyResolver;
+use crate::firewall::{self, Firewall};
+use crate::history::{self, Attempt};
+use crate::limits::{self, LimitExceeded};
+use crate::links::{self, Link};
+use crate::metrics;
+use crate::shares;
+use crate::storage::{self, IncomingFile, OutgoingFile};
+use crate::structure;
+
+/// Transfer kind of HTTP uploads in the history, metrics and active transfers
+const KIND: &str = "http";
+
+/// An error answered as a plain text line, which is what curl prints
+struct UploadError(StatusCode, String);
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        (self.0, format!("{}\n", self.1)).into_response()
+    }
+}
+
+type UploadResult = std::result::Result<(StatusCode, String), UploadError>;
+
+/// Serves HTTP uploads on `addresses`
+///
+/// `curl -T <file> http://host:port/<folder>/<name>` stores one file, a
+/// multipart POST to `http://host:port/<folder>` stores every file field.
+/// Requests authenticate with `Authorization: Bearer <transfer_id>`, or with
+/// the token of an upload link. Shared files are downloaded with
+/// `GET http://host:port/<share_token>`.
+pub async fn serve(addresses: Vec<SocketAddr>, firewall: Arc<Firewall>, limits: LimitsConfig) -> anyhow::Result<()> {
+    let app = router(firewall, limits);
+
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in &addresses {
+        let separate_ipv4 = addresses.iter()
+            .any(|other| other.is_ipv4() && other.ip().is_unspecified());
+        let listener = api::bind_listener(*addr, !separate_ipv4)?;
+        info!("HTTP upload server listening on http://{}", addr);
+
+        let service = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+        servers.spawn(async move { axum::serve(listener, service).await });
+    }
+    while let Some(result) = servers.join_next().await {
+        result.context("HTTP upload server task failed")?
+            .context("HTTP upload server stopped")?;
+    }
+
+    Ok(())
+}
+
+fn router(firewall: Arc<Firewall>, limits: LimitsConfig) -> Router {
+    Router::new()
+        .route("/", put(put_file).post(post_files))
+        .route("/{*path}", put(put_file).post(post_files).get(get_share))
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn_with_state(limits, limits::filter))
+        .layer(middleware::from_fn_with_state(firewall, firewall::filter))
+}
+
+/// Loads the config and checks that the bearer token may upload
+///
+/// Returns the presented token and, for a link, the link; the config is
+/// narrowed to what the token may access.
+fn authorize(headers: &HeaderMap, peer: SocketAddr) -> std::result::Result<(Config, String, Option<Link>), UploadError> {
+    let mut config = Config::current()
+        .map_err(|e| UploadError(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load config: {:#}", e)))?;
+
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| UploadError(StatusCode::UNAUTHORIZED, "Missing transfer ID".to_string()))?
+        .to_string();
+
+    let link = access::authorize(&mut config, &token, access::PERMISSION_UPLOAD, peer.ip())
+        .map_err(|e| UploadError(StatusCode::FORBIDDEN, format!("{:#}", e)))?;
+    Ok((config, token, link))
+}
+
+/// PUT /<folder>/<name> - stores the request body as one file
+async fn put_file(ConnectInfo(peer): ConnectInfo<SocketAddr>, path: Option<UrlPath<String>>, headers: HeaderMap, body: Body) -> UploadResult {
+    let (config, token, link) = authorize(&headers, peer)?;
+
+    let path = path.map(|UrlPath(path)| path).unwrap_or_default();
+    let (folder, name) = match path.rsplit_once('/') {
+        Some((folder, name)) => (Some(folder).filter(|folder| !folder.is_empty()), name),
+        None => (None, path.as_str()),
+    };
+    if name.is_empty() {
+        return Err(UploadError(StatusCode::BAD_REQUEST, "PUT needs a file name: http://host:port/[<folder>/]<name>".to_string()));
+    }
+
+    // The size is needed to check the limits before accepting the data
+    let size = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or_else(|| UploadError(StatusCode::LENGTH_REQUIRED, "Content-Length is required".to_string()))?;
+
+    info!("HTTP upload from {}: {} ({} bytes, folder: {:?})", peer, name, size, folder);
+
+    let attempt = Attempt::start(&config, peer, &token, KIND);
+    let active = ActiveTransfer::start(peer, KIND);
+    active.describe(name, 1, size);
+
+    let transfer = PendingTransfer::new(peer, KIND, name, 1, size, folder);
+    let (stored_name, _) = receive(&config, &attempt, &active, folder, name, Some(size), None, Some((transfer, link)), body.into_data_stream()).await?;
+    active.complete();
+
+    Ok((StatusCode::CREATED, format!("TRANSFER_COMPLETE: {}\n", stored_name)))
+}
+
+/// POST /<folder> with multipart/form-data - stores every file field
+async fn post_files(ConnectInfo(peer): ConnectInfo<SocketAddr>, path: Option<UrlPath<String>>, headers: HeaderMap, mut multipart: Multipart) -> UploadResult {
+    let (config, token, mut link) = authorize(&headers, peer)?;
+
+    let path = path.map(|UrlPath(path)| path).unwrap_or_default();
+    let folder = Some(path.trim_matches('/')).filter(|folder| !folder.is_empty());
+    // Field sizes aren't announced; the request size is the closest estimate
+    let request_size = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let mut attempt = Attempt::start(&config, peer, &token, KIND);
+    let active = ActiveTransfer::start(peer, KIND);
+
+    // An upload link's size limit covers all files of the request together
+    let mut link_budget = link.as_ref().map(|link| link.max_size).filter(|&max_size| max_size > 0);
+
+    let mut lines = Vec::new();
+    loop {
+        let field = multipart.next_field().await
+            .map_err(|e| UploadError(StatusCode::BAD_REQUEST, format!("Invalid form data: {}", e)))?;
+        let Some(field) = field else {
+            break;
+        };
+        // Plain form fields carry no file
+        let Some(name) = field.file_name().map(str::to_string) else {
+            continue;
+        };
+
+        info!("HTTP upload from {}: {} (folder: {:?})", peer, name, folder);
+        attempt.restart_timer();
+        active.describe(&name, lines.len() + 1, request_size);
+
+        // The first file stands for the whole request in approval mode and link uses
+        let transfer = lines.is_empty().then(|| (PendingTransfer::new(peer, KIND, &name, 1, request_size, folder), link.take()));
+        let (stored_name, received) = receive(&config, &attempt, &active, folder, &name, None, link_budget, transfer, field).await?;
+        link_budget = link_budget.map(|budget| budget.saturating_sub(received));
+        lines.push(format!("TRANSFER_COMPLETE: {}\n", stored_name));
+    }
+
+    if lines.is_empty() {
+        return Err(UploadError(StatusCode::BAD_REQUEST, "The form data contains no files".to_string()));
+    }
+    active.complete();
+
+    Ok((StatusCode::CREATED, lines.concat()))
+}
+
+/// GET /<share_token>[/<name>] - sends a shared file
+async fn get_share(ConnectInfo(peer): ConnectInfo<SocketAddr>, UrlPath(path): UrlPath<String>) -> std::result::Result<Response, UploadError> {
+    let config = Config::current()
+        .map_err(|e| UploadError(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load config: {:#}", e)))?;
+
+    // The name after the token only makes the URL end in the file name
+    let token = path.split('/').next().unwrap_or_default();
+    bans::check(&config.limits, peer.ip())
+        .map_err(|e| UploadError(StatusCode::TOO_MANY_REQUESTS, format!("{:#}", e)))?;
+    let share = shares::find(token)
+        .map_err(|e| UploadError(StatusCode::GONE, format!("{:#}", e)))?
+        .ok_or_else(|| {
+            bans::failed(&config.limits, peer.ip());
+            UploadError(StatusCode::NOT_FOUND, "Share not found".to_string())
+        })?;
+    let file_path = share.resolve(&config, "")
+        .map_err(|e| UploadError(StatusCode::NOT_FOUND, format!("{:#}", e)))?;
+
+    let mut keys = KeyResolver::for_server(&config.encryption);
+    let file = OutgoingFile::open(&file_path, &mut keys).await
+        .map_err(|e| UploadError(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    let size = file.size();
+    share.redeem()
+        .map_err(|e| UploadError(StatusCode::GONE, format!("{:#}", e)))?;
+    info!("HTTP download of share {} by {}: {} ({} bytes)", links::short_token(&share.token), peer, share.path, size);
+
+    let chunks = futures_util::stream::try_unfold(file, |mut file| async move {
+        Ok::<_, anyhow::Error>(file.read_chunk().await?.map(|chunk| (Bytes::from(chunk), file)))
+    });
+    let disposition = format!("attachment; filename=\"{}\"", share.file_name().replace(['"', '\\'], "_"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(chunks),
+    ).into_response())
+}
+
+/// Receives one file into `folder` the way a TRANSFER command does
+///
+/// With a known `size` the limits are checked before any data is read;
+/// otherwise they are enforced while the data arrives. `link_budget` is
+/// what is left of an upload link's size limit for the request. `admission`
+/// is submitted for approval once the limits passed, if given, and then
+/// counts as one use of its upload link.
+///
+/// Returns the stored name and the number of bytes received.
+#[allow(clippy::too_many_arguments)]
+async fn receive<S, E>(config: &Config, attempt: &Attempt, active: &ActiveTransfer, folder: Option<&str>, name: &str, size: Option<u64>, link_budget: Option<u64>, admission: Option<(PendingTransfer, Option<Link>)>, data: S) -> std::result::Result<(String, u64), UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let fail = |status: StatusCode, code: &str, size: u64, e: anyhow::Error| {
+        error!("HTTP upload of {} failed: {:#}", name, e);
+        attempt.record(attempt.failed(name, size, code, &e));
+        UploadError(status, format!("{:#}", e))
+    };
+
+    let name = structure::sanitize_filename(name)
+        .map_err(|e| fail(StatusCode::BAD_REQUEST, history::RESULT_FAILED, 0, e))?;
+    let receive_dir = structure::ensure_directory_exists(&config.folder, folder).await
+        .map_err(|e| fail(StatusCode::BAD_REQUEST, history::RESULT_FAILED, 0, e))?;
+
+    if let Some(size) = size {
+        api::check_size_limits(config, size, &receive_dir).await
+            .map_err(|e| fail(StatusCode::PAYLOAD_TOO_LARGE, history::RESULT_SIZE_LIMIT, size, e))?;
+    }
+    // Without an announced size the data is checked against the space left as it arrives
+    let folder_space = match size {
+        Some(_) => None,
+        None => api::folder_space(config, &receive_dir).await
+            .map_err(|e| fail(StatusCode::INTERNAL_SERVER_ERROR, history::RESULT_FAILED, 0, e))?,
+    };
+
+    storage::check_conflict(&receive_dir, name, &config.conflict).await
+        .map_err(|e| fail(StatusCode::CONFLICT, history::RESULT_REJECTED, size.unwrap_or(0), e))?;
+
+    if let Some((transfer, link)) = admission {
+        if let Decision::Rejected(reason) = approval::request(&config.approval, transfer).await {
+            warn!("Transfer rejected: {}", reason);
+            metrics::rejected(metrics::REASON_APPROVAL);
+            return Err(fail(StatusCode::FORBIDDEN, history::RESULT_REJECTED, size.unwrap_or(0), anyhow::anyhow!("Transfer {}", reason)));
+        }
+        if let Some(link) = link {
+            link.redeem()
+                .map_err(|e| fail(StatusCode::FORBIDDEN, history::RESULT_REJECTED, size.unwrap_or(0), e))?;
+        }
+    }
+    active.acknowledged();
+
+    let mut incoming = IncomingFile::create(&receive_dir, name).await
+        .map_err(|e| fail(StatusCode::INTERNAL_SERVER_ERROR, history::RESULT_FAILED, size.unwrap_or(0), e))?;
+    incoming.track(active.progress());
+
+    let mut data = pin!(data);
+    let mut received = 0u64;
+    let result = async {
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk.map_err(|e| {
+                let e = anyhow::Error::new(e);
+                let status = match LimitExceeded::find(&e) {
+                    Some(_) => StatusCode::REQUEST_TIMEOUT,
+                    None => StatusCode::BAD_REQUEST,
+                };
+                (status, history::RESULT_FAILED, e.context("Upload interrupted"))
+            })?;
+            received += chunk.len() as u64;
+            if size.is_some_and(|size| received > size) {
+                return Err((StatusCode::BAD_REQUEST, history::RESULT_FAILED, anyhow::anyhow!("Received more than the announced {} bytes", size.unwrap_or(0))));
+            }
+            api::check_file_size_limit(config, received)
+                .map_err(|e| (StatusCode::PAYLOAD_TOO_LARGE, history::RESULT_SIZE_LIMIT, e))?;
+            if let Some(space) = folder_space && received > space {
+                metrics::rejected(metrics::REASON_FOLDER_SIZE);
+                return Err((StatusCode::PAYLOAD_TOO_LARGE, history::RESULT_SIZE_LIMIT, anyhow::anyhow!(
+                    "Upload exceeds maximum allowed folder size {} bytes ({} bytes were left)", config.max_folder_size, space,
+                )));
+            }
+            if let Some(budget) = link_budget && received > budget {
+                metrics::rejected(metrics::REASON_FILE_SIZE);
+                return Err((StatusCode::PAYLOAD_TOO_LARGE, history::RESULT_SIZE_LIMIT, anyhow::anyhow!("Request exceeds the upload link's size limit")));
+            }
+            incoming.write(&chunk).await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, history::RESULT_FAILED, e))?;
+        }
+
+        match size {
+            Some(size) if received != size => {
+                Err((StatusCode::BAD_REQUEST, history::RESULT_FAILED, anyhow::anyhow!("Received {} of the announced {} bytes", received, size)))
+            }
+            // Checked again, as uploads running at the same time share the space; the partial file already counts
+            None => api::check_folder_size_limit(config, 0, &receive_dir).await
+                .map_err(|e| (StatusCode::PAYLOAD_TOO_LARGE, history::RESULT_SIZE_LIMIT, e)),
+            Some(_) => Ok(()),
+        }
+    }.await;
+
+    if let Err((status, code, e)) = result {
+        incoming.abort().await;
+        return Err(fail(status, code, received, e));
+    }
+
+    let checksum = incoming.checksum();
+    let stored_name = incoming.finish(&config.conflict).await
+        .map_err(|e| fail(StatusCode::INTERNAL_SERVER_ERROR, history::RESULT_FAILED, received, e))?;
+
+    info!("HTTP upload saved as: {}", stored_name);
+    attempt.record(attempt.succeeded(name, &stored_name, received, checksum));
+
+    Ok((stored_name, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serves uploads with Config::for_tests() on a free loopback port
+    async fn start() -> SocketAddr {
+        let config = Config::for_tests();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(Arc::new(Firewall::new(&config).unwrap()), config.limits.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        addr
+    }
+
+    /// Sends one request and returns the status code and body of the response
+    async fn send(addr: SocketAddr, method: &str, path: &str, headers: &[String], body: &[u8]) -> (u16, String) {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n", method, path, addr, body.len());
+        for header in headers {
+            request.push_str(&format!("{}\r\n", header));
+        }
+        request.push_str("\r\n");
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    /// Posts `files` (name and size) as multipart form data into `folder`
+    async fn post(addr: SocketAddr, folder: &str, token: &str, files: &[(&str, usize)]) -> (u16, String) {
+        let mut body = Vec::new();
+        for (name, size) in files {
+            body.extend_from_slice(format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                name,
+            ).as_bytes());
+            body.extend(std::iter::repeat_n(b'x', *size));
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+
+        let headers = [
+            format!("Authorization: Bearer {}", token),
+            "Content-Type: multipart/form-data; boundary=boundary".to_string(),
+        ];
+        send(addr, "POST", &format!("/{}", folder), &headers, &body).await
+    }
+
+    fn received(folder: &str) -> PathBuf {
+        Path::new(&Config::for_tests().folder).join(folder)
+    }
+
+    #[tokio::test]
+    async fn multipart_upload_stores_every_file() {
+        let addr = start().await;
+        let (status, body) = post(addr, "upload-multipart", &Config::for_tests().transfer_id, &[("a.txt", 10), ("b.txt", 20)]).await;
+        assert_eq!(status, 201, "{}", body);
+        assert_eq!(body, "TRANSFER_COMPLETE: a.txt\nTRANSFER_COMPLETE: b.txt\n");
+        assert_eq!(std::fs::metadata(received("upload-multipart").join("a.txt")).unwrap().len(), 10);
+        assert_eq!(std::fs::metadata(received("upload-multipart").join("b.txt")).unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn put_stores_the_body() {
+        let addr = start().await;
+        let headers = [format!("Authorization: Bearer {}", Config::for_tests().transfer_id)];
+        let (status, body) = send(addr, "PUT", "/upload-put/c.txt", &headers, b"hello").await;
+        assert_eq!(status, 201, "{}", body);
+        assert_eq!(std::fs::read(received("upload-put").join("c.txt")).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn multipart_file_over_the_size_limit_is_refused() {
+        let addr = start().await;
+        let (status, body) = post(addr, "upload-too-large", &Config::for_tests().transfer_id, &[("large.bin", 5000)]).await;
+        assert_eq!(status, 413, "{}", body);
+        assert!(!received("upload-too-large").join("large.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn upload_link_limit_covers_the_whole_request() {
+        let link = links::create(None, chrono::Duration::hours(1), 1000, 1).unwrap();
+        let addr = start().await;
+        let (status, body) = post(addr, "", &link.token, &[("part1.bin", 600), ("part2.bin", 600)]).await;
+        assert_eq!(status, 413, "{}", body);
+        assert_eq!(body, "Request exceeds the upload link's size limit\n");
+    }
+
+    #[tokio::test]
+    async fn upload_needs_a_valid_transfer_id() {
+        let addr = start().await;
+        let (status, _) = send(addr, "PUT", "/upload-refused/d.txt", &[], b"hello").await;
+        assert_eq!(status, 401);
+        let (status, body) = post(addr, "upload-refused", "wrong-transfer-id", &[("d.txt", 5)]).await;
+        assert_eq!(status, 403);
+        assert_eq!(body, "Invalid transfer ID\n");
+        assert!(!received("upload-refused").exists());
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use log::{error, info, warn};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use crate::api;
+use crate::config::LimitsConfig;
+use crate::firewall::{self, Firewall};
+use crate::limits;
+use crate::metrics;
+
+/// Largest binary message accepted; senders split payloads into smaller messages
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Buffer between the WebSocket and the protocol handler, in each direction
+const BRIDGE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Serves the transfer protocol over WebSocket on `addresses`
+///
+/// Browsers can't open raw TCP connections, so this lets them send directly:
+/// each text message is one command line (TRANSFER, TREE, SESSION, COMMIT...),
+/// binary messages carry the bytes that follow it (sizes, manifests, payload),
+/// and every response line comes back as a text message.
+pub async fn serve(addresses: Vec<SocketAddr>, firewall: Arc<Firewall>, limits: LimitsConfig) -> Result<()> {
+    let app = router(firewall, limits);
+
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in &addresses {
+        let separate_ipv4 = addresses.iter()
+            .any(|other| other.is_ipv4() && other.ip().is_unspecified());
+        let listener = api::bind_listener(*addr, !separate_ipv4)?;
+        info!("WebSocket transfer server listening on ws://{}", addr);
+
+        let service = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+        servers.spawn(async move { axum::serve(listener, service).await });
+    }
+    while let Some(result) = servers.join_next().await {
+        result.context("WebSocket server task failed")?
+            .context("WebSocket server stopped")?;
+    }
+
+    Ok(())
+}
+
+fn router(firewall: Arc<Firewall>, limits: LimitsConfig) -> Router {
+    Router::new()
+        .route("/", get(upgrade))
+        .layer(middleware::from_fn_with_state(firewall, firewall::filter))
+        .with_state(limits)
+}
+
+async fn upgrade(upgrade: WebSocketUpgrade, ConnectInfo(peer): ConnectInfo<SocketAddr>, State(limits): State<LimitsConfig>) -> Response {
+    let slot = match limits::admit(&limits, peer.ip()) {
+        Ok(slot) => slot,
+        Err(e) => {
+            warn!("Refused WebSocket connection from {}: {}", peer, e);
+            return (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response();
+        }
+    };
+    info!("New WebSocket transfer connection from: {}", peer);
+    metrics::connection_accepted();
+
+    upgrade
+        .max_message_size(MAX_MESSAGE_SIZE)
+        .on_upgrade(move |socket| async move {
+            let _slot = slot;
+            if let Err(e) = handle_socket(socket, peer, limits).await {
+                error!("Error handling WebSocket connection from {}: {:#}", peer, e);
+            }
+        })
+}
+
+/// Runs the protocol handler on one end of a pipe and relays WebSocket messages to the other
+async fn handle_socket(socket: WebSocket, peer: SocketAddr, limits: LimitsConfig) -> Result<()> {
+    let (server_side, bridge_side) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+
+    let connection = tokio::spawn(api::handle_connection(server_side, peer, limits));
+    let bridged = bridge(socket, bridge_side).await;
+
+    // Closing the bridge ends the handler like a TCP disconnect would
+    connection.await.context("Connection handler panicked")??;
+    bridged
+}
+
+/// Relays messages until either side closes
+async fn bridge(mut socket: WebSocket, pipe: DuplexStream) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(pipe);
+    let mut output = Vec::new();
+    let mut buffer = vec![0u8; 8192];
+
+    loop {
+        tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let line = text.as_str().trim_end_matches(['\r', '\n']);
+                    if is_get_command(line) {
+                        // Downloads are binary and don't fit the line-per-message responses
+                        socket.send(Message::Text("ERROR: GET is not available over WebSocket".into())).await
+                            .context("Failed to send WebSocket message")?;
+                        continue;
+                    }
+                    writer.write_all(line.as_bytes()).await
+                        .context("Failed to forward command")?;
+                    writer.write_all(b"\n").await
+                        .context("Failed to forward command")?;
+                }
+                Some(Ok(Message::Binary(data))) => {
+                    writer.write_all(&data).await
+                        .context("Failed to forward data")?;
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e).context("Failed to read WebSocket message"),
+            },
+            read = reader.read(&mut buffer) => {
+                let read = read.context("Failed to read response")?;
+                if read == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buffer[..read]);
+
+                // One text message per response line
+                while let Some(newline) = output.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = output.drain(..=newline).collect();
+                    let line = String::from_utf8_lossy(&line[..newline]).to_string();
+                    socket.send(Message::Text(line.into())).await
+                        .context("Failed to send WebSocket message")?;
+                }
+            }
+        }
+    }
+
+    // Let the handler see the disconnect
+    writer.shutdown().await.ok();
+    socket.send(Message::Close(None)).await.ok();
+    Ok(())
+}
+
+/// True for GET, the only command that answers with binary file contents
+fn is_get_command(line: &str) -> bool {
+    line.split_whitespace().next().is_some_and(|verb| verb.eq_ignore_ascii_case("GET"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use futures_util::{SinkExt, StreamExt};
+    use std::path::Path;
+    use tokio_tungstenite::tungstenite;
+
+    /// Serves the WebSocket endpoint on a free loopback port
+    async fn start(config: &Config) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(Arc::new(Firewall::new(config).unwrap()), config.limits.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        format!("ws://{}/", addr)
+    }
+
+    type Client = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// Connects to `url` and sends `command` as text message
+    async fn send(url: &str, command: &str) -> Client {
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        socket.send(tungstenite::Message::text(command)).await.unwrap();
+        socket
+    }
+
+    async fn next_line(socket: &mut Client) -> String {
+        match socket.next().await.unwrap().unwrap() {
+            tungstenite::Message::Text(line) => line.to_string(),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_and_responses_are_relayed() {
+        let config = Config::for_tests();
+        let folder = Path::new(&config.folder).join("websocket");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("a.txt"), "hello").unwrap();
+
+        let url = start(&config).await;
+        let mut socket = send(&url, &format!("LIST {} websocket", config.transfer_id)).await;
+        let entry = next_line(&mut socket).await;
+        assert!(entry.starts_with("ENTRY file 5 ") && entry.ends_with(" a.txt"), "{}", entry);
+        assert_eq!(next_line(&mut socket).await, "LIST_COMPLETE: 1 entries");
+    }
+
+    #[tokio::test]
+    async fn unknown_transfer_id_is_refused() {
+        let url = start(&Config::for_tests()).await;
+        let mut socket = send(&url, "LIST wrong-transfer-id").await;
+        assert_eq!(next_line(&mut socket).await, "ERROR: Invalid transfer ID");
+    }
+
+    #[tokio::test]
+    async fn get_is_refused() {
+        let url = start(&Config::for_tests()).await;
+        let mut socket = send(&url, "GET some-id file.txt").await;
+        assert_eq!(next_line(&mut socket).await, "ERROR: GET is not available over WebSocket");
+    }
+
+    #[tokio::test]
+    async fn denied_address_cannot_connect() {
+        let mut config = Config::default();
+        config.deny = vec!["127.0.0.0/8".to_string()];
+        let url = start(&config).await;
+        match tokio_tungstenite::connect_async(url).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            other => panic!("denied address connected: {:?}", other.map(|(_, response)| response.status())),
+        }
+    }
+}
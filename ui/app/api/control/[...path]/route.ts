@@ -0,0 +1,21 @@
+import { NextRequest, NextResponse } from 'next/server';
+import { controlRequest } from '../../../../lib/control';
+
+// Forwards browser requests to the control API of the local server, adding the
+// token; middleware.ts only lets requests from pages of this UI through
+async function forward(request: NextRequest, { params }: { params: Promise<{ path: string[] }> }) {
+  const { path } = await params;
+  const body = request.method === 'GET' ? undefined : await request.text();
+
+  const response = await controlRequest(`${path.join('/')}${request.nextUrl.search}`, {
+    method: request.method,
+    body,
+  });
+
+  return new NextResponse(await response.text(), {
+    status: response.status,
+    headers: { 'Content-Type': 'application/json' },
+  });
+}
+
+export { forward as GET, forward as POST, forward as PATCH };
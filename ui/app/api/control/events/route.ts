@@ -0,0 +1,43 @@
+import { controlEvents } from '../../../../lib/control';
+
+export const dynamic = 'force-dynamic';
+
+// Relays the progress events of the local server to the browser as
+// server-sent events, since browsers can't add the token to a WebSocket
+export async function GET() {
+  const encoder = new TextEncoder();
+  let close: (() => void) | null = null;
+
+  const stream = new ReadableStream({
+    async start(controller) {
+      const end = () => {
+        try {
+          controller.close();
+        } catch {
+          // Already closed by the browser
+        }
+      };
+
+      try {
+        close = await controlEvents(
+          (data) => controller.enqueue(encoder.encode(`data: ${data}\n\n`)),
+          end,
+        );
+      } catch (error: any) {
+        controller.enqueue(encoder.encode(`event: error\ndata: ${JSON.stringify({ error: error.message })}\n\n`));
+        end();
+      }
+    },
+    cancel() {
+      close?.();
+    },
+  });
+
+  return new Response(stream, {
+    headers: {
+      'Content-Type': 'text/event-stream',
+      'Cache-Control': 'no-cache, no-transform',
+      'Connection': 'keep-alive',
+    },
+  });
+}
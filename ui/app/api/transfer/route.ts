@@ -34,103 +34,46 @@ export async function POST(request: NextRequest) {
       );
     }
 
-    // Process files sequentially
-    const results: any[] = [];
-    
-    for (const file of files) {
-      try {
-        const fileBuffer = Buffer.from(await file.arrayBuffer());
-        const fileName = file.name;
-        
-        // Connect to TCP server for each file
-        const socket = new Socket();
-        
-        const transferResult = await new Promise((resolve) => {
-          socket.connect(parseInt(port), ip, () => {
-            console.log(`Connected to TCP server at ${ip}:${port} for file: ${fileName}`);
-            
-            // Send TRANSFER command
-            const command = folder && folder.trim() 
-              ? `TRANSFER ${transferId} ${fileName} ${folder.trim()}`
-              : `TRANSFER ${transferId} ${fileName}`;
-            socket.write(command);
-            
-            // Send file size (8 bytes, big-endian)
-            const sizeBuffer = Buffer.alloc(8);
-            sizeBuffer.writeBigUInt64BE(BigInt(fileBuffer.length), 0);
-            socket.write(sizeBuffer);
-            
-            // Wait for ACK before sending file data
-            socket.once('data', (data) => {
-              const response = data.toString().trim();
-              console.log('Received response:', response);
-              
-              if (response === 'ACK') {
-                // Send file data after receiving ACK
-                socket.write(fileBuffer);
-                
-                // Wait for final response
-                socket.once('data', (finalData) => {
-                  const finalResponse = finalData.toString().trim();
-                  console.log('Final response:', finalResponse);
-                  
-                  if (finalResponse.includes('TRANSFER_COMPLETE')) {
-                    resolve({ success: true, fileName, message: 'Transfer completed successfully' });
-                  } else {
-                    resolve({ success: false, fileName, error: finalResponse });
-                  }
-                  
-                  socket.end();
-                });
-              } else {
-                // Error response instead of ACK
-                resolve({ success: false, fileName, error: response });
-                socket.end();
-              }
-            });
-          });
-          
-          socket.on('error', (error) => {
-            console.error('Socket error:', error);
-            resolve({ success: false, fileName, error: `Connection failed: ${error.message}` });
+    // IPv6 addresses may be entered in brackets ([fd00::2]); the socket wants them bare
+    const host = ip.trim().replace(/^\[(.*)\]$/, '$1');
+
+    // The response is newline-delimited JSON: progress lines while the files
+    // are sent, then one result line with the outcome of every file
+    const encoder = new TextEncoder();
+    const stream = new ReadableStream({
+      async start(controller) {
+        const send = (line: object) => controller.enqueue(encoder.encode(JSON.stringify(line) + '\n'));
+
+        // Send all files over one connection as a batch session
+        const results: any[] = await sendBatch(host, parseInt(port), transferId, folder, files,
+          (progress) => send({ type: 'progress', ...progress }));
+
+        // Return combined results
+        const successCount = results.filter(r => r.success).length;
+        const totalCount = results.length;
+
+        if (successCount === totalCount) {
+          send({
+            type: 'result',
+            success: true,
+            message: `All ${totalCount} file(s) transferred successfully`,
+            results
           });
-          
-          socket.on('timeout', () => {
-            console.error('Socket timeout');
-            socket.destroy();
-            resolve({ success: false, fileName, error: 'Connection timed out' });
+        } else {
+          send({
+            type: 'result',
+            success: false,
+            message: `${successCount}/${totalCount} file(s) transferred successfully`,
+            results
           });
-          
-          // Set timeout
-          socket.setTimeout(30000);
-        });
-        
-        results.push(transferResult);
-        
-      } catch (error) {
-        console.error(`Error processing file ${file.name}:`, error);
-        results.push({ success: false, fileName: file.name, error: 'File processing error' });
-      }
-    }
-    
-    // Return combined results
-    const successCount = results.filter(r => r.success).length;
-    const totalCount = results.length;
-    
-    if (successCount === totalCount) {
-      return NextResponse.json({ 
-        success: true, 
-        message: `All ${totalCount} file(s) transferred successfully`,
-        results 
-      });
-    } else {
-      return NextResponse.json({ 
-        success: false, 
-        message: `${successCount}/${totalCount} file(s) transferred successfully`,
-        results 
-      });
-    }
+        }
+        controller.close();
+      },
+    });
 
+    return new Response(stream, {
+      headers: { 'Content-Type': 'application/x-ndjson', 'Cache-Control': 'no-cache' },
+    });
 
   } catch (error) {
     console.error('API error:', error);
@@ -139,4 +82,200 @@ export async function POST(request: NextRequest) {
       { status: 500 }
     );
   }
-} 
\ No newline at end of file
+} 
+
+// Reads newline-terminated responses from the socket
+function createLineReader(socket: Socket) {
+  let buffered = '';
+  const lines: string[] = [];
+  const waiters: ((line: string | null) => void)[] = [];
+  let closed = false;
+
+  socket.on('data', (data) => {
+    buffered += data.toString();
+    let newline;
+    while ((newline = buffered.indexOf('\n')) >= 0) {
+      const line = buffered.slice(0, newline).trim();
+      buffered = buffered.slice(newline + 1);
+      const waiter = waiters.shift();
+      if (waiter) {
+        waiter(line);
+      } else {
+        lines.push(line);
+      }
+    }
+  });
+
+  const close = () => {
+    closed = true;
+    while (waiters.length > 0) {
+      waiters.shift()!(null);
+    }
+  };
+  socket.on('close', close);
+  socket.on('error', close);
+
+  return (): Promise<string | null> => {
+    if (lines.length > 0) {
+      return Promise.resolve(lines.shift()!);
+    }
+    if (closed) {
+      return Promise.resolve(null);
+    }
+    return new Promise((resolve) => waiters.push(resolve));
+  };
+}
+
+// Progress of a batch: "waiting" for the ACK (or approval), "sending" a file,
+// "verifying" while the receiver confirms it, then "complete" or "failed"
+interface SendProgress {
+  fileName: string;
+  state: string;
+  bytes: number;
+  total: number;
+  rate: number;
+  etaSecs: number | null;
+}
+
+// Size of the writes whose completion is reported as progress
+const CHUNK_SIZE = 64 * 1024;
+
+// Minimum time between two progress reports while sending
+const PROGRESS_INTERVAL = 250;
+
+// Writes `data` in chunks, waiting for the socket to drain so `onChunk` sees
+// the bytes actually handed to the network
+async function writeChunked(socket: Socket, data: Buffer, onChunk: (length: number) => void) {
+  for (let offset = 0; offset < data.length; offset += CHUNK_SIZE) {
+    const chunk = data.subarray(offset, offset + CHUNK_SIZE);
+    if (!socket.write(chunk)) {
+      await new Promise<void>((resolve) => {
+        const done = () => {
+          socket.off('drain', done);
+          socket.off('close', done);
+          resolve();
+        };
+        socket.on('drain', done);
+        socket.on('close', done);
+      });
+    }
+    if (socket.destroyed) {
+      throw new Error('Connection closed');
+    }
+    onChunk(chunk.length);
+  }
+}
+
+// Sends the files in one SESSION: the server checks size limits for the whole
+// batch up front, then answers every file with FILE_COMPLETE or FILE_FAILED
+async function sendBatch(ip: string, port: number, transferId: string, folder: string | null, files: File[],
+  onProgress: (progress: SendProgress) => void) {
+  const fail = (error: string) => files.map((file) => ({ success: false, fileName: file.name, error }));
+
+  const total = files.reduce((sum, file) => sum + file.size, 0);
+  let bytes = 0;
+  let sendingSince = 0;
+  let lastReport = 0;
+  const report = (fileName: string, state: string, force = true) => {
+    const now = Date.now();
+    if (!force && now - lastReport < PROGRESS_INTERVAL) {
+      return;
+    }
+    lastReport = now;
+    const elapsed = sendingSince ? (now - sendingSince) / 1000 : 0;
+    const rate = elapsed > 0 ? Math.round(bytes / elapsed) : 0;
+    const etaSecs = rate > 0 ? Math.ceil((total - bytes) / rate) : null;
+    onProgress({ fileName, state, bytes, total, rate, etaSecs });
+  };
+
+  const socket = new Socket();
+  // Long enough for the receiver to accept the transfer in approval mode
+  socket.setTimeout(300000);
+
+  try {
+    await new Promise<void>((resolve, reject) => {
+      socket.once('error', reject);
+      socket.connect(port, ip, () => {
+        socket.off('error', reject);
+        resolve();
+      });
+    });
+  } catch (error: any) {
+    console.error('Socket error:', error);
+    return fail(`Connection failed: ${error.message}`);
+  }
+
+  console.log(`Connected to TCP server at ${ip}:${port} for ${files.length} file(s)`);
+
+  const readLine = createLineReader(socket);
+  socket.on('timeout', () => {
+    console.error('Socket timeout');
+    socket.destroy();
+  });
+
+  try {
+    // Send SESSION command followed by the manifest
+    const command = folder && folder.trim()
+      ? `SESSION ${transferId} ${folder.trim()}\n`
+      : `SESSION ${transferId}\n`;
+    const manifest = Buffer.from(JSON.stringify({
+      entries: files.map((file) => ({ path: file.name, kind: 'file', size: file.size })),
+    }));
+    const lengthBuffer = Buffer.alloc(8);
+    lengthBuffer.writeBigUInt64BE(BigInt(manifest.length), 0);
+    socket.write(command);
+    socket.write(lengthBuffer);
+    socket.write(manifest);
+    report(files[0].name, 'waiting');
+
+    // Wait for ACK before sending file data
+    const response = await readLine();
+    console.log('Received response:', response);
+    if (response !== 'ACK') {
+      report(files[0].name, 'failed');
+      return fail(response ?? 'Connection closed');
+    }
+
+    const results: any[] = [];
+    sendingSince = Date.now();
+    for (const file of files) {
+      report(file.name, 'sending');
+      try {
+        await writeChunked(socket, Buffer.from(await file.arrayBuffer()), (length) => {
+          bytes += length;
+          report(file.name, 'sending', false);
+        });
+      } catch {
+        break;
+      }
+      report(file.name, 'verifying');
+
+      const fileResponse = await readLine();
+      console.log('File response:', fileResponse);
+      if (fileResponse === null) {
+        results.push({ success: false, fileName: file.name, error: 'Connection closed' });
+        break;
+      }
+      if (fileResponse.startsWith('FILE_COMPLETE')) {
+        results.push({ success: true, fileName: file.name, message: 'Transfer completed successfully' });
+      } else {
+        const error = fileResponse.startsWith('FILE_FAILED')
+          ? fileResponse.slice(fileResponse.indexOf(': ') + 2)
+          : fileResponse;
+        results.push({ success: false, fileName: file.name, error });
+      }
+    }
+
+    const summary = await readLine();
+    console.log('Final response:', summary);
+
+    // Files never reached because the connection dropped
+    for (const file of files.slice(results.length)) {
+      results.push({ success: false, fileName: file.name, error: 'Connection closed' });
+    }
+    report(files[files.length - 1].name, results.every((r) => r.success) ? 'complete' : 'failed');
+    return results;
+  } finally {
+    socket.end();
+  }
+}
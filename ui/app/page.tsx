@@ -1,6 +1,7 @@
 'use client';
 
 import { useRouter } from 'next/navigation';
+import Approvals from '../components/approvals';
 import Button from '../components/button';
 import Header from '../components/header';
 
@@ -30,6 +31,8 @@ export default function Home() {
               Quick Start
             </Button>
           </div>
+
+          <Approvals />
         </div>
       </div>
     </div>
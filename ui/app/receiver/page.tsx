@@ -0,0 +1,272 @@
+'use client';
+
+import { useEffect, useState } from 'react';
+import Header from '../../components/header';
+import Button from '../../components/button';
+import Approvals from '../../components/approvals';
+import Progress from '../../components/progress';
+import { formatBytes } from '../../lib/format';
+
+const font = '-apple-system, BlinkMacSystemFont, "SF Pro Text", system-ui, sans-serif';
+
+interface Status {
+  name: string;
+  version: string;
+  transfer_id: string;
+  listen: string[];
+  folder: string;
+  uptime_secs: number;
+  active_transfers: number;
+  pending_approvals: number;
+}
+
+interface Quota {
+  folder: string;
+  used: number;
+  max_folder_size: number;
+  max_file_size: number;
+  folders: { name: string; used: number }[];
+}
+
+interface ActiveTransfer {
+  id: number;
+  peer: string;
+  kind: string;
+  name: string;
+  files: number;
+  size: number;
+  started: string;
+  state: string;
+  file: string;
+  bytes: number;
+  rate: number;
+  eta_secs: number | null;
+}
+
+interface HistoryEntry {
+  timestamp: string;
+  peer: string;
+  filename: string;
+  stored_name?: string;
+  size: number;
+  result: string;
+  error?: string;
+}
+
+// Settings editable from the UI; everything else stays in transfer.toml
+interface Settings {
+  name: string;
+  folder: string;
+  max_file_size: number;
+  max_folder_size: number;
+  conflict: string;
+  compression: boolean;
+  allow_read: boolean;
+  allow_delete: boolean;
+  approval: { enabled: boolean; timeout: number };
+}
+
+async function getJson<T>(path: string): Promise<T | null> {
+  try {
+    const response = await fetch(`/api/control/${path}`);
+    return response.ok ? await response.json() : null;
+  } catch {
+    return null;
+  }
+}
+
+export default function ReceiverPage() {
+  const [status, setStatus] = useState<Status | null>(null);
+  const [quota, setQuota] = useState<Quota | null>(null);
+  const [active, setActive] = useState<ActiveTransfer[]>([]);
+  const [history, setHistory] = useState<HistoryEntry[]>([]);
+  const [settings, setSettings] = useState<Settings | null>(null);
+  const [message, setMessage] = useState('');
+
+  const refresh = async () => {
+    setStatus(await getJson<Status>('status'));
+    setHistory((await getJson<HistoryEntry[]>('history?limit=20') ?? []).reverse());
+  };
+
+  useEffect(() => {
+    refresh();
+    getJson<Quota>('quota').then(setQuota);
+    getJson<Settings>('config').then(setSettings);
+    const interval = setInterval(refresh, 3000);
+    return () => clearInterval(interval);
+  }, []);
+
+  // Live progress of incoming transfers; finished ones stay visible for a few seconds
+  useEffect(() => {
+    const events = new EventSource('/api/control/events');
+    events.onmessage = (message) => {
+      const transfer: ActiveTransfer = JSON.parse(message.data);
+      setActive((current) => {
+        const others = current.filter((t) => t.id !== transfer.id);
+        return [...others, transfer].sort((a, b) => a.id - b.id);
+      });
+      if (transfer.state === 'complete' || transfer.state === 'failed') {
+        setTimeout(() => setActive((current) => current.filter((t) => t.id !== transfer.id)), 5000);
+        refresh();
+      }
+    };
+    return () => events.close();
+  }, []);
+
+  const saveSettings = async () => {
+    if (!settings) {
+      return;
+    }
+    const { name, folder, max_file_size, max_folder_size, conflict, compression, allow_read, allow_delete, approval } = settings;
+    const response = await fetch('/api/control/config', {
+      method: 'PATCH',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ name, folder, max_file_size, max_folder_size, conflict, compression, allow_read, allow_delete, approval }),
+    });
+    const data = await response.json();
+    if (!response.ok) {
+      setMessage(data.error ?? 'Failed to save settings');
+    } else if (data.restart_required?.length > 0) {
+      setMessage(`Saved. Restart the server to apply: ${data.restart_required.join(', ')}`);
+    } else {
+      setMessage('Saved');
+    }
+    getJson<Quota>('quota').then(setQuota);
+  };
+
+  const inputClass = 'w-full px-3 py-1 border border-gray-300 focus:border-black outline-none rounded-lg';
+  const sectionTitle = 'text-lg font-bold text-black mb-2';
+
+  return (
+    <div className="min-h-screen bg-white">
+      <Header />
+      <div className="p-8" style={{ paddingTop: '72px' }}>
+        <div className="max-w-2xl mx-auto space-y-8" style={{ fontFamily: font }}>
+          <h1 className="text-3xl font-bold text-black">Receiver</h1>
+
+          {!status && (
+            <p className="text-sm text-gray-700">The local transfer server is not reachable.</p>
+          )}
+
+          {status && (
+            <div className="text-sm text-gray-700 space-y-1">
+              <div><span className="font-medium">{status.name}</span> (version {status.version}), up {Math.floor(status.uptime_secs / 60)} min</div>
+              <div>Listening on {status.listen.join(', ')}</div>
+              <div>Transfer ID: <span className="font-mono">{status.transfer_id}</span></div>
+            </div>
+          )}
+
+          <Approvals />
+
+          {quota && (
+            <div>
+              <h2 className={sectionTitle}>Storage</h2>
+              <div className="text-sm text-gray-700 space-y-1">
+                <div>
+                  {quota.folder}: {formatBytes(quota.used)}
+                  {quota.max_folder_size > 0 ? ` of ${formatBytes(quota.max_folder_size)}` : ' (no limit)'}
+                </div>
+                {quota.folders.map((folder) => (
+                  <div key={folder.name} className="pl-4">{folder.name}: {formatBytes(folder.used)}</div>
+                ))}
+              </div>
+            </div>
+          )}
+
+          <div>
+            <h2 className={sectionTitle}>Active transfers</h2>
+            {active.length === 0 && <p className="text-sm text-gray-700">None</p>}
+            <div className="space-y-3">
+              {active.map((transfer) => (
+                <Progress
+                  key={transfer.id}
+                  label={`${transfer.files > 1 ? `${transfer.file} (${transfer.files} files)` : transfer.name || transfer.kind} from ${transfer.peer}`}
+                  state={transfer.state}
+                  bytes={transfer.bytes}
+                  total={transfer.size}
+                  rate={transfer.rate}
+                  etaSecs={transfer.eta_secs}
+                />
+              ))}
+            </div>
+          </div>
+
+          <div>
+            <h2 className={sectionTitle}>Recent transfers</h2>
+            {history.length === 0 && <p className="text-sm text-gray-700">None</p>}
+            {history.map((entry, index) => (
+              <div key={index} className="text-sm text-gray-700 flex gap-3">
+                <span className="text-gray-500">{new Date(entry.timestamp).toLocaleString()}</span>
+                <span style={{ color: entry.result === 'ok' ? 'black' : '#dc2626' }}>{entry.result}</span>
+                <span className="break-all">{entry.stored_name ?? entry.filename}</span>
+                <span>{formatBytes(entry.size)}</span>
+                <span className="text-gray-500">{entry.peer}</span>
+              </div>
+            ))}
+          </div>
+
+          {settings && (
+            <div className="space-y-3">
+              <h2 className={sectionTitle}>Settings</h2>
+              <label className="block text-sm text-gray-700">
+                Device name
+                <input className={inputClass} value={settings.name}
+                  onChange={(e) => setSettings({ ...settings, name: e.target.value })} />
+              </label>
+              <label className="block text-sm text-gray-700">
+                Receive folder
+                <input className={inputClass} value={settings.folder}
+                  onChange={(e) => setSettings({ ...settings, folder: e.target.value })} />
+              </label>
+              <div className="flex gap-2">
+                <label className="block text-sm text-gray-700 w-1/2">
+                  Max file size (bytes, 0 = no limit)
+                  <input className={inputClass} type="number" min={0} value={settings.max_file_size}
+                    onChange={(e) => setSettings({ ...settings, max_file_size: Number(e.target.value) })} />
+                </label>
+                <label className="block text-sm text-gray-700 w-1/2">
+                  Max folder size (bytes, 0 = no limit)
+                  <input className={inputClass} type="number" min={0} value={settings.max_folder_size}
+                    onChange={(e) => setSettings({ ...settings, max_folder_size: Number(e.target.value) })} />
+                </label>
+              </div>
+              <label className="block text-sm text-gray-700">
+                When a file name is taken
+                <select className={inputClass} value={settings.conflict}
+                  onChange={(e) => setSettings({ ...settings, conflict: e.target.value })}>
+                  <option value="rename">Keep both (number the new file)</option>
+                  <option value="overwrite">Overwrite the existing file</option>
+                  <option value="reject">Reject the transfer</option>
+                </select>
+              </label>
+              <label className="flex items-center gap-2 text-sm text-gray-700">
+                <input type="checkbox" checked={settings.compression}
+                  onChange={(e) => setSettings({ ...settings, compression: e.target.checked })} />
+                Allow compression
+              </label>
+              <label className="flex items-center gap-2 text-sm text-gray-700">
+                <input type="checkbox" checked={settings.allow_read}
+                  onChange={(e) => setSettings({ ...settings, allow_read: e.target.checked })} />
+                Allow listing and downloading files
+              </label>
+              <label className="flex items-center gap-2 text-sm text-gray-700">
+                <input type="checkbox" checked={settings.allow_delete}
+                  onChange={(e) => setSettings({ ...settings, allow_delete: e.target.checked })} />
+                Allow deleting files
+              </label>
+              <label className="flex items-center gap-2 text-sm text-gray-700">
+                <input type="checkbox" checked={settings.approval.enabled}
+                  onChange={(e) => setSettings({ ...settings, approval: { ...settings.approval, enabled: e.target.checked } })} />
+                Ask before accepting transfers
+              </label>
+              <div className="flex items-center gap-3">
+                <Button onClick={saveSettings}>Save</Button>
+                {message && <span className="text-sm text-gray-700">{message}</span>}
+              </div>
+            </div>
+          )}
+        </div>
+      </div>
+    </div>
+  );
+}
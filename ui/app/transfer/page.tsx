@@ -5,6 +5,8 @@ import Link from 'next/link';
 import { useRouter } from 'next/navigation';
 import Header from '../../components/header';
 import Button from '../../components/button';
+import Progress from '../../components/progress';
+import { sendOverWebSocket, SendProgress } from '../../lib/websocket';
 
 interface TransferForm {
   ip: string;
@@ -12,6 +14,37 @@ interface TransferForm {
   id: string;
   files: File[];
   folder: string;
+  // Port of the receiver's WebSocket transfer server; set to send directly from the browser
+  websocketPort: string;
+}
+
+// Reads the newline-delimited JSON answer of /api/transfer, passing progress
+// lines to `onProgress` and returning the final result line
+async function readTransferResponse(response: Response, onProgress: (progress: SendProgress) => void) {
+  const reader = response.body!.getReader();
+  const decoder = new TextDecoder();
+  let buffered = '';
+  let result: any = null;
+
+  for (;;) {
+    const { done, value } = await reader.read();
+    if (done) {
+      break;
+    }
+    buffered += decoder.decode(value, { stream: true });
+    let newline;
+    while ((newline = buffered.indexOf('\n')) >= 0) {
+      const line = JSON.parse(buffered.slice(0, newline));
+      buffered = buffered.slice(newline + 1);
+      if (line.type === 'progress') {
+        onProgress(line);
+      } else if (line.type === 'result') {
+        result = line;
+      }
+    }
+  }
+
+  return result ?? { success: false, error: 'No result from the transfer' };
 }
 
 interface Destination {
@@ -27,13 +60,15 @@ export default function TransferPage() {
     port: '',
     id: '',
     files: [],
-    folder: ''
+    folder: '',
+    websocketPort: ''
   });
   const [isTransferring, setIsTransferring] = useState(false);
   const [isTransferred, setIsTransferred] = useState(false);
   const [transferStatus, setTransferStatus] = useState<string>('');
   const [transferProgress, setTransferProgress] = useState({ completed: 0, total: 0 });
   const [showProgress, setShowProgress] = useState(false);
+  const [fileProgress, setFileProgress] = useState<SendProgress | null>(null);
   const [selectedDestination, setSelectedDestination] = useState<string>('Custom');
   const [savedDestinations, setSavedDestinations] = useState<Destination[]>([]);
   const [selectFolders, setSelectFolders] = useState(false);
@@ -81,7 +116,8 @@ export default function TransferPage() {
         port: '',
         id: '',
         files: prev.files, // Keep the files
-        folder: prev.folder // Keep the folder
+        folder: prev.folder, // Keep the folder
+        websocketPort: prev.websocketPort
       }));
     } else {
       // Load saved destination data
@@ -92,7 +128,8 @@ export default function TransferPage() {
           port: destination.port,
           id: destination.id,
           files: prev.files, // Keep the files
-          folder: prev.folder // Keep the folder
+          folder: prev.folder, // Keep the folder
+          websocketPort: prev.websocketPort
         }));
       }
     }
@@ -126,12 +163,25 @@ export default function TransferPage() {
     setIsTransferring(true);
     setShowProgress(true);
     setTransferProgress({ completed: 0, total: form.files.length });
+    setFileProgress(null);
 
     try {
       let successfulTransfers = 0;
       
-      // Transfer files one by one to update progress in real-time
-      for (let i = 0; i < form.files.length; i++) {
+      if (form.websocketPort.trim()) {
+        // Stream all files straight to the receiver as one batch
+        const host = form.ip.trim().replace(/^\[(.*)\]$/, '$1');
+        const url = `ws://${host.includes(':') ? `[${host}]` : host}:${form.websocketPort.trim()}/`;
+        const results = await sendOverWebSocket(url, form.id, form.folder, form.files, setFileProgress);
+        for (const result of results.filter((r) => !r.success)) {
+          console.error(`Transfer failed for file ${result.fileName}:`, result.error);
+        }
+        successfulTransfers = results.filter((r) => r.success).length;
+        setTransferProgress({ completed: successfulTransfers, total: form.files.length });
+      }
+      
+      // Otherwise transfer files one by one through the server
+      for (let i = 0; i < form.files.length && !form.websocketPort.trim(); i++) {
         const file = form.files[i];
         
         // Prepare form data for single file transfer
@@ -156,7 +206,7 @@ export default function TransferPage() {
             throw new Error(`HTTP error! status: ${response.status}`);
           }
 
-          const result = await response.json();
+          const result = await readTransferResponse(response, setFileProgress);
           
           if (result.success) {
             successfulTransfers++;
@@ -324,6 +374,20 @@ export default function TransferPage() {
             />
           </div>
 
+          {/* WebSocket Port Field (always visible) */}
+          <div>
+            <input
+              type="text"
+              value={form.websocketPort}
+              onChange={(e) => handleInputChange('websocketPort', e.target.value)}
+              placeholder="WebSocket Port (Optional, sends directly from the browser)"
+              className="w-full px-3 py-1 border border-gray-300 focus:border-black outline-none rounded-lg"
+              style={{
+                fontFamily: '-apple-system, BlinkMacSystemFont, "SF Pro Text", system-ui, sans-serif'
+              }}
+            />
+          </div>
+
           {/* Transfer Button */}
           <div>
             <button
@@ -361,6 +425,19 @@ export default function TransferPage() {
                 {transferProgress.completed}/{transferProgress.total} files transferred
               </div>
             )}
+
+            {showProgress && fileProgress && (
+              <div style={{ marginTop: '8px' }}>
+                <Progress
+                  label={fileProgress.fileName}
+                  state={fileProgress.state}
+                  bytes={fileProgress.bytes}
+                  total={fileProgress.total}
+                  rate={fileProgress.rate}
+                  etaSecs={fileProgress.etaSecs}
+                />
+              </div>
+            )}
           </div>
 
 
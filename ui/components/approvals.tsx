@@ -0,0 +1,78 @@
+'use client';
+
+import { useEffect, useState } from 'react';
+import Button from './button';
+
+interface PendingTransfer {
+  id: number;
+  peer: string;
+  kind: 'file' | 'tree' | 'batch';
+  name: string;
+  files: number;
+  size: number;
+  folder?: string;
+}
+
+// Incoming transfers waiting for the user to accept or reject them
+export default function Approvals() {
+  const [pending, setPending] = useState<PendingTransfer[]>([]);
+
+  const refresh = async () => {
+    try {
+      const response = await fetch('/api/control/approvals');
+      const data = await response.json();
+      setPending(Array.isArray(data) ? data : []);
+    } catch {
+      setPending([]);
+    }
+  };
+
+  useEffect(() => {
+    refresh();
+    const interval = setInterval(refresh, 2000);
+    return () => clearInterval(interval);
+  }, []);
+
+  const decide = async (id: number, accept: boolean) => {
+    await fetch(`/api/control/approvals/${id}`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ accept }),
+    });
+    refresh();
+  };
+
+  if (pending.length === 0) {
+    return null;
+  }
+
+  return (
+    <div style={{ display: 'flex', flexDirection: 'column', gap: '8px', marginTop: '2rem' }}>
+      {pending.map((transfer) => (
+        <div
+          key={transfer.id}
+          className="bg-white border border-gray-200 text-sm"
+          style={{
+            borderRadius: '8px',
+            padding: '8px 12px',
+            display: 'flex',
+            alignItems: 'center',
+            justifyContent: 'space-between',
+            gap: '12px',
+            fontFamily: '-apple-system, BlinkMacSystemFont, "SF Pro Text", system-ui, sans-serif'
+          }}
+        >
+          <span className="text-gray-700 break-all" style={{ textAlign: 'left' }}>
+            {transfer.kind === 'file' ? transfer.name : `${transfer.name} (${transfer.files} files)`}
+            {' '}({transfer.size} bytes) from {transfer.peer}
+            {transfer.folder ? ` into ${transfer.folder}` : ''}
+          </span>
+          <div style={{ display: 'flex', gap: '8px' }}>
+            <Button onClick={() => decide(transfer.id, true)}>Accept</Button>
+            <Button variant="secondary" onClick={() => decide(transfer.id, false)}>Reject</Button>
+          </div>
+        </div>
+      ))}
+    </div>
+  );
+}
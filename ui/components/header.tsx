@@ -75,6 +75,13 @@ export default function Header() {
             Destinations
           </span>
           <span style={{ color: '#9ca3af' }}>/</span>
+          <span 
+            style={{ cursor: 'pointer' }}
+            onClick={() => router.push('/receiver')}
+          >
+            Receiver
+          </span>
+          <span style={{ color: '#9ca3af' }}>/</span>
           <span style={{ cursor: 'pointer' }}>Documentation</span>
         </div>
 
@@ -0,0 +1,40 @@
+import { formatBytes, formatDuration } from '../lib/format';
+
+interface ProgressProps {
+  label: string;
+  state: string;
+  bytes: number;
+  total: number;
+  // Bytes per second
+  rate?: number;
+  etaSecs?: number | null;
+}
+
+// A progress bar with the amount transferred, rate and time left
+export default function Progress({ label, state, bytes, total, rate, etaSecs }: ProgressProps) {
+  const percent = total > 0 ? Math.min(100, (bytes / total) * 100) : state === 'complete' ? 100 : 0;
+  const color = state === 'failed' ? '#dc2626' : state === 'complete' ? '#065f46' : 'black';
+
+  const details = [`${formatBytes(bytes)} of ${formatBytes(total)}`];
+  if (state === 'receiving' || state === 'sending') {
+    if (rate) {
+      details.push(`${formatBytes(rate)}/s`);
+    }
+    if (etaSecs != null) {
+      details.push(`${formatDuration(etaSecs)} left`);
+    }
+  }
+
+  return (
+    <div className="text-sm text-gray-700 space-y-1">
+      <div className="flex justify-between gap-3">
+        <span className="break-all">{label}</span>
+        <span className="text-gray-500 whitespace-nowrap">{state}</span>
+      </div>
+      <div className="w-full h-2 bg-gray-200 rounded-full overflow-hidden">
+        <div className="h-full rounded-full" style={{ width: `${percent}%`, backgroundColor: color, transition: 'width 0.2s ease' }} />
+      </div>
+      <div className="text-gray-500">{details.join(' · ')}</div>
+    </div>
+  );
+}
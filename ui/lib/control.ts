@@ -0,0 +1,138 @@
+import { randomBytes } from 'crypto';
+import { readFileSync } from 'fs';
+import { request as httpRequest } from 'http';
+import { Socket } from 'net';
+import { join } from 'path';
+
+// Control API of the local transfer server ([control] table of transfer.toml)
+const CONTROL_URL = process.env.TRANSFER_CONTROL_URL || 'http://127.0.0.1:1003';
+
+// Same locations the server uses for transfer.toml
+function configPath(): string | null {
+  switch (process.platform) {
+    case 'win32':
+      return process.env.APPDATA ? join(process.env.APPDATA, '.transfer', 'transfer.toml') : null;
+    case 'linux':
+      return '/opt/transfer/transfer.toml';
+    case 'darwin':
+      return '/Users/Shared/transfer/transfer.toml';
+    default:
+      return null;
+  }
+}
+
+// The token comes from TRANSFER_CONTROL_TOKEN, or the [control] table of the local config
+function controlToken(): string | null {
+  if (process.env.TRANSFER_CONTROL_TOKEN) {
+    return process.env.TRANSFER_CONTROL_TOKEN;
+  }
+
+  const path = configPath();
+  if (!path) {
+    return null;
+  }
+  try {
+    const config = readFileSync(path, 'utf8');
+    const control = config.split(/^\[control\]\s*$/m)[1]?.split(/^\[/m)[0] ?? '';
+    return control.match(/^token\s*=\s*"([^"]*)"/m)?.[1] ?? null;
+  } catch {
+    return null;
+  }
+}
+
+// Calls the control API; `path` is relative to /api (e.g. "status" or "history?limit=20")
+export async function controlRequest(path: string, init: RequestInit = {}): Promise<Response> {
+  const token = controlToken();
+  if (!token) {
+    return Response.json({ error: 'Control API token not found' }, { status: 503 });
+  }
+
+  try {
+    return await fetch(`${CONTROL_URL}/api/${path}`, {
+      ...init,
+      headers: {
+        ...init.headers,
+        'Authorization': `Bearer ${token}`,
+        'Content-Type': 'application/json',
+      },
+      cache: 'no-store',
+    });
+  } catch (error: any) {
+    return Response.json({ error: `Transfer server not reachable: ${error.message}` }, { status: 503 });
+  }
+}
+
+// Follows the /api/events WebSocket of the control API, calling `onEvent` with
+// every message. Resolves to a function that closes the socket; `onClose`
+// runs when the server goes away.
+export function controlEvents(onEvent: (data: string) => void, onClose: () => void): Promise<() => void> {
+  const token = controlToken();
+  if (!token) {
+    return Promise.reject(new Error('Control API token not found'));
+  }
+
+  return new Promise((resolve, reject) => {
+    const request = httpRequest(`${CONTROL_URL}/api/events`, {
+      headers: {
+        'Authorization': `Bearer ${token}`,
+        'Connection': 'Upgrade',
+        'Upgrade': 'websocket',
+        'Sec-WebSocket-Version': '13',
+        'Sec-WebSocket-Key': randomBytes(16).toString('base64'),
+      },
+    });
+
+    request.on('response', (response) => {
+      response.resume();
+      reject(new Error(`Control API answered ${response.statusCode}`));
+    });
+    request.on('error', reject);
+
+    request.on('upgrade', (_response, socket: Socket, head: Buffer) => {
+      let buffered = head;
+      let message: Buffer[] = [];
+
+      // Server frames are never masked; text may be split into continuation frames
+      socket.on('data', (data: Buffer) => {
+        buffered = Buffer.concat([buffered, data]);
+        while (buffered.length >= 2) {
+          const fin = (buffered[0] & 0x80) !== 0;
+          const opcode = buffered[0] & 0x0f;
+          let length = buffered[1] & 0x7f;
+          let offset = 2;
+          if (length === 126) {
+            if (buffered.length < 4) return;
+            length = buffered.readUInt16BE(2);
+            offset = 4;
+          } else if (length === 127) {
+            if (buffered.length < 10) return;
+            length = Number(buffered.readBigUInt64BE(2));
+            offset = 10;
+          }
+          if (buffered.length < offset + length) return;
+
+          const payload = buffered.subarray(offset, offset + length);
+          buffered = buffered.subarray(offset + length);
+
+          if (opcode === 0x8) {
+            socket.end();
+            return;
+          }
+          if (opcode === 0x1 || opcode === 0x0) {
+            message.push(payload);
+            if (fin) {
+              onEvent(Buffer.concat(message).toString('utf8'));
+              message = [];
+            }
+          }
+        }
+      });
+      socket.on('close', onClose);
+      socket.on('error', () => socket.destroy());
+
+      resolve(() => socket.destroy());
+    });
+
+    request.end();
+  });
+}
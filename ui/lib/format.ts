@@ -0,0 +1,20 @@
+export function formatBytes(bytes: number): string {
+  const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+  let value = bytes;
+  let unit = 0;
+  while (value >= 1024 && unit < units.length - 1) {
+    value /= 1024;
+    unit++;
+  }
+  return `${unit === 0 ? value : value.toFixed(1)} ${units[unit]}`;
+}
+
+export function formatDuration(secs: number): string {
+  if (secs < 60) {
+    return `${secs}s`;
+  }
+  if (secs < 3600) {
+    return `${Math.floor(secs / 60)}m ${secs % 60}s`;
+  }
+  return `${Math.floor(secs / 3600)}h ${Math.floor((secs % 3600) / 60)}m`;
+}
@@ -0,0 +1,170 @@
+// Sends files straight from the browser to a receiver's WebSocket transfer
+// server (websocket_port in its transfer.toml), without going through Next.js
+
+export interface SendProgress {
+  fileName: string;
+  state: string;
+  bytes: number;
+  total: number;
+  rate: number;
+  etaSecs: number | null;
+}
+
+export interface FileResult {
+  success: boolean;
+  fileName: string;
+  message?: string;
+  error?: string;
+}
+
+// Size of the binary messages the files are split into
+const CHUNK_SIZE = 1024 * 1024;
+
+// Stop queueing chunks while this much is still waiting to be sent
+const MAX_BUFFERED = 8 * 1024 * 1024;
+
+// Minimum time between two progress reports while sending
+const PROGRESS_INTERVAL = 250;
+
+// Every text message from the server is one response line
+function createLineReader(socket: WebSocket) {
+  const lines: string[] = [];
+  const waiters: ((line: string | null) => void)[] = [];
+  let closed = false;
+
+  socket.addEventListener('message', (event) => {
+    if (typeof event.data !== 'string') {
+      return;
+    }
+    const waiter = waiters.shift();
+    if (waiter) {
+      waiter(event.data);
+    } else {
+      lines.push(event.data);
+    }
+  });
+  socket.addEventListener('close', () => {
+    closed = true;
+    while (waiters.length > 0) {
+      waiters.shift()!(null);
+    }
+  });
+
+  return (): Promise<string | null> => {
+    if (lines.length > 0) {
+      return Promise.resolve(lines.shift()!);
+    }
+    if (closed) {
+      return Promise.resolve(null);
+    }
+    return new Promise((resolve) => waiters.push(resolve));
+  };
+}
+
+function sizeHeader(size: number): ArrayBuffer {
+  const header = new ArrayBuffer(8);
+  new DataView(header).setBigUint64(0, BigInt(size));
+  return header;
+}
+
+// Sends the files as one SESSION, like the TCP sender of /api/transfer
+export async function sendOverWebSocket(url: string, transferId: string, folder: string | null, files: File[],
+  onProgress: (progress: SendProgress) => void): Promise<FileResult[]> {
+  const fail = (error: string) => files.map((file) => ({ success: false, fileName: file.name, error }));
+
+  const socket = new WebSocket(url);
+  socket.binaryType = 'arraybuffer';
+  const readLine = createLineReader(socket);
+
+  const opened = await new Promise<boolean>((resolve) => {
+    socket.addEventListener('open', () => resolve(true), { once: true });
+    socket.addEventListener('error', () => resolve(false), { once: true });
+  });
+  if (!opened) {
+    return fail(`Connection to ${url} failed`);
+  }
+
+  const total = files.reduce((sum, file) => sum + file.size, 0);
+  let queued = 0;
+  let sendingSince = 0;
+  let lastReport = 0;
+  const report = (fileName: string, state: string, force = true) => {
+    const now = Date.now();
+    if (!force && now - lastReport < PROGRESS_INTERVAL) {
+      return;
+    }
+    lastReport = now;
+    // Bytes still in the browser's buffer haven't been sent yet
+    const bytes = Math.max(0, queued - socket.bufferedAmount);
+    const elapsed = sendingSince ? (now - sendingSince) / 1000 : 0;
+    const rate = elapsed > 0 ? Math.round(bytes / elapsed) : 0;
+    const etaSecs = rate > 0 ? Math.ceil((total - bytes) / rate) : null;
+    onProgress({ fileName, state, bytes, total, rate, etaSecs });
+  };
+
+  try {
+    const command = folder && folder.trim()
+      ? `SESSION ${transferId} ${folder.trim()}`
+      : `SESSION ${transferId}`;
+    const manifest = new TextEncoder().encode(JSON.stringify({
+      entries: files.map((file) => ({ path: file.name, kind: 'file', size: file.size })),
+    }));
+    socket.send(command);
+    socket.send(sizeHeader(manifest.length));
+    socket.send(manifest);
+    report(files[0].name, 'waiting');
+
+    // Wait for ACK before sending file data
+    const response = await readLine();
+    if (response !== 'ACK') {
+      report(files[0].name, 'failed');
+      return fail(response ?? 'Connection closed');
+    }
+
+    const results: FileResult[] = [];
+    sendingSince = Date.now();
+    for (const file of files) {
+      report(file.name, 'sending');
+      for (let offset = 0; offset < file.size; offset += CHUNK_SIZE) {
+        while (socket.bufferedAmount > MAX_BUFFERED && socket.readyState === WebSocket.OPEN) {
+          await new Promise((resolve) => setTimeout(resolve, 20));
+          report(file.name, 'sending', false);
+        }
+        if (socket.readyState !== WebSocket.OPEN) {
+          break;
+        }
+        const chunk = await file.slice(offset, offset + CHUNK_SIZE).arrayBuffer();
+        socket.send(chunk);
+        queued += chunk.byteLength;
+        report(file.name, 'sending', false);
+      }
+      report(file.name, 'verifying');
+
+      const fileResponse = await readLine();
+      if (fileResponse === null) {
+        results.push({ success: false, fileName: file.name, error: 'Connection closed' });
+        break;
+      }
+      if (fileResponse.startsWith('FILE_COMPLETE')) {
+        results.push({ success: true, fileName: file.name, message: 'Transfer completed successfully' });
+      } else {
+        const error = fileResponse.startsWith('FILE_FAILED')
+          ? fileResponse.slice(fileResponse.indexOf(': ') + 2)
+          : fileResponse;
+        results.push({ success: false, fileName: file.name, error });
+      }
+    }
+
+    // SESSION_COMPLETE summary
+    await readLine();
+
+    // Files never reached because the connection dropped
+    for (const file of files.slice(results.length)) {
+      results.push({ success: false, fileName: file.name, error: 'Connection closed' });
+    }
+    report(files[files.length - 1].name, results.every((r) => r.success) ? 'complete' : 'failed');
+    return results;
+  } finally {
+    socket.close();
+  }
+}
@@ -0,0 +1,80 @@
+import { NextRequest, NextResponse } from 'next/server';
+
+// Cookie proving that a request comes from a page this UI served
+const SESSION_COOKIE = 'transfer_session';
+
+// Random for every run of the UI, so old sessions end when it restarts
+const SESSION = Array.from(crypto.getRandomValues(new Uint8Array(32)),
+  (byte) => byte.toString(16).padStart(2, '0')).join('');
+
+// Host names the UI answers to; more can be listed in TRANSFER_UI_HOSTS (comma separated)
+const HOSTS = [
+  'localhost',
+  '127.0.0.1',
+  '[::1]',
+  ...(process.env.TRANSFER_UI_HOSTS ?? '').split(',').map((host) => host.trim().toLowerCase()).filter(Boolean),
+];
+
+// "localhost:3000" -> "localhost", "[::1]:3000" -> "[::1]"
+function hostName(host: string): string {
+  const name = host.startsWith('[') ? host.slice(0, host.indexOf(']') + 1) : host.split(':')[0];
+  return name.toLowerCase();
+}
+
+// Compares without stopping at the first difference
+function sameSecret(a: string, b: string): boolean {
+  if (a.length !== b.length) {
+    return false;
+  }
+  let difference = 0;
+  for (let i = 0; i < a.length; i++) {
+    difference |= a.charCodeAt(i) ^ b.charCodeAt(i);
+  }
+  return difference === 0;
+}
+
+function refuse(error: string, status: number) {
+  return NextResponse.json({ error }, { status });
+}
+
+// The API routes act with the control token of the local server, so they only
+// answer pages of this UI: the Host must be a local name (no DNS rebinding), a
+// cross-site Origin is refused, and the browser must hold the session cookie
+// that only same-site page loads receive
+export function middleware(request: NextRequest) {
+  const host = request.headers.get('host') ?? '';
+  if (!HOSTS.includes(hostName(host))) {
+    return refuse('Unknown host', 403);
+  }
+
+  const session = request.cookies.get(SESSION_COOKIE)?.value ?? '';
+
+  if (request.nextUrl.pathname.startsWith('/api/')) {
+    const origin = request.headers.get('origin');
+    if (origin !== null) {
+      let originHost = null;
+      try {
+        originHost = new URL(origin).host;
+      } catch {
+        // Opaque origins ("null") are refused below
+      }
+      if (originHost !== host) {
+        return refuse('Cross-origin requests are not allowed', 403);
+      }
+    }
+    if (!sameSecret(session, SESSION)) {
+      return refuse('Missing or invalid session, reload the page', 401);
+    }
+    return NextResponse.next();
+  }
+
+  const response = NextResponse.next();
+  if (!sameSecret(session, SESSION)) {
+    response.cookies.set(SESSION_COOKIE, SESSION, { httpOnly: true, sameSite: 'strict', path: '/' });
+  }
+  return response;
+}
+
+export const config = {
+  matcher: ['/((?!_next/static|_next/image|favicon.ico).*)'],
+};